@@ -1,23 +1,778 @@
-use std::error::Error;
-use std::io::{self, ErrorKind, Write};
-use std::process::Command;
-pub fn run_cgi_script(script_path: &str, body: &str, path_info: &str) -> io::Result<String> {
-    let output = Command::new("python")
-        .arg(script_path)
-        .env("PATH_INFO", path_info)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .and_then(|mut child| {
-            if let Some(stdin) = child.stdin.as_mut() {
-                stdin.write_all(body.as_bytes())?;
-            }
-            let output = child.wait_with_output()?;
-            Ok(output)
-        })?;
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+// # تشغيل سكربتات CGI
+//
+// The script gets the request as RFC 3875 meta-variables in its environment
+// and the body on stdin, byte for byte (a chunked body arrives de-chunked,
+// with CONTENT_LENGTH set to its real length). What it prints is a CGI
+// header block (Status, Content-Type, Location, ...), a blank line and the
+// body, which is sent on as it arrives.
+
+use crate::fastcgi::FastCgiRequest;
+use crate::proxy::ProxyRequest;
+use crate::request_body::RequestBody;
+use crate::requests::{Headers, Request, Response, reason_phrase};
+use crate::serverConfig::{RouterConfig, ServerConfig};
+use mio::unix::pipe;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// A script found under the route's root for a request path
+pub struct CgiScript {
+    pub path: PathBuf,                    // canonical path of the script file
+    pub script_name: String,              // URL path of the script, "/cgi-bin/app.py"
+    pub path_info: String,                // the rest of the URL path, "/users/1"
+    pub interpreter: Option<Vec<String>>, // program and arguments; None runs the file itself
+}
+
+pub enum CgiMatch {
+    Script(CgiScript),
+    Forbidden, // a script extension that may not run, or no way to run it
+    NotCgi,
+}
+
+/// Looks for a script along the request path: "/cgi-bin/app.py/users/1" runs
+/// root/cgi-bin/app.py when ".py" is in the route's `cgi` map or `cgi_extensions`.
+pub fn find_script(route: &RouterConfig, uri_path: &str) -> CgiMatch {
+    if route.cgi.is_empty() && route.cgi_extensions.is_none() {
+        return CgiMatch::NotCgi;
+    }
+    let Some((path, script_name, path_info)) = locate(route, uri_path) else {
+        return CgiMatch::NotCgi;
+    };
+    let extension = match script_name.rfind('.') {
+        Some(dot) if !script_name[dot..].contains('/') => &script_name[dot..],
+        _ => return CgiMatch::NotCgi,
+    };
+    let interpreter = route.cgi.get(extension);
+    let allowed = match &route.cgi_extensions {
+        Some(extensions) => extensions.iter().any(|e| e == extension),
+        None => interpreter.is_some(),
+    };
+    if !allowed {
+        // never send the source of a script we won't run
+        return if interpreter.is_some() {
+            CgiMatch::Forbidden
+        } else {
+            CgiMatch::NotCgi
+        };
+    }
+    let interpreter = if runs_directly(&path) {
+        None
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "CGI script failed"))
+        match interpreter.map(|i| i.split_whitespace().map(String::from).collect::<Vec<_>>()) {
+            Some(argv) if !argv.is_empty() => Some(argv),
+            _ => {
+                eprintln!(
+                    "{} has no interpreter and is not an executable script",
+                    path.display()
+                );
+                return CgiMatch::Forbidden;
+            }
+        }
+    };
+    CgiMatch::Script(CgiScript {
+        path,
+        script_name: script_name.to_string(),
+        path_info: path_info.to_string(),
+        interpreter,
+    })
+}
+
+/// The script a `fastcgi` route hands a request to: the first file along the
+/// path, else the whole path, as the FastCGI server may route requests itself.
+/// With `cgi_extensions` set, only files with those extensions go to it.
+pub fn fastcgi_script(route: &RouterConfig, uri_path: &str) -> Option<CgiScript> {
+    let (path, script_name, path_info) = match locate(route, uri_path) {
+        Some(found) => found,
+        None if uri_path.split('/').any(|segment| segment == "..") => return None,
+        None => (
+            Path::new(&route.root).join(uri_path.trim_start_matches('/')),
+            uri_path,
+            "",
+        ),
+    };
+    if let Some(extensions) = &route.cgi_extensions {
+        let file_name = script_name.rsplit('/').next().unwrap_or("");
+        if !extensions.iter().any(|e| file_name.ends_with(e.as_str())) {
+            return None;
+        }
+    }
+    Some(CgiScript {
+        path,
+        script_name: script_name.to_string(),
+        path_info: path_info.to_string(),
+        interpreter: None,
+    })
+}
+
+// The first file along the path under the route's root, with the part of the
+// URL naming it and the rest (PATH_INFO). None when the path leaves the root,
+// names something missing or only directories.
+fn locate<'a>(route: &RouterConfig, uri_path: &'a str) -> Option<(PathBuf, &'a str, &'a str)> {
+    let root = fs::canonicalize(&route.root).ok()?;
+    let ends = uri_path
+        .match_indices('/')
+        .map(|(i, _)| i)
+        .chain(std::iter::once(uri_path.len()))
+        .filter(|&end| end > 0);
+    for end in ends {
+        let (script_name, path_info) = uri_path.split_at(end);
+        let path = fs::canonicalize(root.join(script_name.trim_start_matches('/'))).ok()?;
+        if !path.starts_with(&root) {
+            return None;
+        }
+        if path.is_dir() {
+            continue;
+        }
+        // the first file along the path decides
+        return Some((path, script_name, path_info));
+    }
+    None
+}
+
+// an executable file starting with "#!" is started as is and the kernel runs its interpreter
+fn runs_directly(path: &Path) -> bool {
+    let executable = fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o111 != 0);
+    let mut magic = [0; 2];
+    executable
+        && File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .is_ok()
+        && &magic == b"#!"
+}
+
+/// The RFC 3875 meta-variables for one request, plus the usual extras
+/// (REQUEST_URI, SCRIPT_FILENAME, DOCUMENT_ROOT, REMOTE_PORT, REDIRECT_STATUS)
+pub fn cgi_environment(
+    req: &Request,
+    server_config: &ServerConfig,
+    route: &RouterConfig,
+    script: &CgiScript,
+) -> Vec<(String, String)> {
+    let path_info = script.path_info.as_str();
+    let mut env: Vec<(String, String)> = Vec::new();
+    let mut set = |name: &str, value: String| env.push((name.to_string(), value));
+
+    set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    set(
+        "SERVER_SOFTWARE",
+        format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+    );
+    set("SERVER_PROTOCOL", req.version.clone());
+    set("REQUEST_METHOD", req.method.clone());
+    set("REQUEST_SCHEME", req.scheme().to_string());
+    if req.secure {
+        set("HTTPS", "on".to_string());
+    }
+    set("REQUEST_URI", req.path.clone());
+    set("QUERY_STRING", req.query().to_string());
+    set("SCRIPT_NAME", script.script_name.clone());
+    set("SCRIPT_FILENAME", script.path.display().to_string());
+    set("DOCUMENT_ROOT", route.root.clone());
+    set("PATH_INFO", path_info.to_string());
+    if !path_info.is_empty() {
+        let translated = Path::new(&route.root).join(path_info.trim_start_matches('/'));
+        set("PATH_TRANSLATED", translated.display().to_string());
+    }
+
+    // SERVER_NAME is the host the client asked for, like the Host header
+    let server_name = req
+        .header("Host")
+        .map(|host| crate::strip_host_port(host).to_string())
+        .or_else(|| {
+            server_config
+                .server_name
+                .split_whitespace()
+                .next()
+                .map(String::from)
+        })
+        .or_else(|| req.local_addr.map(|addr| addr.ip().to_string()))
+        .unwrap_or_default();
+    set("SERVER_NAME", server_name);
+    if let Some(local) = req.local_addr {
+        set("SERVER_ADDR", local.ip().to_string());
+        set("SERVER_PORT", local.port().to_string());
+    }
+    if let Some(remote) = req.remote_addr {
+        set("REMOTE_ADDR", remote.ip().to_string());
+        // no reverse lookup on the event loop: RFC 3875 4.1.9 lets the
+        // address stand in for the host name
+        set("REMOTE_HOST", remote.ip().to_string());
+        set("REMOTE_PORT", remote.port().to_string());
+    }
+
+    // a body is there when the client framed one; a chunked body reaches the
+    // script de-chunked, so its length is the decoded length
+    if req.body.len() > 0
+        || req.header("Content-Length").is_some()
+        || req.header("Transfer-Encoding").is_some()
+    {
+        set("CONTENT_LENGTH", req.body.len().to_string());
+    }
+    if let Some(content_type) = req.header("Content-Type") {
+        set("CONTENT_TYPE", content_type.to_string());
+    }
+    // php-cgi refuses to run without it
+    set("REDIRECT_STATUS", "200".to_string());
+
+    for (name, value) in &req.headers {
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        match name.as_str() {
+            // already passed as CONTENT_LENGTH / CONTENT_TYPE
+            "CONTENT_LENGTH" | "CONTENT_TYPE" => continue,
+            // the body on stdin is not chunked any more
+            "TRANSFER_ENCODING" => continue,
+            // credentials stay with the server (RFC 3875 4.1.18)
+            "AUTHORIZATION" | "PROXY_AUTHORIZATION" => continue,
+            // HTTP_PROXY would be taken as a proxy setting by many scripts ("httpoxy")
+            "PROXY" => continue,
+            _ => set(&format!("HTTP_{}", name), value.clone()),
+        }
+    }
+    env
+}
+
+/// seconds a script may run when the route sets no `cgi_timeout`
+pub const DEFAULT_TIMEOUT: u64 = 30;
+
+/// A script answering a request, run as a child process or by a FastCGI
+/// server, or an upstream HTTP server the request is proxied to. Either way
+/// its output is read by the event loop as it comes.
+pub struct CgiRequest {
+    pub backend: Backend,
+    pub name: String, // the script or upstream, for log messages
+    request: Request, // the request as a GET without a body, for local redirects
+    pub local_redirects: usize,
+    pub set_cookie: Option<String>,
+    last_output: Instant, // the timeout runs from here
+    timeout: Duration,
+}
+
+pub enum Backend {
+    Process(CgiProcess),
+    FastCgi(FastCgiRequest),
+    Proxy(ProxyRequest),
+}
+
+impl CgiRequest {
+    pub fn new(name: String, req: &Request, backend: Backend, timeout: Duration) -> Self {
+        // served as a fresh GET if the script answers with a local redirect (RFC 3875 6.2.2)
+        let mut headers = req.headers.clone();
+        headers.retain(|name, _| {
+            !["Content-Length", "Content-Type", "Transfer-Encoding"]
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+        });
+        let request = Request {
+            method: "GET".to_string(),
+            path: req.path.clone(),
+            version: req.version.clone(),
+            headers,
+            body: RequestBody::Memory(Vec::new()),
+            remote_addr: req.remote_addr,
+            local_addr: req.local_addr,
+            secure: req.secure,
+        };
+        CgiRequest {
+            backend,
+            name,
+            request,
+            local_redirects: 0,
+            set_cookie: None,
+            last_output: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Next bytes of the script's output; Ok(0) at its end and WouldBlock
+    /// while there is nothing new
+    pub fn read_output(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.backend {
+            Backend::Process(process) => process.stdout.read(buf),
+            Backend::FastCgi(fastcgi) => fastcgi.read_stdout(buf),
+            Backend::Proxy(proxy) => proxy.read_response(buf),
+        }
+    }
+
+    /// Fields for the last chunk: an upstream's trailers, none from a script
+    pub fn trailers(&self) -> Headers {
+        match &self.backend {
+            Backend::Proxy(proxy) => proxy.trailers.clone(),
+            _ => Headers::new(),
+        }
+    }
+
+    /// The request to serve when the script answers with a local redirect
+    pub fn redirect_request(&self, location: String) -> Request {
+        Request {
+            method: self.request.method.clone(),
+            path: location,
+            version: self.request.version.clone(),
+            headers: self.request.headers.clone(),
+            body: RequestBody::Memory(Vec::new()),
+            remote_addr: self.request.remote_addr,
+            local_addr: self.request.local_addr,
+            secure: self.request.secure,
+        }
+    }
+
+    /// true for HTTP/1.1 clients, which can take a chunked body
+    pub fn chunked_ok(&self) -> bool {
+        self.request.version == "HTTP/1.1"
+    }
+
+    /// the script wrote something (or was waiting on the client), its clock starts again
+    pub fn touch(&mut self) {
+        self.last_output = Instant::now();
+    }
+
+    /// true when the script has been silent longer than its timeout
+    pub fn timed_out(&self) -> bool {
+        self.last_output.elapsed() > self.timeout
+    }
+}
+
+/// A script started as a child process. Its stdout, and its stdin while the
+/// body is being written, are non-blocking pipes the event loop polls.
+pub struct CgiProcess {
+    pub child: Child,
+    pub stdout: pipe::Receiver,
+    pub stdin: Option<pipe::Sender>,
+    stdin_data: Vec<u8>, // an in-memory body, fed to stdin as the pipe takes it
+    stdin_written: usize,
+}
+
+impl CgiProcess {
+    /// Starts the script in its own process group, so a timeout can kill
+    /// whatever it started too
+    pub fn spawn(
+        script: &CgiScript,
+        body: &RequestBody,
+        env: &[(String, String)],
+    ) -> io::Result<CgiProcess> {
+        // a spooled body is handed to the script as its stdin file directly
+        let (stdin, stdin_data) = match body {
+            RequestBody::Spooled(_) => match body.spool_file() {
+                Some(file) => (Stdio::from(file?), Vec::new()),
+                None => (Stdio::null(), Vec::new()),
+            },
+            RequestBody::Memory(bytes) if bytes.is_empty() => (Stdio::null(), Vec::new()),
+            RequestBody::Memory(bytes) => (Stdio::piped(), bytes.clone()),
+        };
+        CgiProcess::start(script, env, stdin, stdin_data)
+    }
+
+    /// Starts a program that keeps its stdin open for input sent later with
+    /// `queue_stdin`, like a WebSocket `process` handler
+    pub fn interactive(script: &CgiScript, env: &[(String, String)]) -> io::Result<CgiProcess> {
+        CgiProcess::start(script, env, Stdio::piped(), Vec::new())
+    }
+
+    fn start(
+        script: &CgiScript,
+        env: &[(String, String)],
+        stdin: Stdio,
+        stdin_data: Vec<u8>,
+    ) -> io::Result<CgiProcess> {
+        let script_path = script.path.as_path();
+        let mut command = match &script.interpreter {
+            Some(argv) => {
+                let mut command = Command::new(&argv[0]);
+                command.args(&argv[1..]).arg(script_path);
+                command
+            }
+            None => Command::new(script_path),
+        };
+        command
+            // only the meta-variables, not the server's own environment
+            .env_clear()
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .process_group(0);
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        // scripts run in their own directory, so relative paths in them work
+        if let Some(dir) = script_path.parent() {
+            command.current_dir(dir);
+        }
+        let mut child = command.spawn()?;
+
+        let stdout = pipe::Receiver::from(child.stdout.take().expect("stdout is piped"));
+        stdout.set_nonblocking(true)?;
+        let stdin = match child.stdin.take() {
+            Some(stdin) => {
+                let stdin = pipe::Sender::from(stdin);
+                stdin.set_nonblocking(true)?;
+                Some(stdin)
+            }
+            None => None,
+        };
+        Ok(CgiProcess {
+            child,
+            stdout,
+            stdin,
+            stdin_data,
+            stdin_written: 0,
+        })
+    }
+
+    /// Writes as much of the body as the pipe takes. True once it is all
+    /// written, or the script closed its end, and stdin can be closed.
+    pub fn write_stdin(&mut self) -> bool {
+        let Some(stdin) = self.stdin.as_mut() else {
+            return true;
+        };
+        while self.stdin_written < self.stdin_data.len() {
+            match stdin.write(&self.stdin_data[self.stdin_written..]) {
+                Ok(n) => self.stdin_written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // the script won't read the rest
+                Err(_) => return true,
+            }
+        }
+        true
+    }
+
+    /// Adds bytes for stdin; `write_stdin` sends them
+    pub fn queue_stdin(&mut self, data: &[u8]) {
+        self.stdin_data.drain(..self.stdin_written);
+        self.stdin_written = 0;
+        self.stdin_data.extend_from_slice(data);
+    }
+
+    /// Kills the script's whole process group
+    pub fn kill(&mut self) {
+        let pid = self.child.id() as libc::pid_t;
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+}
+
+/// Length of the header block with its blank line, once the output holds all of it
+pub fn header_block_end(output: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    while let Some(lf) = output[pos..].iter().position(|&b| b == b'\n') {
+        let line = &output[pos..pos + lf];
+        pos += lf + 1;
+        if line.is_empty() || line == b"\r" {
+            return Some(pos);
+        }
+    }
+    None
+}
+
+/// A script's response once its header block is parsed
+pub enum CgiOutput {
+    Document(Response),
+    // `Location: /path` with no Status: the server answers with that path instead
+    LocalRedirect(String),
+}
+
+// fields that describe the connection, not the document; we set our own
+const HOP_BY_HOP: [&str; 4] = [
+    "Connection",
+    "Keep-Alive",
+    "Transfer-Encoding",
+    "Content-Length",
+];
+
+/// Splits the script output into its header block and body (RFC 3875 section 6)
+pub fn parse_cgi_output(output: &[u8]) -> Result<CgiOutput, String> {
+    // the block ends at the first empty line; scripts use \n or \r\n
+    let mut pos = 0;
+    let mut lines = Vec::new();
+    loop {
+        let Some(lf) = output[pos..].iter().position(|&b| b == b'\n') else {
+            return Err("no blank line after the header block".to_string());
+        };
+        let line = &output[pos..pos + lf];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        pos += lf + 1;
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return Err("empty header block".to_string());
+    }
+
+    let mut status: Option<(u16, String)> = None;
+    let mut location = None;
+    let mut headers = Headers::new();
+    for line in lines {
+        let line = std::str::from_utf8(line).map_err(|_| "header line is not UTF-8")?;
+        let (name, value) = line
+            .split_once(':')
+            .filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+            .ok_or_else(|| format!("malformed header line '{}'", line))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Status") {
+            // "Status: 404 Not Found", the reason may be left out
+            let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+            let code: u16 = code
+                .parse()
+                .ok()
+                .filter(|code| (100..=599).contains(code))
+                .ok_or_else(|| format!("invalid Status '{}'", value))?;
+            let reason = match reason.trim() {
+                "" => reason_phrase(code).to_string(),
+                reason => reason.to_string(),
+            };
+            status = Some((code, reason));
+        } else if name.eq_ignore_ascii_case("Location") {
+            location = Some(value.to_string());
+        } else if !HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            headers.append(name, value);
+        }
+    }
+    let body = output[pos..].to_vec();
+
+    if let Some(location) = location {
+        if location.starts_with('/') && status.is_none() {
+            return Ok(CgiOutput::LocalRedirect(location));
+        }
+        headers.insert("Location", location);
+        // a client redirect without Status is a 302
+        status.get_or_insert_with(|| (302, reason_phrase(302).to_string()));
+    }
+    let (status_code, reason_phrase) = status.unwrap_or((200, "OK".to_string()));
+    Ok(CgiOutput::Document(Response {
+        status_code,
+        reason_phrase,
+        headers,
+        body: body.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::{ResponseBody, parse_request_head};
+    use std::collections::HashMap;
+
+    fn request(raw: &str) -> Request {
+        let mut req = parse_request_head(raw.as_bytes()).unwrap().0;
+        req.remote_addr = Some("192.0.2.7:51000".parse().unwrap());
+        req.local_addr = Some("127.0.0.1:8080".parse().unwrap());
+        req
+    }
+
+    fn environment(req: &Request) -> Vec<(String, String)> {
+        let script = CgiScript {
+            path: PathBuf::from("/srv/cgi-bin/app.py"),
+            script_name: "/cgi-bin/app.py".to_string(),
+            path_info: String::new(),
+            interpreter: None,
+        };
+        let route = RouterConfig {
+            root: "/srv".to_string(),
+            ..Default::default()
+        };
+        cgi_environment(req, &ServerConfig::default(), &route, &script)
+    }
+
+    fn var<'a>(env: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        env.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn repeated_headers_are_joined() {
+        let req = request(
+            "GET /cgi-bin/app.py HTTP/1.1\r\nHost: a.test\r\nAccept: text/html\r\n\
+             X-Forwarded-For: 10.0.0.1\r\naccept: application/json\r\n\
+             Cookie: a=1\r\nCookie: b=2\r\n\r\n",
+        );
+        let env = environment(&req);
+        assert_eq!(
+            var(&env, "HTTP_ACCEPT"),
+            Some("text/html, application/json")
+        );
+        assert_eq!(var(&env, "HTTP_COOKIE"), Some("a=1; b=2"));
+        assert_eq!(var(&env, "HTTP_X_FORWARDED_FOR"), Some("10.0.0.1"));
+        assert_eq!(env.iter().filter(|(k, _)| k == "HTTP_ACCEPT").count(), 1);
+    }
+
+    #[test]
+    fn remote_host_is_the_address() {
+        let req = request("GET /cgi-bin/app.py HTTP/1.1\r\nHost: a.test:8080\r\n\r\n");
+        let env = environment(&req);
+        assert_eq!(var(&env, "REMOTE_ADDR"), Some("192.0.2.7"));
+        assert_eq!(var(&env, "REMOTE_HOST"), Some("192.0.2.7"));
+        assert_eq!(var(&env, "REMOTE_PORT"), Some("51000"));
+        assert_eq!(var(&env, "SERVER_NAME"), Some("a.test"));
+        assert_eq!(var(&env, "SERVER_PORT"), Some("8080"));
+    }
+
+    #[test]
+    fn credentials_and_proxy_are_not_passed() {
+        let req = request(
+            "POST /cgi-bin/app.py HTTP/1.1\r\nHost: a\r\nAuthorization: Basic eA==\r\n\
+             Proxy: http://evil\r\nContent-Type: text/plain\r\nContent-Length: 0\r\n\r\n",
+        );
+        let env = environment(&req);
+        assert_eq!(var(&env, "HTTP_AUTHORIZATION"), None);
+        assert_eq!(var(&env, "HTTP_PROXY"), None);
+        assert_eq!(var(&env, "HTTP_CONTENT_TYPE"), None);
+        assert_eq!(var(&env, "CONTENT_TYPE"), Some("text/plain"));
+        assert_eq!(var(&env, "CONTENT_LENGTH"), Some("0"));
+    }
+
+    // a root holding cgi-bin/app.py (run by the map), cgi-bin/run.sh (a
+    // "#!" script), cgi-bin/plain.sh (same, not executable) and cgi-bin/sub/
+    fn cgi_root(name: &str) -> RouterConfig {
+        let root =
+            std::env::temp_dir().join(format!("localhost-cgi-{}-{}", std::process::id(), name));
+        let bin = root.join("cgi-bin");
+        fs::create_dir_all(bin.join("sub")).unwrap();
+        fs::write(bin.join("app.py"), "print('hi')\n").unwrap();
+        for script in ["run.sh", "plain.sh"] {
+            fs::write(bin.join(script), "#!/bin/sh\necho\n").unwrap();
+        }
+        fs::set_permissions(bin.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(bin.join("plain.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+        RouterConfig {
+            root: root.to_string_lossy().into_owned(),
+            cgi: HashMap::from([(".py".to_string(), "/usr/bin/python3 -u".to_string())]),
+            cgi_extensions: Some(vec![".py".to_string(), ".sh".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    fn script(found: CgiMatch) -> CgiScript {
+        match found {
+            CgiMatch::Script(script) => script,
+            CgiMatch::Forbidden => panic!("forbidden"),
+            CgiMatch::NotCgi => panic!("not a script"),
+        }
+    }
+
+    #[test]
+    fn interpreter_map_and_direct_executables() {
+        let route = cgi_root("run");
+        let app = script(find_script(&route, "/cgi-bin/app.py"));
+        assert_eq!(
+            app.interpreter,
+            Some(vec!["/usr/bin/python3".to_string(), "-u".to_string()])
+        );
+        let run = script(find_script(&route, "/cgi-bin/run.sh"));
+        assert_eq!(run.interpreter, None);
+        assert!(runs_directly(&run.path));
+        // allowed, but nothing in the map runs it and it can't run itself
+        assert!(!runs_directly(&app.path.with_file_name("plain.sh")));
+        assert!(matches!(
+            find_script(&route, "/cgi-bin/plain.sh"),
+            CgiMatch::Forbidden
+        ));
+
+        // in the map but not allowed: never served as source
+        let route = RouterConfig {
+            cgi_extensions: Some(vec![".sh".to_string()]),
+            ..route
+        };
+        assert!(matches!(
+            find_script(&route, "/cgi-bin/app.py"),
+            CgiMatch::Forbidden
+        ));
+        assert!(matches!(
+            find_script(&route, "/cgi-bin/missing.sh"),
+            CgiMatch::NotCgi
+        ));
+    }
+
+    #[test]
+    fn path_info_split() {
+        let route = cgi_root("locate");
+        let (path, script_name, path_info) = locate(&route, "/cgi-bin/app.py/users/1").unwrap();
+        assert!(path.ends_with("cgi-bin/app.py"));
+        assert_eq!((script_name, path_info), ("/cgi-bin/app.py", "/users/1"));
+        let (_, script_name, path_info) = locate(&route, "/cgi-bin/sub/../app.py/").unwrap();
+        assert_eq!((script_name, path_info), ("/cgi-bin/sub/../app.py", "/"));
+        assert!(locate(&route, "/cgi-bin/sub/").is_none());
+        assert!(locate(&route, "/cgi-bin/../../../etc/passwd").is_none());
+
+        let found = script(find_script(&route, "/cgi-bin/app.py/a/b.py"));
+        assert_eq!(found.script_name, "/cgi-bin/app.py");
+        assert_eq!(found.path_info, "/a/b.py");
+    }
+
+    fn document(output: &[u8]) -> Response {
+        match parse_cgi_output(output).unwrap() {
+            CgiOutput::Document(response) => response,
+            CgiOutput::LocalRedirect(path) => panic!("local redirect to {}", path),
+        }
+    }
+
+    #[test]
+    fn status_and_location() {
+        let response = document(b"Status: 404\r\nContent-Type: text/plain\r\n\r\ngone");
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response.reason_phrase, "Not Found");
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+
+        let response = document(b"Status: 201 Made\r\nContent-Length: 99\r\n\r\n");
+        assert_eq!(
+            (response.status_code, response.reason_phrase.as_str()),
+            (201, "Made")
+        );
+        assert_eq!(response.headers.get("Content-Length"), None);
+
+        // a local path without Status is answered by the server itself
+        assert!(matches!(
+            parse_cgi_output(b"Location: /other?x=1\r\n\r\n").unwrap(),
+            CgiOutput::LocalRedirect(path) if path == "/other?x=1"
+        ));
+        // a URL is a client redirect, 302 unless Status says otherwise
+        let response = document(b"Location: http://a.test/\r\n\r\n");
+        assert_eq!(response.status_code, 302);
+        assert_eq!(response.headers.get("Location"), Some("http://a.test/"));
+        let response = document(b"Status: 301\r\nLocation: /moved\r\n\r\n");
+        assert_eq!(response.status_code, 301);
+        assert_eq!(response.headers.get("Location"), Some("/moved"));
+    }
+
+    #[test]
+    fn bare_lf_header_block() {
+        let output = b"Content-Type: text/html\nX-A: 1\n\n<p>\r\n\r\n</p>";
+        assert_eq!(header_block_end(output), Some(32));
+        assert_eq!(
+            header_block_end(b"Content-Type: text/html\r\n\r\nx"),
+            Some(27)
+        );
+        assert_eq!(header_block_end(b"Content-Type: text/html\n"), None);
+        let response = document(output);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.headers.get("X-A"), Some("1"));
+        assert!(matches!(response.body, ResponseBody::Bytes(body) if body == b"<p>\r\n\r\n</p>"));
+    }
+
+    #[test]
+    fn malformed_header_block() {
+        for output in [
+            &b"Content-Type: text/html\r\n"[..],
+            b"\r\nbody",
+            b"no colon\r\n\r\n",
+            b"Bad Name: x\r\n\r\n",
+            b": x\r\n\r\n",
+            b"Status: 99\r\n\r\n",
+            b"Status: abc\r\n\r\n",
+            b"X: \xff\r\n\r\n",
+        ] {
+            assert!(
+                parse_cgi_output(output).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(output)
+            );
+        }
     }
 }
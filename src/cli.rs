@@ -0,0 +1,214 @@
+// # خيارات سطر الأوامر
+
+use crate::serverConfig::{ServerAddress, ServerConfig};
+use std::path::PathBuf;
+
+/// read when no config file is named, relative to the working directory
+pub const DEFAULT_CONFIG: &str = "src/config.json";
+
+pub const USAGE: &str = "\
+Usage: localhost [OPTIONS] [CONFIG]
+
+Options:
+  -c, --config <PATH>    config file to load (default: src/config.json)
+  -t, --test             check the config file and exit
+  -l, --listen <IP:PORT> listen only on this address, for every server block
+                         (may be given more than once)
+      --ip <IP>          listen on this IP instead, keeping the configured ports
+  -h, --help             print this help
+";
+
+#[derive(Debug)]
+pub struct CliOptions {
+    pub config_path: PathBuf,
+    pub test_config: bool,
+    pub listen: Vec<ServerAddress>,
+    pub ip: Option<String>,
+    pub help: bool,
+}
+
+/// parses the arguments after the program name
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliOptions, String> {
+    let mut options = CliOptions {
+        config_path: PathBuf::from(DEFAULT_CONFIG),
+        test_config: false,
+        listen: Vec::new(),
+        ip: None,
+        help: false,
+    };
+    let mut config_given = false;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // accept both "--config path" and "--config=path"
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| -> Result<String, String> {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("option '{}' needs a value", name))
+        };
+        match flag.as_str() {
+            "-c" | "--config" => {
+                options.config_path = PathBuf::from(value(&flag)?);
+                config_given = true;
+            }
+            "-t" | "--test" => options.test_config = true,
+            "-l" | "--listen" => {
+                let address = value(&flag)?;
+                options.listen.push(parse_listen(&address)?);
+            }
+            "--ip" => {
+                let ip = value(&flag)?;
+                if ip.parse::<std::net::IpAddr>().is_err() {
+                    return Err(format!("invalid IP address '{}'", ip));
+                }
+                options.ip = Some(ip);
+            }
+            "-h" | "--help" => options.help = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if !config_given => {
+                options.config_path = PathBuf::from(arg);
+                config_given = true;
+            }
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// "127.0.0.1:8080" or "[::1]:8080"
+fn parse_listen(address: &str) -> Result<ServerAddress, String> {
+    let socket_addr: std::net::SocketAddr = address
+        .parse()
+        .map_err(|_| format!("invalid listen address '{}', expected IP:PORT", address))?;
+    Ok(ServerAddress {
+        ip: socket_addr.ip().to_string(),
+        port: socket_addr.port(),
+        tls: None,
+    })
+}
+
+/// applies --listen and --ip to every server block. --listen can't stand in
+/// for an HTTPS address: its certificate would be dropped and the address
+/// served in the clear.
+pub fn apply_overrides(options: &CliOptions, servers: &mut [ServerConfig]) -> Result<(), String> {
+    for server in servers.iter_mut() {
+        if !options.listen.is_empty() {
+            if let Some(address) = server.server_address.iter().find(|a| a.tls.is_some()) {
+                return Err(format!(
+                    "--listen would serve {}:{} ({}) without TLS; use --ip or edit the config",
+                    address.ip, address.port, server.server_name
+                ));
+            }
+            server.server_address = options.listen.clone();
+        }
+        if let Some(ip) = &options.ip {
+            for address in server.server_address.iter_mut() {
+                address.ip = ip.clone();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serverConfig::TlsConfig;
+
+    fn parse(args: &[&str]) -> Result<CliOptions, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    fn address(ip: &str, port: u16, tls: bool) -> ServerAddress {
+        ServerAddress {
+            ip: ip.to_string(),
+            port,
+            tls: tls.then(|| TlsConfig {
+                cert: "a.crt".to_string(),
+                key: "a.key".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn config_path() {
+        assert_eq!(
+            parse(&[]).unwrap().config_path,
+            PathBuf::from(DEFAULT_CONFIG)
+        );
+        assert_eq!(
+            parse(&["--config=x.conf"]).unwrap().config_path,
+            PathBuf::from("x.conf")
+        );
+        assert_eq!(
+            parse(&["-c", "y.json"]).unwrap().config_path,
+            PathBuf::from("y.json")
+        );
+        let options = parse(&["-t", "site.conf"]).unwrap();
+        assert!(options.test_config);
+        assert_eq!(options.config_path, PathBuf::from("site.conf"));
+        assert_eq!(
+            parse(&["a.conf", "b.conf"]).unwrap_err(),
+            "unexpected argument 'b.conf'"
+        );
+    }
+
+    #[test]
+    fn listen_more_than_once() {
+        let options = parse(&["-l", "0.0.0.0:80", "--listen=[::1]:8080"]).unwrap();
+        assert_eq!(
+            options.listen,
+            [address("0.0.0.0", 80, false), address("::1", 8080, false)]
+        );
+        assert!(parse(&["-l", "localhost:80"]).is_err());
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert_eq!(
+            parse(&["--ip", "1.2.3"]).unwrap_err(),
+            "invalid IP address '1.2.3'"
+        );
+        assert_eq!(parse(&["--ip", "::1"]).unwrap().ip.as_deref(), Some("::1"));
+        assert_eq!(parse(&["--port"]).unwrap_err(), "unknown option '--port'");
+        assert_eq!(parse(&["-c"]).unwrap_err(), "option '-c' needs a value");
+        assert_eq!(
+            parse(&["--listen"]).unwrap_err(),
+            "option '--listen' needs a value"
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let server = |addresses| ServerConfig {
+            server_name: "a.test".to_string(),
+            server_address: addresses,
+            ..Default::default()
+        };
+        let mut servers = [server(vec![address("127.0.0.1", 8080, false)])];
+        let options = parse(&["-l", "0.0.0.0:80", "-l", "0.0.0.0:81"]).unwrap();
+        apply_overrides(&options, &mut servers).unwrap();
+        assert_eq!(
+            servers[0].server_address,
+            [address("0.0.0.0", 80, false), address("0.0.0.0", 81, false)]
+        );
+
+        // --ip keeps the port and the certificate, --listen refuses to drop it
+        let mut servers = [server(vec![address("127.0.0.1", 8443, true)])];
+        apply_overrides(&parse(&["--ip", "0.0.0.0"]).unwrap(), &mut servers).unwrap();
+        assert_eq!(servers[0].server_address, [address("0.0.0.0", 8443, true)]);
+        let error = apply_overrides(&options, &mut servers).unwrap_err();
+        assert!(
+            error.contains("0.0.0.0:8443 (a.test) without TLS"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,418 @@
+// # ضغط الردود (gzip / deflate / br)
+//
+// The encoding is picked from Accept-Encoding. Bodies are compressed when the
+// route has `compression`, the type is in its list and the body is big enough;
+// a file.br / file.gz next to a static file is sent instead when there is one
+// and it is not older than the file.
+
+use crate::conditional::Validators;
+use crate::requests::{FileBody, Request, Response, ResponseBody};
+use crate::serverConfig::CompressionConfig;
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MIN_LENGTH: u64 = 1024;
+const DEFAULT_TYPES: [&str; 8] = [
+    "text/html",
+    "text/css",
+    "text/plain",
+    "text/javascript",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+// bodies above this are only sent compressed when a precompressed copy exists;
+// compressing happens in one go on the event loop, which waits for it
+const MAX_DYNAMIC_SIZE: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    // file name suffix of a precompressed copy
+    fn suffix(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some(".br"),
+            Encoding::Gzip => Some(".gz"),
+            Encoding::Deflate => None,
+        }
+    }
+}
+
+/// Encodings the client accepts, best first (our preference among equal q-values)
+pub fn accepted_encodings(req: &Request) -> Vec<Encoding> {
+    let Some(header) = req.header("Accept-Encoding") else {
+        return Vec::new();
+    };
+    let mut wildcard = None;
+    let mut listed: Vec<(&str, f32)> = Vec::new();
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if coding == "*" {
+            wildcard = Some(q);
+        } else if !coding.is_empty() {
+            listed.push((coding, q));
+        }
+    }
+    let mut accepted: Vec<(Encoding, f32)> = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate]
+        .into_iter()
+        .filter_map(|encoding| {
+            let q = listed
+                .iter()
+                .find(|(coding, _)| {
+                    coding.eq_ignore_ascii_case(encoding.name())
+                        || (encoding == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+                })
+                .map(|(_, q)| *q)
+                .or(wildcard)?;
+            (q > 0.0).then_some((encoding, q))
+        })
+        .collect();
+    // stable sort keeps br > gzip > deflate for equal q
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    accepted.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// A precompressed copy of a static file, with validators of its own
+pub struct Precompressed {
+    encoding: Encoding,
+    file: File,
+    len: u64,
+    pub validators: Validators,
+}
+
+/// The sibling (`path`.br or `path`.gz) to send instead of the file when the
+/// client accepts its encoding. One older than the file is stale and skipped.
+/// Its ETag is the file's with the encoding added, so the two byte sequences
+/// never share a validator.
+pub fn precompressed(
+    req: &Request,
+    config: &CompressionConfig,
+    path: &Path,
+    original: &Validators,
+) -> Option<Precompressed> {
+    if !config.precompressed.unwrap_or(true) {
+        return None;
+    }
+    accepted_encodings(req).into_iter().find_map(|encoding| {
+        let suffix = encoding.suffix()?;
+        let mut sibling = PathBuf::from(path).into_os_string();
+        sibling.push(suffix);
+        let file = File::open(sibling).ok()?;
+        let metadata = file.metadata().ok()?;
+        let sibling_validators = Validators::from_metadata(&metadata);
+        if !metadata.is_file() || sibling_validators.modified < original.modified {
+            return None;
+        }
+        let etag = original.etag.trim_end_matches('"');
+        Some(Precompressed {
+            encoding,
+            file,
+            len: metadata.len(),
+            validators: Validators {
+                etag: format!("{}-{}\"", etag, suffix.trim_start_matches('.')),
+                modified: sibling_validators.modified,
+            },
+        })
+    })
+}
+
+/// Swaps the body of a static file's 200 for its precompressed copy
+pub fn serve_precompressed(sibling: Precompressed, response: &mut Response) {
+    response.body = ResponseBody::File(FileBody {
+        file: sibling.file,
+        offset: 0,
+        len: sibling.len,
+    });
+    response.headers.insert(
+        "Content-Encoding".to_string(),
+        sibling.encoding.name().to_string(),
+    );
+    response
+        .headers
+        .insert("ETag".to_string(), sibling.validators.etag.clone());
+    response.headers.insert(
+        "Last-Modified".to_string(),
+        sibling.validators.last_modified(),
+    );
+    response.headers.remove("Accept-Ranges");
+}
+
+/// Compresses a 200 response when the route config and Accept-Encoding allow it.
+/// Every response of the route gets `Vary: Accept-Encoding`, 304s and 206s
+/// included, so caches keep the encodings of a resource apart.
+pub fn apply(req: &Request, config: &CompressionConfig, response: &mut Response) {
+    add_vary(response);
+    if response.status_code != 200 {
+        return;
+    }
+    let already_encoded = response.headers.contains_key("Content-Encoding");
+    let content_type = response
+        .headers
+        .get("Content-Type")
+        .map(|t| {
+            t.split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .unwrap_or_default();
+    let compressible = match &config.types {
+        Some(types) => types.iter().any(|t| t.eq_ignore_ascii_case(&content_type)),
+        None => DEFAULT_TYPES.contains(&content_type.as_str()),
+    };
+    if already_encoded || !compressible {
+        return;
+    }
+
+    let len = response.body.len();
+    if len < config.min_length.unwrap_or(DEFAULT_MIN_LENGTH) || len > MAX_DYNAMIC_SIZE {
+        return;
+    }
+    let Some(encoding) = accepted_encodings(req).into_iter().next() else {
+        return;
+    };
+    let body = match &response.body {
+        ResponseBody::Bytes(bytes) => compress(encoding, config, bytes),
+        ResponseBody::File(file) => {
+            let mut contents = vec![0; file.len as usize];
+            file.file
+                .read_exact_at(&mut contents, file.offset)
+                .and_then(|_| compress(encoding, config, &contents))
+        }
+        ResponseBody::Parts(_) | ResponseBody::Stream(_) => return,
+    };
+    match body {
+        Ok(body) => {
+            response.body = body.into();
+            response
+                .headers
+                .insert("Content-Encoding".to_string(), encoding.name().to_string());
+            // ranges of the compressed bytes are not offered
+            response.headers.remove("Accept-Ranges");
+        }
+        Err(e) => eprintln!("Compression failed, sending as is: {}", e),
+    }
+}
+
+fn add_vary(response: &mut Response) {
+    let vary = response.headers.get("Vary").unwrap_or("");
+    if vary
+        .split(',')
+        .any(|v| v.trim().eq_ignore_ascii_case("Accept-Encoding"))
+    {
+        return;
+    }
+    let vary = if vary.is_empty() {
+        "Accept-Encoding".to_string()
+    } else {
+        format!("{}, Accept-Encoding", vary)
+    };
+    response.headers.insert("Vary", vary);
+}
+
+fn compress(encoding: Encoding, config: &CompressionConfig, data: &[u8]) -> io::Result<Vec<u8>> {
+    let level = config.level.unwrap_or(DEFAULT_LEVEL);
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level.min(9)));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            // "deflate" in HTTP is the zlib format
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level.min(9)));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level.min(11), 22);
+            encoder.write_all(data)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::parse_request_head;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn request(accept_encoding: &str) -> Request {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {}\r\n\r\n",
+            accept_encoding
+        );
+        parse_request_head(raw.as_bytes()).unwrap().0
+    }
+
+    fn names(accept_encoding: &str) -> Vec<&'static str> {
+        accepted_encodings(&request(accept_encoding))
+            .into_iter()
+            .map(Encoding::name)
+            .collect()
+    }
+
+    #[test]
+    fn accept_encoding_order() {
+        assert_eq!(names("gzip, deflate, br"), ["br", "gzip", "deflate"]);
+        assert_eq!(names("gzip;q=1.0, br;q=0.5"), ["gzip", "br"]);
+        assert_eq!(names("x-gzip"), ["gzip"]);
+        assert_eq!(names("GZIP;Q=0.8"), ["gzip"]);
+    }
+
+    #[test]
+    fn accept_encoding_refusals() {
+        assert!(names("identity").is_empty());
+        assert!(names("gzip;q=0").is_empty());
+        assert_eq!(names("*;q=0.5, br;q=0"), ["gzip", "deflate"]);
+        assert!(names("*;q=0").is_empty());
+        assert_eq!(names("gzip;q=abc"), ["gzip"]);
+    }
+
+    #[test]
+    fn no_accept_encoding() {
+        let raw = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        let req = parse_request_head(raw).unwrap().0;
+        assert!(accepted_encodings(&req).is_empty());
+    }
+
+    #[test]
+    fn compresses_text() {
+        let body = b"hello world ".repeat(200);
+        let mut response = Response::html(200, "OK", body.clone());
+        apply(
+            &request("gzip"),
+            &CompressionConfig::default(),
+            &mut response,
+        );
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        let ResponseBody::Bytes(compressed) = &response.body else {
+            panic!("expected bytes");
+        };
+        let mut decoded = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn small_and_large_bodies_stay_as_they_are() {
+        for len in [10, MAX_DYNAMIC_SIZE as usize + 1] {
+            let mut response = Response::html(200, "OK", vec![b'a'; len]);
+            apply(
+                &request("gzip"),
+                &CompressionConfig::default(),
+                &mut response,
+            );
+            assert_eq!(response.headers.get("Content-Encoding"), None);
+            assert_eq!(response.body.len(), len as u64);
+        }
+    }
+
+    #[test]
+    fn vary_on_every_status() {
+        for status in [200, 206, 304, 404] {
+            let mut response = Response::html(status, "", Vec::new());
+            response.headers.insert("Vary", "Origin");
+            apply(
+                &request("gzip"),
+                &CompressionConfig::default(),
+                &mut response,
+            );
+            assert_eq!(
+                response.headers.get("Vary"),
+                Some("Origin, Accept-Encoding")
+            );
+            apply(
+                &request("gzip"),
+                &CompressionConfig::default(),
+                &mut response,
+            );
+            assert_eq!(
+                response.headers.get("Vary"),
+                Some("Origin, Accept-Encoding")
+            );
+        }
+    }
+
+    // a file and its .gz copy, the copy `age` seconds older than the file
+    fn file_with_copy(name: &str, age: i64) -> (PathBuf, Validators) {
+        let path =
+            std::env::temp_dir().join(format!("localhost-{}-{}.txt", std::process::id(), name));
+        std::fs::write(&path, "plain").unwrap();
+        let copy = File::create(format!("{}.gz", path.display())).unwrap();
+        copy.write_all_at(b"gzipped", 0).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let copy_time = if age >= 0 {
+            modified - std::time::Duration::from_secs(age as u64)
+        } else {
+            modified + std::time::Duration::from_secs(-age as u64)
+        };
+        copy.set_modified(copy_time).unwrap();
+        let validators = Validators::from_metadata(&std::fs::metadata(&path).unwrap());
+        (path, validators)
+    }
+
+    #[test]
+    fn precompressed_copy_has_its_own_etag() {
+        let (path, original) = file_with_copy("fresh", -5);
+        let config = CompressionConfig::default();
+        assert!(precompressed(&request("br"), &config, &path, &original).is_none());
+        let sibling = precompressed(&request("br, gzip"), &config, &path, &original).unwrap();
+        let expected = format!("{}-gz\"", original.etag.trim_end_matches('"'));
+        assert_eq!(sibling.validators.etag, expected);
+        assert!(sibling.validators.modified >= original.modified);
+
+        let mut response = Response::html(200, "OK", b"plain".to_vec());
+        response.headers.insert("ETag", original.etag.as_str());
+        response.headers.insert("Accept-Ranges", "bytes");
+        serve_precompressed(sibling, &mut response);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("ETag"), Some(expected.as_str()));
+        assert_eq!(response.headers.get("Accept-Ranges"), None);
+        assert_eq!(response.body.len(), 7);
+    }
+
+    #[test]
+    fn stale_copy_is_skipped() {
+        let (path, original) = file_with_copy("stale", 60);
+        let config = CompressionConfig::default();
+        assert!(precompressed(&request("gzip"), &config, &path, &original).is_none());
+        let off = CompressionConfig {
+            precompressed: Some(false),
+            ..Default::default()
+        };
+        let (path, original) = file_with_copy("off", -5);
+        assert!(precompressed(&request("gzip"), &off, &path, &original).is_none());
+    }
+}
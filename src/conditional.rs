@@ -0,0 +1,369 @@
+// # الطلبات المشروطة (ETag و Last-Modified)
+//
+// Validators for static files and the precondition checks of RFC 9110 13.2.2:
+// If-Match, If-Unmodified-Since, If-None-Match, If-Modified-Since, in that order.
+
+use crate::requests::Request;
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// ETag and modification time of a file, the time in whole seconds like HTTP dates
+pub struct Validators {
+    pub etag: String,
+    pub modified: u64, // seconds since the epoch
+}
+
+impl Validators {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Validators {
+            // weak: size and mtime say the content is the same, not the bytes
+            etag: format!("W/\"{:x}-{:x}\"", metadata.len(), modified),
+            modified,
+        }
+    }
+
+    pub fn last_modified(&self) -> String {
+        http_date(UNIX_EPOCH + Duration::from_secs(self.modified))
+    }
+}
+
+pub enum Precondition {
+    Proceed,
+    NotModified, // 304, GET and HEAD only
+    Failed,      // 412
+}
+
+/// Evaluates the conditional headers of `req` against the file's validators
+pub fn evaluate(req: &Request, validators: &Validators) -> Precondition {
+    let safe = req.method == "GET" || req.method == "HEAD";
+
+    if let Some(if_match) = req.header("If-Match") {
+        // strong comparison (RFC 9110 13.1.1): our weak ETags only pass as "*"
+        if !strong_etag_matches(if_match, &validators.etag) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = req.header("If-Unmodified-Since").and_then(parse_http_date)
+        && validators.modified > since
+    {
+        return Precondition::Failed;
+    }
+
+    if let Some(if_none_match) = req.header("If-None-Match") {
+        if etag_matches(if_none_match, &validators.etag) {
+            return if safe {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if safe
+        && let Some(since) = req.header("If-Modified-Since").and_then(parse_http_date)
+        && validators.modified <= since
+    {
+        return Precondition::NotModified;
+    }
+    Precondition::Proceed
+}
+
+/// If-Range: a range request only applies while the client's copy is current;
+/// true when there is no If-Range or it names the current ETag or Last-Modified
+pub fn if_range_matches(req: &Request, validators: &Validators) -> bool {
+    match req.header("If-Range").map(str::trim) {
+        None => true,
+        Some(value) if value.starts_with('"') || value.starts_with("W/") => {
+            // strong comparison (RFC 9110 13.1.5): a weak ETag never gives a 206
+            strong_etag_matches(value, &validators.etag)
+        }
+        Some(value) => parse_http_date(value) == Some(validators.modified),
+    }
+}
+
+// "*" or a list of entity tags, compared without the W/ prefix
+fn etag_matches(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let header = header.trim();
+    header == "*" || header.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+// "*" or a list of entity tags, equal to ours with neither of them weak
+fn strong_etag_matches(header: &str, etag: &str) -> bool {
+    let header = header.trim();
+    header == "*"
+        || (!etag.starts_with("W/")
+            && header
+                .split(',')
+                .map(str::trim)
+                .any(|tag| !tag.starts_with("W/") && tag == etag))
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"]; // 1970-01-01 was a Thursday
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses an IMF-fixdate into seconds since the epoch; other formats and
+/// dates out of range give None and the header is then ignored, as RFC 9110 asks
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let (_, rest) = value.trim().split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day = digits(parts.next()?, 2)? as u32;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year = digits(parts.next()?, 4)? as i64;
+    let mut clock = parts.next()?.split(':').map(|n| digits(n, 2));
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    if clock.next().is_some() || parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day)?;
+    // 31 Feb and the like would land on another day
+    if days < 0 || civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    (days as u64)
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)
+}
+
+// exactly `len` ASCII digits
+fn digits(value: &str, len: usize) -> Option<u64> {
+    if value.len() != len || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// days since 1970-01-01 -> (year, month, day), Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// None when the year is too far out for the arithmetic
+fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era.checked_mul(146097)?.checked_add(doe - 719468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::parse_request_head;
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{} / HTTP/1.1\r\nHost: x\r\n{}\r\n", method, headers);
+        parse_request_head(raw.as_bytes()).unwrap().0
+    }
+
+    fn validators(etag: &str) -> Validators {
+        Validators {
+            etag: etag.to_string(),
+            modified: 784111777,
+        }
+    }
+
+    #[test]
+    fn parses_imf_fixdate() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+    }
+
+    #[test]
+    fn formats_and_parses_back() {
+        for secs in [0, 784111777, 951782400, 4102444799] {
+            let date = http_date(UNIX_EPOCH + Duration::from_secs(secs));
+            assert_eq!(parse_http_date(&date), Some(secs), "{}", date);
+        }
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(784111777)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_and_other_formats() {
+        for value in [
+            "Sun, 06 Nov 99999999999999999999 08:49:37 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sun, 30 Feb 2001 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49:37:01 GMT",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, +6 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn bad_date_is_an_absent_header() {
+        let req = request(
+            "GET",
+            "If-Modified-Since: Sun, 06 Nov 99999999999 08:49:37 GMT\r\n",
+        );
+        assert!(matches!(
+            evaluate(&req, &validators("W/\"a\"")),
+            Precondition::Proceed
+        ));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let req = request("GET", "If-None-Match: \"x\", \"a\"\r\n");
+        assert!(matches!(
+            evaluate(&req, &validators("W/\"a\"")),
+            Precondition::NotModified
+        ));
+        let req = request("DELETE", "If-None-Match: *\r\n");
+        assert!(matches!(
+            evaluate(&req, &validators("W/\"a\"")),
+            Precondition::Failed
+        ));
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        let weak = validators("W/\"a\"");
+        for header in ["If-Match: W/\"a\"\r\n", "If-Match: \"a\"\r\n"] {
+            let req = request("PUT", header);
+            assert!(matches!(evaluate(&req, &weak), Precondition::Failed));
+        }
+        let req = request("PUT", "If-Match: *\r\n");
+        assert!(matches!(evaluate(&req, &weak), Precondition::Proceed));
+        let req = request("PUT", "If-Match: \"b\", \"a\"\r\n");
+        assert!(matches!(
+            evaluate(&req, &validators("\"a\"")),
+            Precondition::Proceed
+        ));
+    }
+
+    #[test]
+    fn if_range_compares_strongly() {
+        let weak = validators("W/\"a\"");
+        for header in ["If-Range: W/\"a\"\r\n", "If-Range: \"a\"\r\n"] {
+            assert!(!if_range_matches(&request("GET", header), &weak));
+        }
+        let strong = validators("\"a\"");
+        assert!(if_range_matches(
+            &request("GET", "If-Range: \"a\"\r\n"),
+            &strong
+        ));
+        assert!(!if_range_matches(
+            &request("GET", "If-Range: W/\"a\"\r\n"),
+            &strong
+        ));
+        assert!(!if_range_matches(
+            &request("GET", "If-Range: \"b\"\r\n"),
+            &strong
+        ));
+        let date = "If-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        assert!(if_range_matches(&request("GET", date), &weak));
+        assert!(if_range_matches(&request("GET", ""), &weak));
+    }
+
+    // Our ETags are weak and If-Match compares strongly, so a DELETE sent back
+    // with the ETag a GET gave always fails. Only "*" or the date work, and
+    // If-Range has to use Last-Modified.
+    #[test]
+    fn served_etag_never_satisfies_if_match() {
+        let path = std::env::temp_dir().join(format!("localhost-etag-{}", std::process::id()));
+        std::fs::write(&path, "content").unwrap();
+        let served = Validators::from_metadata(&std::fs::metadata(&path).unwrap());
+        assert!(served.etag.starts_with("W/"));
+
+        let header = |name: &str, value: &str| format!("{}: {}\r\n", name, value);
+        let delete = |headers: String| evaluate(&request("DELETE", &headers), &served);
+        assert!(matches!(
+            delete(header("If-Match", &served.etag)),
+            Precondition::Failed
+        ));
+        assert!(matches!(
+            delete(header("If-Match", "*")),
+            Precondition::Proceed
+        ));
+        assert!(matches!(
+            delete(header("If-Unmodified-Since", &served.last_modified())),
+            Precondition::Proceed
+        ));
+
+        let range = |headers: String| if_range_matches(&request("GET", &headers), &served);
+        assert!(!range(header("If-Range", &served.etag)));
+        assert!(range(header("If-Range", &served.last_modified())));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let v = validators("W/\"a\"");
+        let req = request(
+            "GET",
+            "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n",
+        );
+        assert!(matches!(evaluate(&req, &v), Precondition::NotModified));
+        let req = request(
+            "GET",
+            "If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n",
+        );
+        assert!(matches!(evaluate(&req, &v), Precondition::Proceed));
+        let req = request(
+            "POST",
+            "If-Unmodified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n",
+        );
+        assert!(matches!(evaluate(&req, &v), Precondition::Failed));
+    }
+}
@@ -0,0 +1,636 @@
+// # فحص الإعدادات قبل تشغيل السيرفر
+//
+// serde only checks the shape of the config. This pass checks the values and
+// collects every problem, each with the JSON path of the offending field.
+
+use crate::fastcgi;
+use crate::mime;
+use crate::proxy;
+use crate::serverConfig::{RouterConfig, ServerConfig, UpstreamConfig};
+use crate::tls;
+use crate::upstream;
+use crate::websocket;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const KNOWN_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "CONNECT", "TRACE",
+];
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+#[derive(Debug)]
+pub struct ConfigIssue {
+    pub path: String, // e.g. [0].router[2].redirection.status
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// checks every server block and returns all problems found
+pub fn validate_config(servers: &[ServerConfig]) -> Result<(), Vec<ConfigIssue>> {
+    let mut issues = Vec::new();
+    let mut issue = |path: String, message: String| issues.push(ConfigIssue { path, message });
+
+    if servers.is_empty() {
+        issue("$".to_string(), "no server block defined".to_string());
+    }
+
+    // (address, server index, address index) of every valid listen address
+    let mut bound: Vec<(SocketAddr, usize, usize)> = Vec::new();
+
+    for (i, server) in servers.iter().enumerate() {
+        let base = format!("[{}]", i);
+
+        if server.server_address.is_empty() {
+            issue(
+                format!("{}.server_address", base),
+                "at least one address is required".to_string(),
+            );
+        }
+        for (a, address) in server.server_address.iter().enumerate() {
+            let path = format!("{}.server_address[{}]", base, a);
+            let ip = match address.ip.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    issue(
+                        format!("{}.ip", path),
+                        format!("'{}' is not a valid IP address", address.ip),
+                    );
+                    continue;
+                }
+            };
+            if address.port == 0 {
+                issue(
+                    format!("{}.port", path),
+                    "port 0 is not allowed".to_string(),
+                );
+                continue;
+            }
+            if let Some(Err(e)) = address.tls.as_ref().map(tls::load_key) {
+                issue(format!("{}.tls", path), e);
+            }
+            bound.push((SocketAddr::new(ip, address.port), i, a));
+        }
+
+        for code in server.error_msg.keys() {
+            if !(400..=599).contains(code) {
+                issue(
+                    format!("{}.error_msg.{}", base, code),
+                    "error pages can only be set for 4xx and 5xx status codes".to_string(),
+                );
+            }
+        }
+        if server.keepalive_requests == Some(0) {
+            issue(
+                format!("{}.keepalive_requests", base),
+                "must be at least 1".to_string(),
+            );
+        }
+
+        for (extension, mime_type) in &server.mime_types {
+            let path = format!("{}.mime_types.{}", base, extension);
+            if extension.is_empty() || extension.contains(['.', '/']) {
+                issue(
+                    path.clone(),
+                    format!("'{}' must be an extension without the dot", extension),
+                );
+            }
+            if !mime::is_valid(mime_type) {
+                issue(
+                    path,
+                    format!("'{}' is not a MIME type (type/subtype)", mime_type),
+                );
+            }
+        }
+
+        for (name, upstream) in &server.upstreams {
+            check_upstream(
+                &format!("{}.upstreams.{}", base, name),
+                upstream,
+                &mut issue,
+            );
+        }
+
+        if server.router.is_empty() {
+            issue(format!("{}.router", base), "no routes defined".to_string());
+        }
+        for (r, route) in server.router.iter().enumerate() {
+            let path = format!("{}.router[{}]", base, r);
+            if server.router[..r]
+                .iter()
+                .any(|other| other.path == route.path)
+            {
+                issue(
+                    format!("{}.path", path),
+                    format!("route '{}' is defined more than once", route.path),
+                );
+            }
+            check_route(&path, route, server, &mut issue);
+        }
+    }
+
+    // the same ip:port may be shared by several blocks only when Host can tell
+    // them apart, and a wildcard address can't be combined with a specific one
+    for (n, (addr, i, a)) in bound.iter().enumerate() {
+        let path = format!("[{}].server_address[{}]", i, a);
+        let secure = servers[*i].server_address[*a].tls.is_some();
+        for (other_addr, j, b) in &bound[..n] {
+            if addr.port() != other_addr.port() {
+                continue;
+            }
+            if addr.ip() == other_addr.ip() {
+                if secure != servers[*j].server_address[*b].tls.is_some() {
+                    issue(
+                        format!("{}.tls", path),
+                        format!("{} is used by [{}] with TLS on one side only", addr, j),
+                    );
+                } else if i == j {
+                    issue(
+                        path.clone(),
+                        format!("{} is listed twice in this server", addr),
+                    );
+                } else if servers[*i].server_name == servers[*j].server_name {
+                    issue(
+                        path.clone(),
+                        format!(
+                            "{} is already used by [{}] with the same server_name '{}'",
+                            addr, j, servers[*i].server_name
+                        ),
+                    );
+                } else if servers[*i].default_server && servers[*j].default_server {
+                    issue(
+                        format!("[{}].default_server", i),
+                        format!("[{}] is already the default server for {}", j, addr),
+                    );
+                }
+            } else if addr.ip().is_unspecified() || other_addr.ip().is_unspecified() {
+                issue(
+                    path.clone(),
+                    format!(
+                        "{} overlaps {} used by [{}]; both cannot be bound",
+                        addr, other_addr, j
+                    ),
+                );
+            }
+        }
+    }
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(issues)
+    }
+}
+
+fn check_upstream(path: &str, upstream: &UpstreamConfig, issue: &mut impl FnMut(String, String)) {
+    if upstream.servers.is_empty() {
+        issue(
+            format!("{}.servers", path),
+            "at least one server is required".to_string(),
+        );
+    }
+    for (s, server) in upstream.servers.iter().enumerate() {
+        match proxy::Target::parse(server) {
+            Ok(target) if target.path.is_none() => {}
+            Ok(_) => issue(
+                format!("{}.servers[{}]", path, s),
+                format!("'{}' must be host:port, without a path", server),
+            ),
+            Err(e) => issue(format!("{}.servers[{}]", path, s), e),
+        }
+    }
+    if let Some(balance) = &upstream.balance
+        && upstream::Balance::parse(balance).is_none()
+    {
+        issue(
+            format!("{}.balance", path),
+            format!(
+                "unknown method '{}' (use round_robin, least_conn or ip_hash)",
+                balance
+            ),
+        );
+    }
+    if upstream.max_fails == Some(0) {
+        issue(
+            format!("{}.max_fails", path),
+            "must be at least 1".to_string(),
+        );
+    }
+    if upstream.fail_timeout == Some(0) {
+        issue(
+            format!("{}.fail_timeout", path),
+            "must be at least 1 second".to_string(),
+        );
+    }
+    if let Some(health_check) = &upstream.health_check {
+        if let Some(probe_path) = &health_check.path
+            && !probe_path.starts_with('/')
+        {
+            issue(
+                format!("{}.health_check.path", path),
+                format!("'{}' must start with '/'", probe_path),
+            );
+        }
+        for (field, value) in [
+            ("interval", health_check.interval),
+            ("timeout", health_check.timeout),
+        ] {
+            if value == Some(0) {
+                issue(
+                    format!("{}.health_check.{}", path, field),
+                    "must be at least 1 second".to_string(),
+                );
+            }
+        }
+    }
+}
+
+fn check_route(
+    path: &str,
+    route: &RouterConfig,
+    server: &ServerConfig,
+    issue: &mut impl FnMut(String, String),
+) {
+    if !route.path.starts_with('/') {
+        issue(
+            format!("{}.path", path),
+            format!("'{}' must start with '/'", route.path),
+        );
+    }
+
+    if route.methods.is_empty() {
+        issue(
+            format!("{}.methods", path),
+            "at least one method is required".to_string(),
+        );
+    }
+    for (m, method) in route.methods.iter().enumerate() {
+        if !KNOWN_METHODS.contains(&method.as_str()) {
+            issue(
+                format!("{}.methods[{}]", path, m),
+                format!("unknown HTTP method '{}'", method),
+            );
+        }
+    }
+
+    if let Some(redirection) = &route.redirection {
+        if let Some(status) = redirection.status
+            && !REDIRECT_STATUSES.contains(&status)
+        {
+            issue(
+                format!("{}.redirection.status", path),
+                format!(
+                    "{} is not a redirect status (use 301, 302, 303, 307 or 308)",
+                    status
+                ),
+            );
+        }
+        if redirection.target.is_empty() {
+            issue(
+                format!("{}.redirection.target", path),
+                "redirect target is empty".to_string(),
+            );
+        }
+    } else if !Path::new(&route.root).is_dir() {
+        // redirect-only routes never touch their root
+        issue(
+            format!("{}.root", path),
+            format!("directory '{}' does not exist", route.root),
+        );
+    }
+
+    if let Some(index) = &route.index
+        && (index.is_empty() || index.contains('/'))
+    {
+        issue(
+            format!("{}.index", path),
+            format!("'{}' must be a plain file name", index),
+        );
+    }
+
+    if let Some(default_type) = &route.default_type
+        && !mime::is_valid(default_type)
+    {
+        issue(
+            format!("{}.default_type", path),
+            format!("'{}' is not a MIME type (type/subtype)", default_type),
+        );
+    }
+
+    if let Some(compression) = &route.compression {
+        if let Some(level) = compression.level
+            && !(1..=9).contains(&level)
+        {
+            issue(
+                format!("{}.compression.level", path),
+                format!("{} is not a compression level (1-9)", level),
+            );
+        }
+        for (t, mime_type) in compression.types.iter().flatten().enumerate() {
+            if !mime::is_valid(mime_type) {
+                issue(
+                    format!("{}.compression.types[{}]", path, t),
+                    format!("'{}' is not a MIME type (type/subtype)", mime_type),
+                );
+            }
+        }
+    }
+
+    for (ext, interpreter) in &route.cgi {
+        if !ext.starts_with('.') {
+            issue(
+                format!("{}.cgi[\"{}\"]", path, ext),
+                format!("extension '{}' must start with '.'", ext),
+            );
+        }
+        // interpreters given by full path must be there; bare names are looked up in PATH
+        if let Some(program) = interpreter.split_whitespace().next()
+            && program.starts_with('/')
+        {
+            match std::fs::metadata(program) {
+                Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => {}
+                Ok(meta) if meta.is_file() => issue(
+                    format!("{}.cgi[\"{}\"]", path, ext),
+                    format!("interpreter '{}' is not executable", program),
+                ),
+                _ => issue(
+                    format!("{}.cgi[\"{}\"]", path, ext),
+                    format!("interpreter '{}' does not exist", program),
+                ),
+            }
+        }
+    }
+    if let Some(fastcgi) = &route.fastcgi {
+        if let Err(e) = fastcgi::Address::parse(fastcgi) {
+            issue(format!("{}.fastcgi", path), e);
+        }
+        if !route.cgi.is_empty() {
+            issue(
+                format!("{}.cgi", path),
+                "a fastcgi route runs no CGI interpreters".to_string(),
+            );
+        }
+    }
+    if route.cgi_timeout == Some(0) {
+        issue(
+            format!("{}.cgi_timeout", path),
+            "must be at least 1 second".to_string(),
+        );
+    }
+    if let Some(proxy_pass) = &route.proxy_pass {
+        match proxy::Target::parse(proxy_pass) {
+            Ok(target) => check_proxy_host(&format!("{}.proxy_pass", path), &target, server, issue),
+            Err(e) => issue(format!("{}.proxy_pass", path), e),
+        }
+        if route.fastcgi.is_some() || !route.cgi.is_empty() {
+            issue(
+                format!("{}.proxy_pass", path),
+                "a proxied route runs no CGI or FastCGI".to_string(),
+            );
+        }
+    }
+    if let Some(ws) = &route.websocket {
+        let path = format!("{}.websocket", path);
+        match (ws.handler.as_str(), &ws.target) {
+            ("echo", None) => {}
+            ("echo", Some(_)) => issue(
+                format!("{}.target", path),
+                "the echo handler takes no target".to_string(),
+            ),
+            ("proxy", Some(target)) => match websocket::parse_target(target) {
+                Ok(target) => check_proxy_host(&format!("{}.target", path), &target, server, issue),
+                Err(e) => issue(format!("{}.target", path), e),
+            },
+            ("process", Some(command)) => {
+                if let Some(program) = command.split_whitespace().next()
+                    && program.starts_with('/')
+                    && !Path::new(program).is_file()
+                {
+                    issue(
+                        format!("{}.target", path),
+                        format!("program '{}' does not exist", program),
+                    );
+                }
+            }
+            ("proxy" | "process", None) => issue(
+                format!("{}.target", path),
+                format!("the {} handler needs a target", ws.handler),
+            ),
+            (other, _) => issue(
+                format!("{}.handler", path),
+                format!("unknown handler '{}' (use echo, proxy or process)", other),
+            ),
+        }
+        if ws.max_message_size == Some(0) {
+            issue(
+                format!("{}.max_message_size", path),
+                "must be at least 1 byte".to_string(),
+            );
+        }
+    }
+    if route.event_stream.is_some() && !route.methods.iter().any(|m| m == "GET") {
+        issue(
+            format!("{}.event_stream", path),
+            "the events are sent for GET, which methods does not allow".to_string(),
+        );
+    }
+    if route.proxy_timeout == Some(0) {
+        issue(
+            format!("{}.proxy_timeout", path),
+            "must be at least 1 second".to_string(),
+        );
+    }
+    for (e, ext) in route.cgi_extensions.iter().flatten().enumerate() {
+        if !ext.starts_with('.') {
+            issue(
+                format!("{}.cgi_extensions[{}]", path, e),
+                format!("extension '{}' must start with '.'", ext),
+            );
+        }
+    }
+}
+
+// A bare name such as "backend" is taken for an upstream; when the server has
+// none by that name it would be looked up in DNS instead, which is most likely
+// a typo
+fn check_proxy_host(
+    path: &str,
+    target: &proxy::Target,
+    server: &ServerConfig,
+    issue: &mut impl FnMut(String, String),
+) {
+    let host = &target.host;
+    if !host.contains(':')
+        && !host.contains('.')
+        && host != "localhost"
+        && !server.upstreams.contains_key(host)
+    {
+        issue(
+            path.to_string(),
+            format!("no upstream named '{}' in this server", host),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serverConfig::ServerAddress;
+    use std::collections::HashMap;
+
+    fn server(name: &str) -> ServerConfig {
+        ServerConfig {
+            server_name: name.to_string(),
+            server_address: vec![ServerAddress {
+                ip: "127.0.0.1".to_string(),
+                port: 8080,
+                tls: None,
+            }],
+            max_body_size: 1024,
+            router: vec![RouterConfig {
+                path: "/".to_string(),
+                methods: vec!["GET".to_string()],
+                root: std::env::temp_dir().to_string_lossy().into_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    // a file in the temp directory with the given permissions
+    fn file(name: &str, mode: u32) -> String {
+        let path =
+            std::env::temp_dir().join(format!("localhost-check-{}-{}", std::process::id(), name));
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn valid_config_has_no_issue() {
+        let mut second = server("b.test");
+        second.upstreams.insert(
+            "backend".to_string(),
+            UpstreamConfig {
+                servers: vec!["127.0.0.1:3001".to_string()],
+                ..Default::default()
+            },
+        );
+        second.router[0].cgi = HashMap::from([(".sh".to_string(), file("ok.sh", 0o755))]);
+        second.router.push(RouterConfig {
+            path: "/api".to_string(),
+            methods: vec!["GET".to_string()],
+            root: second.router[0].root.clone(),
+            proxy_pass: Some("backend".to_string()),
+            ..Default::default()
+        });
+        assert!(validate_config(&[server("a.test"), second]).is_ok());
+    }
+
+    #[test]
+    fn each_rule_names_its_field() {
+        type Case = (fn(&mut Vec<ServerConfig>), &'static str, String);
+        let cases: Vec<Case> = vec![
+            (
+                |servers| {
+                    servers[0].default_server = true;
+                    let mut other = server("b.test");
+                    other.default_server = true;
+                    servers.push(other);
+                },
+                "[1].default_server",
+                "[0] is already the default server for 127.0.0.1:8080".to_string(),
+            ),
+            (
+                |servers| servers.push(server("a.test")),
+                "[1].server_address[0]",
+                "127.0.0.1:8080 is already used by [0] with the same server_name 'a.test'"
+                    .to_string(),
+            ),
+            (
+                |servers| servers[0].router[0].proxy_pass = Some("backnd".to_string()),
+                "[0].router[0].proxy_pass",
+                "no upstream named 'backnd' in this server".to_string(),
+            ),
+            (
+                |servers| {
+                    servers[0].router[0].websocket = Some(crate::serverConfig::WebSocketConfig {
+                        handler: "proxy".to_string(),
+                        target: Some("ws://chat/room".to_string()),
+                        max_message_size: None,
+                    })
+                },
+                "[0].router[0].websocket.target",
+                "no upstream named 'chat' in this server".to_string(),
+            ),
+            (
+                |servers| {
+                    servers[0]
+                        .mime_types
+                        .insert("md".to_string(), "markdown".to_string());
+                },
+                "[0].mime_types.md",
+                "'markdown' is not a MIME type (type/subtype)".to_string(),
+            ),
+            (
+                |servers| {
+                    servers[0]
+                        .mime_types
+                        .insert(".md".to_string(), "text/markdown".to_string());
+                },
+                "[0].mime_types..md",
+                "'.md' must be an extension without the dot".to_string(),
+            ),
+            (
+                |servers| servers[0].router[0].default_type = Some("binary".to_string()),
+                "[0].router[0].default_type",
+                "'binary' is not a MIME type (type/subtype)".to_string(),
+            ),
+            (
+                |servers| servers[0].router[0].root = "/nonexistent/www".to_string(),
+                "[0].router[0].root",
+                "directory '/nonexistent/www' does not exist".to_string(),
+            ),
+            (
+                |servers| {
+                    servers[0].router[0].cgi =
+                        HashMap::from([(".sh".to_string(), file("plain.sh", 0o644))]);
+                },
+                "[0].router[0].cgi[\".sh\"]",
+                format!(
+                    "interpreter '{}' is not executable",
+                    file("plain.sh", 0o644)
+                ),
+            ),
+            (
+                |servers| {
+                    servers[0].router[0].cgi =
+                        HashMap::from([(".py".to_string(), "/nonexistent/python3".to_string())]);
+                },
+                "[0].router[0].cgi[\".py\"]",
+                "interpreter '/nonexistent/python3' does not exist".to_string(),
+            ),
+            (
+                |servers| servers[0].router[0].methods = vec!["FETCH".to_string()],
+                "[0].router[0].methods[0]",
+                "unknown HTTP method 'FETCH'".to_string(),
+            ),
+        ];
+        for (change, path, message) in cases {
+            let mut servers = vec![server("a.test")];
+            change(&mut servers);
+            let issues = validate_config(&servers).unwrap_err();
+            assert_eq!(issues.len(), 1, "{:?}", issues);
+            assert_eq!(
+                (issues[0].path.as_str(), &issues[0].message),
+                (path, &message)
+            );
+        }
+    }
+}
@@ -0,0 +1,894 @@
+// # قراءة ملف الإعدادات بصيغة config.conf (على طريقة nginx)
+//
+// server {
+//     server_address 127.0.0.1;
+//     port 8080 9090;
+//     server_name myserver;
+//     error_page 404 /errors/404.html;
+//     client_max_body_size 10M;
+//
+//     route / {
+//         root ./public;
+//         index index.html;
+//         methods GET POST DELETE;
+//         autoindex on;
+//     }
+//
+//     mime_types {
+//         text/markdown md markdown;
+//     }
+// }
+
+use crate::serverConfig::{
+    RedirectionConfig, RouterConfig, ServerAddress, ServerConfig, TlsConfig, UpstreamConfig,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A syntax or value error in the config file, with the 1-based position it was found at
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    OpenBrace,
+    CloseBrace,
+    Semicolon,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(w) => format!("'{}'", w),
+            TokenKind::OpenBrace => "'{'".to_string(),
+            TokenKind::CloseBrace => "'}'".to_string(),
+            TokenKind::Semicolon => "';'".to_string(),
+        }
+    }
+}
+
+/// تقسيم النص إلى كلمات و أقواس و فواصل منقوطة مع حفظ السطر و العمود
+fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let (mut line, mut column) = (1, 1);
+
+    while let Some(&c) = chars.peek() {
+        let (start_line, start_column) = (line, column);
+        let single = match c {
+            '{' => Some(TokenKind::OpenBrace),
+            '}' => Some(TokenKind::CloseBrace),
+            ';' => Some(TokenKind::Semicolon),
+            _ => None,
+        };
+        if let Some(kind) = single {
+            chars.next();
+            column += 1;
+            tokens.push(Token {
+                kind,
+                line: start_line,
+                column: start_column,
+            });
+            continue;
+        }
+        if c == '\n' {
+            chars.next();
+            line += 1;
+            column = 1;
+        } else if c.is_whitespace() {
+            chars.next();
+            column += 1;
+        } else if c == '#' {
+            // comment until end of line
+            while let Some(&c) = chars.peek() {
+                if c == '\n' {
+                    break;
+                }
+                chars.next();
+                column += 1;
+            }
+        } else if c == '"' || c == '\'' {
+            let quote = c;
+            chars.next();
+            column += 1;
+            let mut word = String::new();
+            loop {
+                match chars.next() {
+                    Some(c) if c == quote => {
+                        column += 1;
+                        break;
+                    }
+                    Some('\n') | None => {
+                        return Err(ParseError {
+                            line: start_line,
+                            column: start_column,
+                            message: "unterminated quoted string".to_string(),
+                        });
+                    }
+                    Some(c) => {
+                        column += 1;
+                        word.push(c);
+                    }
+                }
+            }
+            tokens.push(Token {
+                kind: TokenKind::Word(word),
+                line: start_line,
+                column: start_column,
+            });
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || matches!(c, '{' | '}' | ';' | '#') {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+                column += 1;
+            }
+            tokens.push(Token {
+                kind: TokenKind::Word(word),
+                line: start_line,
+                column: start_column,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+/// A directive name, its arguments and the position of each
+struct Directive {
+    name: Token,
+    args: Vec<(String, Token)>,
+}
+
+impl Directive {
+    fn name(&self) -> &str {
+        match &self.name.kind {
+            TokenKind::Word(w) => w,
+            _ => "",
+        }
+    }
+
+    fn expect_args(&self, min: usize, max: usize) -> Result<(), ParseError> {
+        if self.args.len() < min || self.args.len() > max {
+            let expected = if min == max {
+                format!("{}", min)
+            } else if max == usize::MAX {
+                format!("at least {}", min)
+            } else {
+                format!("{} to {}", min, max)
+            };
+            return Err(self.name.error(format!(
+                "'{}' takes {} argument(s), got {}",
+                self.name(),
+                expected,
+                self.args.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn arg(&self, index: usize) -> &str {
+        &self.args[index].0
+    }
+
+    fn number<T: std::str::FromStr>(&self, index: usize) -> Result<T, ParseError> {
+        let (value, token) = &self.args[index];
+        value
+            .parse()
+            .map_err(|_| token.error(format!("invalid number '{}'", value)))
+    }
+
+    fn on_off(&self, index: usize) -> Result<bool, ParseError> {
+        let (value, token) = &self.args[index];
+        match value.as_str() {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(token.error(format!("expected 'on' or 'off', got '{}'", value))),
+        }
+    }
+
+    fn size(&self, index: usize) -> Result<usize, ParseError> {
+        let (value, token) = &self.args[index];
+        parse_size(value).ok_or_else(|| token.error(format!("invalid size '{}'", value)))
+    }
+}
+
+/// "10M" -> 10485760, "512K", "1G" or plain bytes
+pub fn parse_size(value: &str) -> Option<usize> {
+    let (digits, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // position reported for errors at end of file
+    eof: (usize, usize),
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eof_error(&self, message: &str) -> ParseError {
+        ParseError {
+            line: self.eof.0,
+            column: self.eof.1,
+            message: message.to_string(),
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &str) -> Result<Token, ParseError> {
+        match self.next() {
+            Some(token) if token.kind == kind => Ok(token),
+            Some(token) => {
+                Err(token.error(format!("expected {}, found {}", what, token.describe())))
+            }
+            None => Err(self.eof_error(&format!("expected {}, found end of file", what))),
+        }
+    }
+
+    /// reads `name arg arg ... ;` and stops before a `{` so blocks can take over
+    fn directive(&mut self) -> Result<Directive, ParseError> {
+        let name = self
+            .next()
+            .ok_or_else(|| self.eof_error("unexpected end of file"))?;
+        if !matches!(name.kind, TokenKind::Word(_)) {
+            return Err(name.error(format!("expected a directive, found {}", name.describe())));
+        }
+        let mut args = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token {
+                    kind: TokenKind::Word(w),
+                    ..
+                }) => {
+                    let w = w.clone();
+                    let token = self.next().unwrap();
+                    args.push((w, token));
+                }
+                Some(Token {
+                    kind: TokenKind::Semicolon,
+                    ..
+                }) => {
+                    self.next();
+                    return Ok(Directive { name, args });
+                }
+                Some(Token {
+                    kind: TokenKind::OpenBrace,
+                    ..
+                }) => return Ok(Directive { name, args }),
+                Some(token) => {
+                    return Err(token.error(format!(
+                        "expected ';' after '{}', found {}",
+                        match &name.kind {
+                            TokenKind::Word(w) => w.as_str(),
+                            _ => "",
+                        },
+                        token.describe()
+                    )));
+                }
+                None => return Err(self.eof_error("missing ';' at end of file")),
+            }
+        }
+    }
+
+    fn at_block_end(&self) -> Result<bool, ParseError> {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::CloseBrace,
+                ..
+            }) => Ok(true),
+            Some(_) => Ok(false),
+            None => Err(self.eof_error("missing '}' at end of file")),
+        }
+    }
+
+    fn parse_file(&mut self) -> Result<Vec<ServerConfig>, ParseError> {
+        let mut servers = Vec::new();
+        while self.peek().is_some() {
+            let directive = self.directive()?;
+            if directive.name() != "server" {
+                return Err(directive.name.error(format!(
+                    "unknown top-level directive '{}', expected 'server'",
+                    directive.name()
+                )));
+            }
+            directive.expect_args(0, 0)?;
+            self.expect(TokenKind::OpenBrace, "'{'")?;
+            servers.push(self.parse_server(&directive.name)?);
+        }
+        if servers.is_empty() {
+            return Err(self.eof_error("no server block found"));
+        }
+        Ok(servers)
+    }
+
+    fn parse_server(&mut self, start: &Token) -> Result<ServerConfig, ParseError> {
+        let mut server = ServerConfig {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            ..Default::default()
+        };
+        let mut ips: Vec<String> = Vec::new();
+        let mut ports: Vec<u16> = Vec::new();
+        // `listen ... ssl` addresses, with the error to give when the
+        // certificate or key is missing
+        let mut ssl_listens: Vec<(usize, ParseError)> = Vec::new();
+        let mut ssl_certificate: Option<String> = None;
+        let mut ssl_certificate_key: Option<String> = None;
+
+        while !self.at_block_end()? {
+            let directive = self.directive()?;
+            match directive.name() {
+                "server_address" | "host" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    ips.extend(directive.args.iter().map(|(ip, _)| ip.clone()));
+                }
+                "port" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    for i in 0..directive.args.len() {
+                        ports.push(directive.number(i)?);
+                    }
+                }
+                "listen" => {
+                    // listen 127.0.0.1:8080;  listen [::1]:8443 ssl;
+                    directive.expect_args(1, 2)?;
+                    let (value, token) = &directive.args[0];
+                    // an IPv6 address is bracketed, its own colons are not the port's
+                    let split = match value.strip_prefix('[') {
+                        Some(rest) => rest.split_once("]:"),
+                        None => value.rsplit_once(':'),
+                    };
+                    let (ip, port) =
+                        split.ok_or_else(|| token.error("expected ip:port or [ipv6]:port"))?;
+                    let port = port
+                        .parse()
+                        .map_err(|_| token.error(format!("invalid port '{}'", port)))?;
+                    if let Some((flag, token)) = directive.args.get(1) {
+                        if flag != "ssl" {
+                            return Err(token.error(format!("expected 'ssl', got '{}'", flag)));
+                        }
+                        ssl_listens.push((
+                            server.server_address.len(),
+                            token.error("'listen ... ssl' needs 'ssl_certificate' and 'ssl_certificate_key'"),
+                        ));
+                    }
+                    server.server_address.push(ServerAddress {
+                        ip: ip.to_string(),
+                        port,
+                        tls: None,
+                    });
+                }
+                "ssl_certificate" => {
+                    directive.expect_args(1, 1)?;
+                    ssl_certificate = Some(directive.arg(0).to_string());
+                }
+                "ssl_certificate_key" => {
+                    directive.expect_args(1, 1)?;
+                    ssl_certificate_key = Some(directive.arg(0).to_string());
+                }
+                "server_name" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    let names: Vec<&str> = directive.args.iter().map(|(n, _)| n.as_str()).collect();
+                    server.server_name = names.join(" ");
+                }
+                "default_server" => {
+                    directive.expect_args(0, 1)?;
+                    server.default_server = directive.args.is_empty() || directive.on_off(0)?;
+                }
+                "error_page" => {
+                    directive.expect_args(2, usize::MAX)?;
+                    let page = directive.arg(directive.args.len() - 1).to_string();
+                    for i in 0..directive.args.len() - 1 {
+                        server.error_msg.insert(directive.number(i)?, page.clone());
+                    }
+                }
+                "client_max_body_size" => {
+                    directive.expect_args(1, 1)?;
+                    server.max_body_size = directive.size(0)?;
+                }
+                "keepalive_timeout" => {
+                    directive.expect_args(1, 1)?;
+                    server.keepalive_timeout = Some(directive.number(0)?);
+                }
+                "keepalive_requests" => {
+                    directive.expect_args(1, 1)?;
+                    server.keepalive_requests = Some(directive.number(0)?);
+                }
+                "route" | "location" => {
+                    directive.expect_args(1, 1)?;
+                    self.expect(TokenKind::OpenBrace, "'{'")?;
+                    let route = self.parse_route(&directive)?;
+                    server.router.push(route);
+                }
+                "mime_types" | "types" => {
+                    directive.expect_args(0, 0)?;
+                    self.expect(TokenKind::OpenBrace, "'{'")?;
+                    self.parse_mime_types(&mut server.mime_types)?;
+                }
+                "upstream" => {
+                    directive.expect_args(1, 1)?;
+                    self.expect(TokenKind::OpenBrace, "'{'")?;
+                    let upstream = self.parse_upstream()?;
+                    server
+                        .upstreams
+                        .insert(directive.arg(0).to_string(), upstream);
+                }
+                other => {
+                    return Err(directive
+                        .name
+                        .error(format!("unknown directive '{}' in server block", other)));
+                }
+            }
+        }
+        self.expect(TokenKind::CloseBrace, "'}'")?;
+
+        if ips.is_empty() && !ports.is_empty() {
+            ips.push("0.0.0.0".to_string());
+        }
+        if ports.is_empty() && !ips.is_empty() {
+            return Err(start.error("server block has 'server_address' but no 'port'"));
+        }
+        for ip in &ips {
+            for port in &ports {
+                server.server_address.push(ServerAddress {
+                    ip: ip.clone(),
+                    port: *port,
+                    tls: None,
+                });
+            }
+        }
+        for (index, missing) in ssl_listens {
+            let (Some(cert), Some(key)) = (&ssl_certificate, &ssl_certificate_key) else {
+                return Err(missing);
+            };
+            server.server_address[index].tls = Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+            });
+        }
+        if server.server_address.is_empty() {
+            return Err(start.error("server block has no 'port' or 'listen' directive"));
+        }
+        Ok(server)
+    }
+
+    /// `mime_types { text/markdown md markdown; }`, the same layout as nginx's `types`
+    fn parse_mime_types(
+        &mut self,
+        mime_types: &mut HashMap<String, String>,
+    ) -> Result<(), ParseError> {
+        while !self.at_block_end()? {
+            let directive = self.directive()?;
+            directive.expect_args(1, usize::MAX)?;
+            for (extension, _) in &directive.args {
+                let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+                mime_types.insert(extension, directive.name().to_string());
+            }
+        }
+        self.expect(TokenKind::CloseBrace, "'}'")?;
+        Ok(())
+    }
+
+    /// `upstream backend { server 127.0.0.1:3001; least_conn; health_check /health; }`
+    fn parse_upstream(&mut self) -> Result<UpstreamConfig, ParseError> {
+        let mut upstream = UpstreamConfig::default();
+        while !self.at_block_end()? {
+            let directive = self.directive()?;
+            match directive.name() {
+                "server" => {
+                    directive.expect_args(1, 1)?;
+                    upstream.servers.push(directive.arg(0).to_string());
+                }
+                "balance" => {
+                    directive.expect_args(1, 1)?;
+                    upstream.balance = Some(directive.arg(0).to_string());
+                }
+                // nginx's spelling of the same thing
+                "round_robin" | "least_conn" | "ip_hash" => {
+                    directive.expect_args(0, 0)?;
+                    upstream.balance = Some(directive.name().to_string());
+                }
+                "max_fails" => {
+                    directive.expect_args(1, 1)?;
+                    upstream.max_fails = Some(directive.number(0)?);
+                }
+                "fail_timeout" => {
+                    directive.expect_args(1, 1)?;
+                    upstream.fail_timeout = Some(directive.number(0)?);
+                }
+                // health_check turns the probes on, with "/" when no path is given
+                "health_check" => {
+                    directive.expect_args(0, 1)?;
+                    let health_check = upstream.health_check.get_or_insert_default();
+                    if !directive.args.is_empty() {
+                        health_check.path = Some(directive.arg(0).to_string());
+                    }
+                }
+                "health_interval" => {
+                    directive.expect_args(1, 1)?;
+                    upstream.health_check.get_or_insert_default().interval =
+                        Some(directive.number(0)?);
+                }
+                "health_timeout" => {
+                    directive.expect_args(1, 1)?;
+                    upstream.health_check.get_or_insert_default().timeout =
+                        Some(directive.number(0)?);
+                }
+                other => {
+                    return Err(directive
+                        .name
+                        .error(format!("unknown directive '{}' in upstream block", other)));
+                }
+            }
+        }
+        self.expect(TokenKind::CloseBrace, "'}'")?;
+        Ok(upstream)
+    }
+
+    fn parse_route(&mut self, start: &Directive) -> Result<RouterConfig, ParseError> {
+        let mut route = RouterConfig {
+            path: start.arg(0).to_string(),
+            methods: vec!["GET".to_string()],
+            ..Default::default()
+        };
+        let mut has_root = false;
+
+        while !self.at_block_end()? {
+            let directive = self.directive()?;
+            match directive.name() {
+                "root" => {
+                    directive.expect_args(1, 1)?;
+                    route.root = directive.arg(0).to_string();
+                    has_root = true;
+                }
+                "index" => {
+                    directive.expect_args(1, 1)?;
+                    route.index = Some(directive.arg(0).to_string());
+                }
+                "methods" | "allow_methods" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    route.methods = directive.args.iter().map(|(m, _)| m.clone()).collect();
+                }
+                "autoindex" | "directory_listing" => {
+                    directive.expect_args(1, 1)?;
+                    route.directory_listing = Some(directive.on_off(0)?);
+                }
+                "cgi" => {
+                    // cgi .py /usr/bin/python3;  or  cgi .cgi;  for scripts that run themselves
+                    directive.expect_args(1, usize::MAX)?;
+                    let interpreter: Vec<&str> = directive.args[1..]
+                        .iter()
+                        .map(|(a, _)| a.as_str())
+                        .collect();
+                    route
+                        .cgi
+                        .insert(directive.arg(0).to_string(), interpreter.join(" "));
+                }
+                "cgi_extensions" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    route.cgi_extensions =
+                        Some(directive.args.iter().map(|(e, _)| e.clone()).collect());
+                }
+                "fastcgi_pass" | "fastcgi" => {
+                    directive.expect_args(1, 1)?;
+                    route.fastcgi = Some(directive.arg(0).to_string());
+                }
+                "cgi_timeout" => {
+                    directive.expect_args(1, 1)?;
+                    route.cgi_timeout = Some(directive.number(0)?);
+                }
+                "proxy_pass" => {
+                    directive.expect_args(1, 1)?;
+                    route.proxy_pass = Some(directive.arg(0).to_string());
+                }
+                // websocket echo;  websocket proxy ws://127.0.0.1:9000/chat;
+                // websocket process /usr/bin/python3 bot.py;
+                "websocket" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    let target: Vec<&str> = directive.args[1..]
+                        .iter()
+                        .map(|(a, _)| a.as_str())
+                        .collect();
+                    let websocket = route.websocket.get_or_insert_default();
+                    websocket.handler = directive.arg(0).to_string();
+                    websocket.target = (!target.is_empty()).then(|| target.join(" "));
+                }
+                "websocket_max_message_size" => {
+                    directive.expect_args(1, 1)?;
+                    route.websocket.get_or_insert_default().max_message_size =
+                        Some(directive.size(0)?);
+                }
+                "event_stream" => {
+                    directive.expect_args(1, 1)?;
+                    route.event_stream = Some(directive.arg(0).to_string());
+                }
+                "proxy_timeout" => {
+                    directive.expect_args(1, 1)?;
+                    route.proxy_timeout = Some(directive.number(0)?);
+                }
+                "client_max_body_size" => {
+                    directive.expect_args(1, 1)?;
+                    route.max_body_size = Some(directive.size(0)?);
+                }
+                "default_type" => {
+                    directive.expect_args(1, 1)?;
+                    route.default_type = Some(directive.arg(0).to_string());
+                }
+                "compression" | "gzip" => {
+                    directive.expect_args(1, 1)?;
+                    if directive.on_off(0)? {
+                        route.compression.get_or_insert_default();
+                    } else {
+                        route.compression = None;
+                    }
+                }
+                // the compression_* settings turn compression on as well
+                "compression_level" => {
+                    directive.expect_args(1, 1)?;
+                    route.compression.get_or_insert_default().level = Some(directive.number(0)?);
+                }
+                "compression_types" => {
+                    directive.expect_args(1, usize::MAX)?;
+                    let types = directive.args.iter().map(|(t, _)| t.clone()).collect();
+                    route.compression.get_or_insert_default().types = Some(types);
+                }
+                "compression_min_length" => {
+                    directive.expect_args(1, 1)?;
+                    let min_length = directive.size(0)? as u64;
+                    route.compression.get_or_insert_default().min_length = Some(min_length);
+                }
+                "compression_static" | "precompressed" => {
+                    directive.expect_args(1, 1)?;
+                    let precompressed = directive.on_off(0)?;
+                    route.compression.get_or_insert_default().precompressed = Some(precompressed);
+                }
+                "return" | "redirect" => {
+                    // return 301 /new;  or  return /new;
+                    directive.expect_args(1, 2)?;
+                    let redirection = if directive.args.len() == 2 {
+                        RedirectionConfig {
+                            status: Some(directive.number(0)?),
+                            target: directive.arg(1).to_string(),
+                        }
+                    } else {
+                        RedirectionConfig {
+                            status: None,
+                            target: directive.arg(0).to_string(),
+                        }
+                    };
+                    route.redirection = Some(redirection);
+                }
+                other => {
+                    return Err(directive
+                        .name
+                        .error(format!("unknown directive '{}' in route block", other)));
+                }
+            }
+        }
+        self.expect(TokenKind::CloseBrace, "'}'")?;
+
+        if !has_root && route.redirection.is_none() {
+            return Err(start
+                .name
+                .error(format!("route '{}' has no 'root' directive", route.path)));
+        }
+        Ok(route)
+    }
+}
+
+// nginx's default for client_max_body_size
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// يحول نص ملف config.conf إلى نفس الـ ServerConfig اللي يطلع من ملف JSON
+pub fn parse_config(source: &str) -> Result<Vec<ServerConfig>, ParseError> {
+    let tokens = tokenize(source)?;
+    let lines: Vec<&str> = source.split('\n').collect();
+    let eof = (
+        lines.len(),
+        lines.last().map(|l| l.chars().count()).unwrap_or(0) + 1,
+    );
+    Parser {
+        tokens,
+        pos: 0,
+        eof,
+    }
+    .parse_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serverConfig::{HealthCheckConfig, WebSocketConfig};
+
+    // the ```conf block of the README
+    fn readme_example() -> String {
+        let readme = include_str!("../README.md");
+        let start = readme.find("```conf\n").unwrap() + "```conf\n".len();
+        let end = start + readme[start..].find("```").unwrap();
+        readme[start..end].to_string()
+    }
+
+    fn error_at(source: &str) -> (usize, usize, String) {
+        let e = parse_config(source).unwrap_err();
+        (e.line, e.column, e.message)
+    }
+
+    #[test]
+    fn readme_example_parses() {
+        let servers = parse_config(&readme_example()).unwrap();
+        assert_eq!(servers.len(), 2);
+
+        let main = &servers[0];
+        assert_eq!(main.server_name, "myserver");
+        assert_eq!(main.server_address.len(), 1);
+        assert_eq!(
+            (
+                main.server_address[0].ip.as_str(),
+                main.server_address[0].port
+            ),
+            ("127.0.0.1", 8080)
+        );
+        assert_eq!(main.max_body_size, 10 * 1024 * 1024);
+        assert_eq!(main.error_msg[&404], "/errors/404.html");
+        assert_eq!(
+            main.upstreams["backend"],
+            UpstreamConfig {
+                servers: vec!["127.0.0.1:3001".to_string(), "127.0.0.1:3002".to_string()],
+                balance: Some("least_conn".to_string()),
+                max_fails: Some(3),
+                fail_timeout: Some(30),
+                health_check: Some(HealthCheckConfig {
+                    path: Some("/health".to_string()),
+                    interval: Some(5),
+                    timeout: None,
+                }),
+            }
+        );
+
+        let route = |path: &str| main.router.iter().find(|r| r.path == path).unwrap();
+        assert_eq!(
+            *route("/"),
+            RouterConfig {
+                path: "/".to_string(),
+                methods: vec!["GET".to_string(), "POST".to_string(), "DELETE".to_string()],
+                root: "/var/www/html".to_string(),
+                index: Some("index.html".to_string()),
+                directory_listing: Some(false),
+                ..Default::default()
+            }
+        );
+        let cgi = route("/cgi-bin");
+        assert_eq!(cgi.cgi[".py"], "/usr/bin/python3");
+        assert_eq!(cgi.cgi[".cgi"], "");
+        assert_eq!(
+            cgi.cgi_extensions,
+            Some(vec![".py".to_string(), ".cgi".to_string()])
+        );
+        assert_eq!(cgi.cgi_timeout, Some(10));
+        assert_eq!(
+            route("/api").proxy_pass.as_deref(),
+            Some("http://127.0.0.1:3000/v1")
+        );
+        assert_eq!(route("/shop").proxy_pass.as_deref(), Some("backend"));
+        assert_eq!(
+            route("/chat").websocket,
+            Some(WebSocketConfig {
+                handler: "process".to_string(),
+                target: Some("/usr/bin/python3 bot.py".to_string()),
+                max_message_size: Some(64 * 1024),
+            })
+        );
+        assert_eq!(route("/feed").event_stream.as_deref(), Some("metrics.log"));
+
+        let secure = &servers[1];
+        assert_eq!(secure.server_address[0].port, 8443);
+        assert_eq!(
+            secure.server_address[0].tls,
+            Some(TlsConfig {
+                cert: "/etc/ssl/secure.example.com.pem".to_string(),
+                key: "/etc/ssl/secure.example.com.key".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("10M"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1k"), Some(1024));
+        assert_eq!(parse_size("2G"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("-1K"), None);
+        assert_eq!(parse_size("99999999999999999999G"), None);
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(
+            error_at("server {\n    listen 127.0.0.1:8080;\n    route / {\n        root /tmp;\n"),
+            (5, 1, "missing '}' at end of file".to_string())
+        );
+        assert_eq!(
+            error_at("server {\n    listen 127.0.0.1:8080;\n    colour blue;\n}"),
+            (
+                3,
+                5,
+                "unknown directive 'colour' in server block".to_string()
+            )
+        );
+        assert_eq!(
+            error_at("server {\n    listen 127.0.0.1:8080;\n    route / { root /tmp }\n}"),
+            (3, 25, "expected ';' after 'root', found '}'".to_string())
+        );
+        assert_eq!(
+            error_at("server {\n    server_name \"a b;\n}"),
+            (2, 17, "unterminated quoted string".to_string())
+        );
+    }
+
+    #[test]
+    fn listen_takes_bracketed_ipv6() {
+        let servers = parse_config(
+            "server { listen [::1]:8080; listen 127.0.0.1:8081; route / { root /tmp; } }",
+        )
+        .unwrap();
+        let addresses: Vec<(&str, u16)> = servers[0]
+            .server_address
+            .iter()
+            .map(|a| (a.ip.as_str(), a.port))
+            .collect();
+        assert_eq!(addresses, [("::1", 8080), ("127.0.0.1", 8081)]);
+    }
+
+    #[test]
+    fn listen_rejects_ipv6_without_port() {
+        assert!(parse_config("server { listen [::1]; route / { root /tmp; } }").is_err());
+    }
+}
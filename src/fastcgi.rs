@@ -0,0 +1,507 @@
+// # عميل FastCGI
+//
+// A route with `fastcgi` hands its requests to a FastCGI server (php-fpm,
+// flup, ...) over TCP or a unix socket instead of starting a process. The
+// request goes out as PARAMS and STDIN records; the STDOUT records carry the
+// same output a CGI script prints. Connections are opened with FCGI_KEEP_CONN
+// and reused for later requests.
+
+use crate::request_body::RequestBody;
+use mio::event::Source;
+use mio::net::{TcpStream, UnixStream};
+use mio::{Interest, Registry, Token};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+const VERSION: u8 = 1;
+// record types
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+// one request at a time on a connection, so the id is always the same
+const REQUEST_ID: u16 = 1;
+const MAX_CONTENT: usize = 65535;
+// body bytes read from a spooled body per STDIN record
+const STDIN_CHUNK: usize = 32 * 1024;
+
+/// Where the FastCGI server listens: "127.0.0.1:9000" or "unix:/run/php/php-fpm.sock"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(value: &str) -> Result<Address, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_string());
+            }
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        value
+            .parse()
+            .map(Address::Tcp)
+            .map_err(|_| format!("'{}' is not ip:port or unix:/path", value))
+    }
+}
+
+/// A connection to a FastCGI server
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Starts connecting; a TCP connect finishes once the socket is writable
+    pub fn connect(address: &Address) -> io::Result<Stream> {
+        match address {
+            Address::Tcp(addr) => TcpStream::connect(*addr).map(Stream::Tcp),
+            Address::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    /// true for an idle connection the server hasn't closed
+    pub fn is_alive(&mut self) -> bool {
+        let mut byte = [0; 1];
+        matches!(self.read(&mut byte), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            Stream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            Stream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            Stream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+/// One request to a FastCGI server. Records are written and read as the
+/// socket allows; the event loop gives it a connection before the first event.
+pub struct FastCgiRequest {
+    pub address: String, // as configured, also the key of the connection pool
+    pub stream: Option<Stream>,
+    out: Vec<u8>, // records not written yet
+    written: usize,
+    body: Option<File>, // a spooled body, sent as STDIN records as the socket takes them
+    input: Vec<u8>,     // bytes read that don't make a whole record yet
+    stdout: Vec<u8>,    // STDOUT content not read by the event loop yet
+    ended: bool,        // END_REQUEST received
+}
+
+impl FastCgiRequest {
+    pub fn new(
+        address: &str,
+        env: &[(String, String)],
+        body: &RequestBody,
+    ) -> io::Result<FastCgiRequest> {
+        let mut out = Vec::new();
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        begin.push(KEEP_CONN);
+        begin.extend_from_slice(&[0; 5]);
+        push_record(&mut out, BEGIN_REQUEST, &begin);
+
+        let mut params = Vec::new();
+        for (name, value) in env {
+            push_length(&mut params, name.len());
+            push_length(&mut params, value.len());
+            params.extend_from_slice(name.as_bytes());
+            params.extend_from_slice(value.as_bytes());
+        }
+        for chunk in params.chunks(MAX_CONTENT) {
+            push_record(&mut out, PARAMS, chunk);
+        }
+        push_record(&mut out, PARAMS, &[]); // end of the params stream
+
+        let body = match body {
+            RequestBody::Memory(bytes) => {
+                for chunk in bytes.chunks(MAX_CONTENT) {
+                    push_record(&mut out, STDIN, chunk);
+                }
+                push_record(&mut out, STDIN, &[]);
+                None
+            }
+            RequestBody::Spooled(_) => body.spool_file().transpose()?,
+        };
+        Ok(FastCgiRequest {
+            address: address.to_string(),
+            stream: None,
+            out,
+            written: 0,
+            body,
+            input: Vec::new(),
+            stdout: Vec::new(),
+            ended: false,
+        })
+    }
+
+    /// Writes records until the socket is full; true once the request is all out
+    fn send(&mut self) -> io::Result<bool> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        loop {
+            while self.written < self.out.len() {
+                match stream.write(&self.out[self.written..]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => self.written += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.out.clear();
+            self.written = 0;
+            // the next piece of a spooled body, an empty record ends it
+            let Some(file) = self.body.as_mut() else {
+                return Ok(true);
+            };
+            let mut chunk = vec![0; STDIN_CHUNK];
+            let n = file.read(&mut chunk)?;
+            push_record(&mut self.out, STDIN, &chunk[..n]);
+            if n == 0 {
+                self.body = None;
+            }
+        }
+    }
+
+    /// Sends what the socket takes, then reads STDOUT content into `buf`:
+    /// Ok(0) once the server ended the request, WouldBlock while it sent nothing new
+    pub fn read_stdout(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.send()?;
+        loop {
+            if !self.stdout.is_empty() {
+                let n = buf.len().min(self.stdout.len());
+                buf[..n].copy_from_slice(&self.stdout[..n]);
+                self.stdout.drain(..n);
+                return Ok(n);
+            }
+            if self.ended {
+                return Ok(0);
+            }
+            let mut chunk = [0; 16384];
+            let n = match self.stream.as_mut() {
+                Some(stream) => stream.read(&mut chunk)?,
+                None => return Err(io::ErrorKind::NotConnected.into()),
+            };
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "FastCGI server closed the connection",
+                ));
+            }
+            self.input.extend_from_slice(&chunk[..n]);
+            self.read_records()?;
+        }
+    }
+
+    // Takes the complete records out of `input`
+    fn read_records(&mut self) -> io::Result<()> {
+        let mut pos = 0;
+        while self.input.len() - pos >= 8 {
+            let header = &self.input[pos..pos + 8];
+            if header[0] != VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a FastCGI record",
+                ));
+            }
+            let kind = header[1];
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let padding = header[6] as usize;
+            if self.input.len() - pos < 8 + len + padding {
+                break;
+            }
+            let content = &self.input[pos + 8..pos + 8 + len];
+            match kind {
+                STDOUT => self.stdout.extend_from_slice(content),
+                // the server's own messages go to the error log, like a script's stderr
+                STDERR => eprintln!(
+                    "FastCGI {}: {}",
+                    self.address,
+                    String::from_utf8_lossy(content).trim_end()
+                ),
+                END_REQUEST => {
+                    // protocolStatus: 0 done, else the server refused the request
+                    if let Some(&status) = content.get(4).filter(|&&status| status != 0) {
+                        eprintln!(
+                            "FastCGI {}: request refused (protocol status {})",
+                            self.address, status
+                        );
+                    }
+                    self.ended = true;
+                }
+                _ => {}
+            }
+            pos += 8 + len + padding;
+        }
+        self.input.drain(..pos);
+        Ok(())
+    }
+
+    /// The connection can take another request once this one ended cleanly
+    pub fn reusable(&self) -> bool {
+        self.ended && self.input.is_empty() && self.out.is_empty() && self.body.is_none()
+    }
+}
+
+fn push_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[VERSION, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.extend_from_slice(&[0; 7][..padding]);
+}
+
+// name and value lengths: one byte below 128, else four with the high bit set
+fn push_length(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_body::{BodyReader, SPOOL_THRESHOLD};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // a record from the responder's side: type, request id, content
+    fn read_record(conn: &mut std::net::TcpStream) -> io::Result<(u8, u16, Vec<u8>)> {
+        let mut header = [0; 8];
+        conn.read_exact(&mut header)?;
+        assert_eq!(header[0], VERSION);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0; len + header[6] as usize];
+        conn.read_exact(&mut content)?;
+        content.truncate(len);
+        Ok((
+            header[1],
+            u16::from_be_bytes([header[2], header[3]]),
+            content,
+        ))
+    }
+
+    fn read_length(data: &[u8], pos: &mut usize) -> usize {
+        if data[*pos] < 128 {
+            *pos += 1;
+            return data[*pos - 1] as usize;
+        }
+        let bytes = [
+            data[*pos] & 0x7f,
+            data[*pos + 1],
+            data[*pos + 2],
+            data[*pos + 3],
+        ];
+        *pos += 4;
+        u32::from_be_bytes(bytes) as usize
+    }
+
+    // Answers requests on one connection until it is closed: the output lists
+    // the params and echoes STDIN, in small STDOUT records after a STDERR one
+    fn responder(listener: TcpListener) {
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        while let Ok((kind, id, begin)) = read_record(&mut conn) {
+            assert_eq!((kind, id), (BEGIN_REQUEST, REQUEST_ID));
+            assert_eq!(u16::from_be_bytes([begin[0], begin[1]]), RESPONDER);
+            assert_eq!(begin[2], KEEP_CONN);
+
+            let mut params = Vec::new();
+            loop {
+                let (kind, _, content) = read_record(&mut conn).unwrap();
+                assert_eq!(kind, PARAMS);
+                if content.is_empty() {
+                    break;
+                }
+                params.extend_from_slice(&content);
+            }
+            let mut output = b"Content-Type: text/plain\r\n\r\n".to_vec();
+            let mut pos = 0;
+            while pos < params.len() {
+                let name_len = read_length(&params, &mut pos);
+                let value_len = read_length(&params, &mut pos);
+                let name = &params[pos..pos + name_len];
+                let value = &params[pos + name_len..pos + name_len + value_len];
+                output.extend_from_slice(format!("{}={}\n", name.len(), value.len()).as_bytes());
+                output.extend_from_slice(name);
+                output.push(b'\n');
+                pos += name_len + value_len;
+            }
+            let mut records = 0;
+            loop {
+                let (kind, _, content) = read_record(&mut conn).unwrap();
+                assert_eq!(kind, STDIN);
+                if content.is_empty() {
+                    break;
+                }
+                records += 1;
+                output.extend_from_slice(&content);
+            }
+            output.extend_from_slice(format!("\nstdin records: {}", records).as_bytes());
+
+            let mut reply = Vec::new();
+            push_record(&mut reply, STDERR, b"a warning");
+            for chunk in output.chunks(1000) {
+                push_record(&mut reply, STDOUT, chunk);
+            }
+            push_record(&mut reply, STDOUT, &[]);
+            push_record(&mut reply, END_REQUEST, &[0; 8]);
+            // in odd pieces, so records arrive split across reads
+            for piece in reply.chunks(777) {
+                conn.write_all(piece).unwrap();
+                conn.flush().unwrap();
+            }
+        }
+    }
+
+    fn output(request: &mut FastCgiRequest) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut output = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match request.read_stdout(&mut buf) {
+                Ok(0) => return output,
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "no END_REQUEST");
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn request_over_a_reused_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || responder(listener));
+
+        // long values take four length bytes, a body over MAX_CONTENT two records
+        let long = "v".repeat(300);
+        let env = vec![
+            ("SCRIPT_NAME".to_string(), "/index.php".to_string()),
+            ("LONG".to_string(), long.clone()),
+        ];
+        let body: Vec<u8> = (0..70000).map(|i| (i % 251) as u8).collect();
+        let mut request =
+            FastCgiRequest::new(&addr.to_string(), &env, &RequestBody::Memory(body.clone()))
+                .unwrap();
+        request.stream = Some(Stream::connect(&Address::Tcp(addr)).unwrap());
+        let out = output(&mut request);
+        let mut expected =
+            b"Content-Type: text/plain\r\n\r\n11=10\nSCRIPT_NAME\n4=300\nLONG\n".to_vec();
+        expected.extend_from_slice(&body);
+        expected.extend_from_slice(b"\nstdin records: 2");
+        assert_eq!(out, expected);
+        assert!(request.reusable());
+
+        // the same connection takes the next request, with a spooled body
+        let mut stream = request.stream.take().unwrap();
+        assert!(stream.is_alive());
+        let len = SPOOL_THRESHOLD + 1;
+        let mut reader = BodyReader::new(false, len as u64, u64::MAX).unwrap();
+        reader.feed(&vec![b'x'; len]).unwrap();
+        let body = reader.finish().unwrap();
+        assert!(matches!(body, RequestBody::Spooled(_)));
+        let mut request = FastCgiRequest::new(&addr.to_string(), &[], &body).unwrap();
+        request.stream = Some(stream);
+        let out = output(&mut request);
+        let mut expected = b"Content-Type: text/plain\r\n\r\n".to_vec();
+        expected.extend_from_slice(&vec![b'x'; len]);
+        expected.extend_from_slice(
+            format!("\nstdin records: {}", len.div_ceil(STDIN_CHUNK)).as_bytes(),
+        );
+        assert!(out == expected);
+        assert!(request.reusable());
+
+        drop(request);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn bad_record_version() {
+        let mut request =
+            FastCgiRequest::new("test", &[], &RequestBody::Memory(Vec::new())).unwrap();
+        request.input = vec![2, STDOUT, 0, 1, 0, 0, 0, 0];
+        assert!(request.read_records().is_err());
+    }
+
+    #[test]
+    fn partial_record_waits() {
+        let mut request =
+            FastCgiRequest::new("test", &[], &RequestBody::Memory(Vec::new())).unwrap();
+        let mut record = Vec::new();
+        push_record(&mut record, STDOUT, b"hello");
+        request.input = record[..10].to_vec();
+        request.read_records().unwrap();
+        assert!(request.stdout.is_empty());
+        request.input.extend_from_slice(&record[10..]);
+        request.read_records().unwrap();
+        assert_eq!(request.stdout, b"hello");
+        assert!(request.input.is_empty());
+        assert!(!request.reusable());
+    }
+}
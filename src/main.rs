@@ -1,35 +1,115 @@
-use cgi::run_cgi_script;
-use mio::{Events, Interest, Poll, Token};
-use serde::Deserialize;
-use serverConfig::ServerConfig;
-use static_file::{FileResponse, build_http_response, read_static_file_with_listing};
-use std::collections::HashMap;
+use cgi::{Backend, CgiMatch, CgiOutput, CgiProcess, CgiRequest, CgiScript};
+use conditional::{Precondition, Validators};
+use config_parser::parse_config;
+use fastcgi::FastCgiRequest;
+use mio::{Events, Interest, Poll, Registry, Token};
+use proxy::ProxyRequest;
+use range::RangeRequest;
+use serverConfig::{RouterConfig, ServerAddress, ServerConfig, WebSocketConfig};
+use static_file::{
+    FileResponse, build_http_response, not_modified_response, partial_response,
+    read_static_file_with_listing,
+};
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::Arc;
+use tls::ClientStream;
+use upstream::{PeerId, Upstream};
+use websocket::{Handler, Incoming, ProcessHandler, ProxyHandler, Start, WebSocket};
 // use std::os::unix::io::{AsRawFd, RawFd};
-use mio::net::{TcpListener, TcpStream};
-use requests::{Request, Response, build_response, parse_http_request};
+use mio::net::TcpListener;
+use request_body::{BodyError, BodyReader};
+use requests::{
+    Headers, Request, Response, ResponseBody, build_response, chunk, last_chunk,
+    parse_request_head, response_head,
+};
 use std::time::Instant;
 use std::{fs, time::Duration};
-use upload_handler::{UploadResult, build_upload_response, handle_file_upload};
+use upload_handler::{build_upload_response, handle_file_upload};
 mod session_manager;
 use session_manager::SessionManager;
 
-use crate::serverConfig::Connection;
+use crate::serverConfig::{CgiJob, Connection, EventStreamJob, PendingRequest};
 mod cgi;
+mod cli;
+mod compression;
+mod conditional;
+mod config_check;
+mod config_parser;
+mod fastcgi;
+mod mime;
+mod proxy;
+mod range;
+mod request_body;
 mod requests;
+mod response_writer;
+#[allow(non_snake_case)]
 mod serverConfig;
+mod sse;
 mod static_file;
+mod tls;
 mod upload_handler;
+mod upstream;
+mod websocket;
 
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30); // Increased from 10 to 30 seconds
 
 fn main() {
-    let servers = json_parser();
+    let options = match cli::parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("localhost: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        print!("{}", cli::USAGE);
+        return;
+    }
+
+    let config_path = &options.config_path;
+    println!("Loading config {}", config_path.display());
+    let mut servers = match load_config(config_path) {
+        Ok(servers) => servers,
+        Err(e) => {
+            eprintln!("Config error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cli::apply_overrides(&options, &mut servers) {
+        eprintln!("Config error: {}", e);
+        std::process::exit(1);
+    }
+
+    // every problem is reported before any socket is bound
+    if let Err(issues) = config_check::validate_config(&servers) {
+        for issue in &issues {
+            eprintln!("Config error: {}", issue);
+        }
+        eprintln!(
+            "configuration file {} has {} error(s)",
+            config_path.display(),
+            issues.len()
+        );
+        std::process::exit(1);
+    }
+    // like nginx -t: only check the configuration
+    if options.test_config {
+        println!(
+            "configuration file {} test is successful",
+            config_path.display()
+        );
+        return;
+    }
+
     let mut session_manager = SessionManager::new(); // create a new session manager
-    for server in &servers {
-        listener_socket(server, &mut session_manager, server);
+    if let Err(e) = listener_socket(&servers, &mut session_manager) {
+        eprintln!("Server error: {}", e);
+        std::process::exit(1);
     }
 }
 // fn test_cgi()-> Result<(), Box<dyn Error>> {
@@ -46,650 +126,2303 @@ fn main() {
 // Ok(())
 // }
 
-fn json_parser() -> Vec<ServerConfig> {
-    let mut wd = env::current_dir().unwrap();
-    let config_path = wd.join("src/config.json");
-    println!("wd {}", wd.display());
-    let file = fs::read_to_string(config_path).expect("file don't exist");
-    println!("fu=ile {}", file);
-    let servers: Vec<ServerConfig> =
-        serde_json::from_str(&file).expect("JSON was not well-formatted");
-
-    // let servers: Vec<ServerConfig> =
-    //     serde_yaml::from_str(&file).expect("yaml was not well-formatted");
-    //for printing the servers configs
-    // for server in servers {
-    //     println!("Server Name: {:#?}", server);
-    // }
-
-    servers
+// Loads the server blocks from a JSON file or, for any other extension,
+// from the nginx-style config.conf format
+fn load_config(path: &Path) -> Result<Vec<ServerConfig>, String> {
+    let file = fs::read_to_string(path).map_err(|e| {
+        // a relative path is shown as it was resolved, from the working directory
+        let tried = std::path::absolute(path).unwrap_or(path.to_path_buf());
+        let mut message = format!("cannot read config file {}: {}", tried.display(), e);
+        if path == Path::new(cli::DEFAULT_CONFIG) {
+            message += " (run from the repository root, or pass --config <PATH>)";
+        }
+        message
+    })?;
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&file).map_err(|e| format!("{}: {}", path.display(), e))
+    } else {
+        parse_config(&file).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// A bound socket and the server blocks that share its ip:port.
+// The first entry of `servers` is the default block for that address.
+pub struct ServerListener {
+    pub listener: TcpListener,
+    pub servers: Vec<usize>,
+    pub tls: Option<Arc<rustls::ServerConfig>>, // HTTPS, with a certificate per server block
+}
+
+fn socket_address(address: &ServerAddress) -> Result<std::net::SocketAddr, String> {
+    let ip: std::net::IpAddr = address
+        .ip
+        .parse()
+        .map_err(|_| format!("Invalid socket address {}:{}", address.ip, address.port))?;
+    Ok(std::net::SocketAddr::new(ip, address.port))
 }
-const SERVER: Token = Token(0);
 
+// Binds every distinct address once. Server blocks that declare the same
+// ip:port share one listener and are told apart by the Host header.
 fn listener_socket(
-    server: &ServerConfig,
+    servers: &[ServerConfig],
     session_manager: &mut SessionManager,
-    server_config: &ServerConfig,
 ) -> std::io::Result<()> {
-    let mut listeners: HashMap<Token, TcpListener> = HashMap::new();
+    let mut groups: Vec<(std::net::SocketAddr, Vec<usize>)> = Vec::new();
+    // the `tls` of each server block on an address
+    let mut certificates: HashMap<(std::net::SocketAddr, usize), &serverConfig::TlsConfig> =
+        HashMap::new();
+
+    for (server_index, server) in servers.iter().enumerate() {
+        for address in &server.server_address {
+            let socket_addr = socket_address(address)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            if let Some(tls) = &address.tls {
+                certificates.insert((socket_addr, server_index), tls);
+            }
+            match groups.iter_mut().find(|(addr, _)| *addr == socket_addr) {
+                Some((_, members)) => {
+                    if !members.contains(&server_index) {
+                        members.push(server_index);
+                    }
+                }
+                None => groups.push((socket_addr, vec![server_index])),
+            }
+        }
+    }
 
-    for address in &server.server_address {
-        let mut next_token = listeners.len();
-        let bind_address = format!("{}:{}", address.ip, address.port);
-        let socket_addr: std::net::SocketAddr =
-            bind_address.parse().expect("Invalid socket address");
+    let mut listeners: HashMap<Token, ServerListener> = HashMap::new();
+    for (socket_addr, mut members) in groups {
+        // the block marked default_server (or the first one) answers unknown hosts
+        if let Some(pos) = members.iter().position(|&i| servers[i].default_server) {
+            let default = members.remove(pos);
+            members.insert(0, default);
+        }
 
         // opens a tcp socket and it become passive
         // binding is like : I want to listen for connections on this IP:PORT
-        let mut listener =
-            TcpListener::bind(socket_addr).expect(&format!("Failed to bind to {}", bind_address));
-        println!("Listening on {}", bind_address);
+        let listener = TcpListener::bind(socket_addr).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to bind to {}: {}", socket_addr, e),
+            )
+        })?;
+        let names: Vec<&str> = members
+            .iter()
+            .map(|&i| servers[i].server_name.as_str())
+            .collect();
+        let blocks: Vec<(&ServerConfig, &serverConfig::TlsConfig)> = members
+            .iter()
+            .filter_map(|&i| Some((&servers[i], *certificates.get(&(socket_addr, i))?)))
+            .collect();
+        let tls = if blocks.is_empty() {
+            None
+        } else {
+            Some(
+                tls::server_config(&blocks)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            )
+        };
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        println!(
+            "Listening on {}://{} ({})",
+            scheme,
+            socket_addr,
+            names.join(", ")
+        );
+
+        let token = Token(listeners.len());
+
+        listeners.insert(
+            token,
+            ServerListener {
+                listener,
+                servers: members,
+                tls,
+            },
+        );
+    }
 
-        let token = Token(next_token);
-        println!("listenr tokken {:?}", token);
+    run_mio_server(listeners, session_manager, servers)
+}
+
+// Picks the index of the server block whose server_name matches the Host header,
+// falling back to the default block of the listener. Names compare without
+// case and an IPv6 literal without its brackets.
+fn select_server(servers: &[ServerConfig], candidates: &[usize], host: Option<&str>) -> usize {
+    if let Some(host) = host {
+        let host = strip_host_port(host.trim());
+        for &index in candidates {
+            if servers[index]
+                .server_name
+                .split_whitespace()
+                .any(|name| strip_brackets(name).eq_ignore_ascii_case(host))
+            {
+                return index;
+            }
+        }
+    }
+    candidates[0]
+}
 
-        listeners.insert(token, listener);
+// "example.com:8080" -> "example.com", "[::1]:8080" -> "::1"
+fn strip_host_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((address, _)) => address,
+            None => host,
+        };
+    }
+    match host.rsplit_once(':') {
+        // a bare IPv6 address has no port to take off
+        Some((name, _)) if name.contains(':') => host,
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
     }
+}
+
+fn strip_brackets(name: &str) -> &str {
+    name.strip_prefix('[')
+        .and_then(|name| name.strip_suffix(']'))
+        .unwrap_or(name)
+}
+
+// Helper to load custom error page if configured. A value starting with
+// '/' (error_page 404 /errors/404.html) is a URI served from the root of
+// the route it falls under, anything else is a page name under html/.
+fn custom_error_body(code: u16, config: &ServerConfig) -> Option<Vec<u8>> {
+    let page = config.error_msg.get(&code)?;
+    let path = if page.starts_with('/') {
+        let route = config.find_route(page)?;
+        Path::new(&route.root).join(page.trim_start_matches('/'))
+    } else {
+        PathBuf::from(format!(
+            "html/{}.html",
+            page.replace(' ', "_").to_lowercase()
+        ))
+    };
+    std::fs::read(path).ok()
+}
+
+// Error page response with the custom body when one is configured
+fn error_response(code: u16, reason: &str, server_config: &ServerConfig) -> Response {
+    let body = custom_error_body(code, server_config)
+        .unwrap_or_else(|| format!("<h1>{} {}</h1>", code, reason).into_bytes());
+    Response::html(code, reason, body)
+}
 
-    run_mio_server(listeners, session_manager, server_config)
+// What a request turned into: a response ready to send, a CGI script
+// whose output the event loop sends as it arrives, a WebSocket handshake, or
+// a stream of events from a file
+enum Reply {
+    Ready(Response),
+    Cgi(Box<CgiRequest>),
+    WebSocket(Box<websocket::Upgrade>),
+    Events(Box<EventStreamJob>),
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        Reply::Ready(response)
+    }
 }
 
 // Centralized request handler
 fn handle_request(
-    raw_request: &[u8],
+    request: Option<Request>,
     session_manager: &mut SessionManager,
     server_config: &ServerConfig,
-) -> Vec<u8> {
-    // Helper to load custom error page if configured
-    fn custom_error_body(code: u16, config: &ServerConfig) -> Option<Vec<u8>> {
-        if let Some(page_name) = config.error_msg.get(&code) {
-            let path = format!("html/{}.html", page_name.replace(' ', "_").to_lowercase());
-            if let Ok(contents) = std::fs::read(&path) {
-                return Some(contents);
-            }
-        }
-        None
-    }
-    // Parse the HTTP request
-    let req = match parse_http_request(raw_request) {
-        Some(r) => r,
-        None => {
-            let mut headers = HashMap::new();
-            let body = custom_error_body(400, server_config)
-                .unwrap_or_else(|| b"<h1>400 Bad Request</h1>".to_vec());
-            headers.insert("Content-Type".to_string(), "text/html".to_string());
-            return build_response(Response {
-                status_code: 400,
-                reason_phrase: "Bad Request".to_string(),
-                headers,
-                body,
-            });
-        }
+) -> Reply {
+    // Requests that failed to parse get a 400
+    let Some(req) = request else {
+        return error_response(400, "Bad Request", server_config).into();
     };
     // Session management
-    let cookie_header = req.headers.get("Cookie").map(|s| s.as_str());
+    let cookie_header = req.header("Cookie");
     let session = session_manager.get_or_create_session(cookie_header);
     let mut set_cookie_header = None;
     if cookie_header.is_none() || !cookie_header.unwrap().contains(&session.id) {
         set_cookie_header = Some(format!("session_id={}; Path=/; HttpOnly", session.id));
     }
-    // Routing: find matching route
-    println!("DEBUG: Request path: '{}'", req.path);
-    println!("DEBUG: Request method: '{}'", req.method);
-    let route = server_config.router.iter().max_by_key(|r| {
-        if req.path.starts_with(&r.path) {
-            r.path.len()
-        } else {
-            0
-        }
-    });
-    if let Some(route) = route {
-        println!("DEBUG: Matched route path: '{}'", route.path);
-        println!("DEBUG: Route methods: {:?}", route.methods);
-
-        // Redirection support
-        if let Some(redir) = &route.redirection {
-            let status = redir.status.unwrap_or(302);
-            let reason = match status {
-                301 => "Moved Permanently",
-                302 => "Found",
-                307 => "Temporary Redirect",
-                308 => "Permanent Redirect",
-                _ => "Found",
+
+    let reply = route_request(&req, server_config, 0);
+    finish_reply(&req, reply, set_cookie_header, server_config)
+}
+
+// Compression and the session cookie; a script's response gets the cookie
+// once its header block is in. A generated body is chunked only for HTTP/1.1.
+fn finish_reply(
+    req: &Request,
+    reply: Reply,
+    set_cookie: Option<String>,
+    server_config: &ServerConfig,
+) -> Reply {
+    match reply {
+        Reply::Ready(mut response) => {
+            if let ResponseBody::Stream(stream) = &mut response.body {
+                stream.chunked = req.version == "HTTP/1.1";
+            }
+            if let Some(route) = server_config.find_route(req.uri_path())
+                && let Some(compression) = &route.compression
+            {
+                compression::apply(req, compression, &mut response);
+            }
+            if let Some(cookie) = set_cookie {
+                response.headers.append("Set-Cookie", cookie);
+            }
+            Reply::Ready(response)
+        }
+        Reply::Cgi(mut cgi) => {
+            cgi.set_cookie = set_cookie;
+            Reply::Cgi(cgi)
+        }
+        Reply::WebSocket(mut upgrade) => {
+            upgrade.set_cookie = set_cookie;
+            Reply::WebSocket(upgrade)
+        }
+        Reply::Events(mut job) => {
+            job.set_cookie = set_cookie;
+            Reply::Events(job)
+        }
+    }
+}
+
+// a CGI local redirect can lead to another one; this many and we give up
+const MAX_LOCAL_REDIRECTS: usize = 10;
+
+// Routing: find the matching route and run its handler. `local_redirects`
+// counts the CGI local redirects that led to this request.
+fn route_request(req: &Request, server_config: &ServerConfig, local_redirects: usize) -> Reply {
+    let Some(route) = server_config.find_route(req.uri_path()) else {
+        // No matching route
+        return error_response(404, "Not Found", server_config).into();
+    };
+
+    // Redirection support
+    if let Some(redir) = &route.redirection {
+        let status = redir.status.unwrap_or(302);
+        let reason = match status {
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            _ => "Found",
+        };
+        let mut headers = Headers::new();
+        headers.insert("Location".to_string(), redir.target.clone());
+        return Response {
+            status_code: status,
+            reason_phrase: reason.to_string(),
+            headers,
+            body: Vec::new().into(),
+        }
+        .into();
+    }
+    // Method allowed?
+    if !route.methods.iter().any(|m| m == &req.method) {
+        let mut response = error_response(405, "Method Not Allowed", server_config);
+        response
+            .headers
+            .insert("Allow".to_string(), route.methods.join(", "));
+        return response.into();
+    }
+    // WebSocket: the handshake is checked here, the event loop runs the handler
+    if let Some(ws) = &route.websocket
+        && websocket::is_upgrade(req)
+    {
+        return websocket_upgrade(req, server_config, route, ws);
+    }
+    // Server-sent events: the event loop sends the lines the file gains
+    if let Some(file) = &route.event_stream
+        && req.method == "GET"
+    {
+        return match sse::FileEvents::open(&Path::new(&route.root).join(file), req) {
+            Ok(events) => Reply::Events(Box::new(EventStreamJob {
+                events,
+                chunked: req.version == "HTTP/1.1",
+                set_cookie: None,
+                next_poll: Instant::now(),
+            })),
+            Err(e) => {
+                eprintln!("Failed to open event stream {}: {}", file, e);
+                if e.kind() == io::ErrorKind::NotFound {
+                    error_response(404, "Not Found", server_config).into()
+                } else {
+                    error_response(500, "Internal Server Error", server_config).into()
+                }
+            }
+        };
+    }
+    // Reverse proxy: the request is forwarded to the upstream by the event loop
+    if let Some(proxy_pass) = &route.proxy_pass {
+        let timeout = route.proxy_timeout.unwrap_or(proxy::DEFAULT_TIMEOUT);
+        let backend = proxy::Target::parse(proxy_pass)
+            .map_err(io::Error::other)
+            .and_then(|target| ProxyRequest::new(target, req, route))
+            .map(Backend::Proxy);
+        let name = format!("proxy_pass {}", proxy_pass);
+        return start_cgi(req, server_config, name, timeout, backend, local_redirects);
+    }
+    let cgi_timeout = route.cgi_timeout.unwrap_or(cgi::DEFAULT_TIMEOUT);
+    // FastCGI: the request is sent to the route's server by the event loop
+    if let Some(address) = &route.fastcgi
+        && let Some(script) = cgi::fastcgi_script(route, req.uri_path())
+    {
+        let env = cgi::cgi_environment(req, server_config, route, &script);
+        let backend = FastCgiRequest::new(address, &env, &req.body).map(Backend::FastCgi);
+        let name = script.path.display().to_string();
+        return start_cgi(
+            req,
+            server_config,
+            name,
+            cgi_timeout,
+            backend,
+            local_redirects,
+        );
+    }
+    // CGI handler: the script is started here, its output is read by the event loop
+    match cgi::find_script(route, req.uri_path()) {
+        CgiMatch::Script(script) => {
+            let env = cgi::cgi_environment(req, server_config, route, &script);
+            let backend = CgiProcess::spawn(&script, &req.body, &env).map(Backend::Process);
+            let name = script.path.display().to_string();
+            return start_cgi(
+                req,
+                server_config,
+                name,
+                cgi_timeout,
+                backend,
+                local_redirects,
+            );
+        }
+        CgiMatch::Forbidden => return error_response(403, "Forbidden", server_config).into(),
+        CgiMatch::NotCgi => {}
+    }
+    // Upload handler
+    if req.method == "POST" && route.path == "/upload" {
+        let content_type = req.header("Content-Type").unwrap_or("");
+        let result = handle_file_upload(&req.body, content_type);
+        return build_upload_response(result).into();
+    }
+    // DELETE handler
+    if req.method == "DELETE" {
+        return delete_file(req, route, server_config).into();
+    }
+    // Static file handler
+    let rel_path = req.uri_path().trim_start_matches('/');
+    let file_response = read_static_file_with_listing(
+        rel_path,
+        &route.root,
+        route.index.as_deref(),
+        route.directory_listing.unwrap_or(false),
+    );
+    let response = match file_response {
+        FileResponse::NotFound => error_response(404, "Not Found", server_config),
+        FileResponse::Forbidden => error_response(403, "Forbidden", server_config),
+        FileResponse::Ok(ref static_file) => {
+            let len = static_file.metadata.len();
+            let file_validators = Validators::from_metadata(&static_file.metadata);
+            let range = range::requested(req, &file_validators, len);
+            // a whole file may go out as its .br/.gz copy, which the
+            // preconditions are then checked against
+            let sibling = match (&range, &route.compression) {
+                (RangeRequest::Full, Some(compression)) => compression::precompressed(
+                    req,
+                    compression,
+                    &static_file.path,
+                    &file_validators,
+                ),
+                _ => None,
             };
-            let mut headers = HashMap::new();
-            headers.insert("Location".to_string(), redir.target.clone());
-            if let Some(cookie) = set_cookie_header.clone() {
-                headers.insert("Set-Cookie".to_string(), cookie);
-            }
-            return build_response(Response {
-                status_code: status,
-                reason_phrase: reason.to_string(),
-                headers,
-                body: Vec::new(),
-            });
-        }
-        // Method allowed?
-        if !route.methods.iter().any(|m| m == &req.method) {
-            let mut headers = HashMap::new();
-            headers.insert("Content-Type".to_string(), "text/html".to_string());
-            if let Some(cookie) = set_cookie_header.clone() {
-                headers.insert("Set-Cookie".to_string(), cookie);
-            }
-            let body = custom_error_body(405, server_config)
-                .unwrap_or_else(|| b"<h1>405 Method Not Allowed</h1>".to_vec());
-            return build_response(Response {
-                status_code: 405,
-                reason_phrase: "Method Not Allowed".to_string(),
-                headers,
-                body,
-            });
-        }
-        // CGI handler
-        if let Some((ext, script)) = &route.cgi {
-            if req.path.ends_with(ext) {
-                let path_info = &req.path;
-                // Construct the full path to the script
-                let script_path = format!("{}/{}", route.root, script);
-                match run_cgi_script(
-                    &script_path,
-                    std::str::from_utf8(&req.body).unwrap_or(""),
-                    path_info,
-                ) {
-                    Ok(output) => {
-                        let mut headers = HashMap::new();
-                        headers.insert("Content-Type".to_string(), "text/plain".to_string());
-                        if let Some(cookie) = set_cookie_header.clone() {
-                            headers.insert("Set-Cookie".to_string(), cookie);
+            let validators = sibling.as_ref().map_or(&file_validators, |s| &s.validators);
+            match conditional::evaluate(req, validators) {
+                Precondition::NotModified => not_modified_response(validators),
+                Precondition::Failed => error_response(412, "Precondition Failed", server_config),
+                Precondition::Proceed => {
+                    let response = build_http_response(
+                        file_response,
+                        &server_config.mime_types,
+                        route.default_type.as_deref(),
+                    );
+                    match range {
+                        RangeRequest::Full => {
+                            let mut response = response;
+                            if let Some(sibling) = sibling {
+                                compression::serve_precompressed(sibling, &mut response);
+                            }
+                            response
                         }
-                        return build_response(Response {
-                            status_code: 200,
-                            reason_phrase: "OK".to_string(),
-                            headers,
-                            body: output.into_bytes(),
-                        });
-                    }
-                    Err(_) => {
-                        let mut headers = HashMap::new();
-                        headers.insert("Content-Type".to_string(), "text/html".to_string());
-                        if let Some(cookie) = set_cookie_header.clone() {
-                            headers.insert("Set-Cookie".to_string(), cookie);
+                        RangeRequest::Ranges(ranges) => partial_response(response, &ranges, len)
+                            .unwrap_or_else(|_| {
+                                error_response(500, "Internal Server Error", server_config)
+                            }),
+                        RangeRequest::Unsatisfiable => {
+                            let mut response =
+                                error_response(416, "Range Not Satisfiable", server_config);
+                            response
+                                .headers
+                                .insert("Content-Range".to_string(), format!("bytes */{}", len));
+                            response
                         }
-                        let body = custom_error_body(500, server_config)
-                            .unwrap_or_else(|| b"<h1>500 Internal Server Error</h1>".to_vec());
-                        return build_response(Response {
-                            status_code: 500,
-                            reason_phrase: "Internal Server Error".to_string(),
-                            headers,
-                            body,
-                        });
                     }
                 }
             }
         }
-        // Upload handler
-        if req.method == "POST" && route.path == "/upload" {
-            println!("DEBUG: Upload handler condition met!");
-            let content_type = req
-                .headers
-                .get("Content-Type")
-                .map(|s| s.as_str())
-                .unwrap_or("");
-            let result = handle_file_upload(&req.body, content_type);
-            let mut response = build_upload_response(result);
-            if let Some(cookie) = set_cookie_header {
-                // Insert Set-Cookie header if needed
-                let mut resp_str = String::from_utf8_lossy(&response).to_string();
-                let insert_pos = resp_str.find("\r\n\r\n").unwrap_or(resp_str.len());
-                resp_str.insert_str(insert_pos, &format!("Set-Cookie: {}\r\n", cookie));
-                return resp_str.into_bytes();
-            }
-            return response;
-        } else {
-            println!(
-                "DEBUG: Upload handler condition NOT met. req.method='{}', route.path='{}'",
-                req.method, route.path
-            );
+        _ => build_http_response(
+            file_response,
+            &server_config.mime_types,
+            route.default_type.as_deref(),
+        ),
+    };
+    response.into()
+}
+
+fn websocket_upgrade(
+    req: &Request,
+    server_config: &ServerConfig,
+    route: &RouterConfig,
+    ws: &WebSocketConfig,
+) -> Reply {
+    let accept = match websocket::accept(req) {
+        Ok(accept) => accept,
+        Err(websocket::HandshakeError::Invalid(reason)) => {
+            eprintln!("Bad WebSocket handshake: {}", reason);
+            return error_response(400, "Bad Request", server_config).into();
         }
-        // DELETE handler
-        if req.method == "DELETE" {
-            // Only allow DELETE for files, not directories
-            let rel_path = if req.path == "/" { "" } else { &req.path[1..] };
-            let base = std::path::Path::new(&route.root);
-            let full_path = base.join(rel_path);
-            let full_path = match std::fs::canonicalize(&full_path) {
-                Ok(path) => path,
-                Err(_) => {
-                    let body = custom_error_body(404, server_config)
-                        .unwrap_or_else(|| b"<h1>404 Not Found</h1>".to_vec());
-                    return build_response(Response {
-                        status_code: 404,
-                        reason_phrase: "Not Found".to_string(),
-                        headers: {
-                            let mut h = HashMap::new();
-                            h.insert("Content-Type".to_string(), "text/html".to_string());
-                            if let Some(cookie) = set_cookie_header.clone() {
-                                h.insert("Set-Cookie".to_string(), cookie);
-                            }
-                            h
-                        },
-                        body,
-                    });
-                }
+        Err(websocket::HandshakeError::Version) => {
+            let mut response = error_response(426, "Upgrade Required", server_config);
+            response.headers.insert("Sec-WebSocket-Version", "13");
+            return response.into();
+        }
+    };
+    let target = ws.target.as_deref().unwrap_or("");
+    let handler = match ws.handler.as_str() {
+        "process" => {
+            // run like a CGI script: the last word is the script, relative to the root
+            let argv: Vec<String> = target.split_whitespace().map(str::to_string).collect();
+            let script = CgiScript {
+                path: Path::new(&route.root).join(argv.last().map_or("", |a| a.as_str())),
+                script_name: route.path.clone(),
+                path_info: req
+                    .uri_path()
+                    .get(route.path.len()..)
+                    .unwrap_or("")
+                    .to_string(),
+                interpreter: (argv.len() > 1).then(|| argv[..argv.len() - 1].to_vec()),
             };
-            if !full_path.starts_with(std::fs::canonicalize(base).unwrap()) {
-                let body = custom_error_body(403, server_config)
-                    .unwrap_or_else(|| b"<h1>403 Forbidden</h1>".to_vec());
-                return build_response(Response {
-                    status_code: 403,
-                    reason_phrase: "Forbidden".to_string(),
-                    headers: {
-                        let mut h = HashMap::new();
-                        h.insert("Content-Type".to_string(), "text/html".to_string());
-                        if let Some(cookie) = set_cookie_header.clone() {
-                            h.insert("Set-Cookie".to_string(), cookie);
-                        }
-                        h
-                    },
-                    body,
-                });
-            }
-            if full_path.is_dir() {
-                let body = custom_error_body(403, server_config)
-                    .unwrap_or_else(|| b"<h1>403 Forbidden</h1>".to_vec());
-                return build_response(Response {
-                    status_code: 403,
-                    reason_phrase: "Forbidden".to_string(),
-                    headers: {
-                        let mut h = HashMap::new();
-                        h.insert("Content-Type".to_string(), "text/html".to_string());
-                        if let Some(cookie) = set_cookie_header.clone() {
-                            h.insert("Set-Cookie".to_string(), cookie);
-                        }
-                        h
-                    },
-                    body,
-                });
-            }
-            match std::fs::remove_file(&full_path) {
-                Ok(_) => {
-                    let body = b"<h1>File deleted successfully</h1>".to_vec();
-                    return build_response(Response {
-                        status_code: 200,
-                        reason_phrase: "OK".to_string(),
-                        headers: {
-                            let mut h = HashMap::new();
-                            h.insert("Content-Type".to_string(), "text/html".to_string());
-                            if let Some(cookie) = set_cookie_header.clone() {
-                                h.insert("Set-Cookie".to_string(), cookie);
-                            }
-                            h
-                        },
-                        body,
-                    });
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    let body = custom_error_body(404, server_config)
-                        .unwrap_or_else(|| b"<h1>404 Not Found</h1>".to_vec());
-                    return build_response(Response {
-                        status_code: 404,
-                        reason_phrase: "Not Found".to_string(),
-                        headers: {
-                            let mut h = HashMap::new();
-                            h.insert("Content-Type".to_string(), "text/html".to_string());
-                            if let Some(cookie) = set_cookie_header.clone() {
-                                h.insert("Set-Cookie".to_string(), cookie);
-                            }
-                            h
-                        },
-                        body,
-                    });
-                }
-                Err(_) => {
-                    let body = custom_error_body(500, server_config)
-                        .unwrap_or_else(|| b"<h1>500 Internal Server Error</h1>".to_vec());
-                    return build_response(Response {
-                        status_code: 500,
-                        reason_phrase: "Internal Server Error".to_string(),
-                        headers: {
-                            let mut h = HashMap::new();
-                            h.insert("Content-Type".to_string(), "text/html".to_string());
-                            if let Some(cookie) = set_cookie_header.clone() {
-                                h.insert("Set-Cookie".to_string(), cookie);
-                            }
-                            h
-                        },
-                        body,
-                    });
+            let env = cgi::cgi_environment(req, server_config, route, &script);
+            match CgiProcess::interactive(&script, &env) {
+                Ok(process) => Start::Process(process),
+                Err(e) => {
+                    eprintln!("Failed to start {}: {}", target, e);
+                    return error_response(500, "Internal Server Error", server_config).into();
                 }
             }
         }
-        // Static file handler
-        let rel_path = if req.path == "/" { "" } else { &req.path[1..] };
-        let file_response = read_static_file_with_listing(
-            rel_path,
-            &route.root,
-            route.index.as_deref(),
-            route.directory_listing.unwrap_or(false),
-        );
-        let mut response = match &file_response {
-            FileResponse::Ok(_) | FileResponse::DirectoryListing(_) => {
-                build_http_response(file_response)
-            }
-            FileResponse::NotFound => {
-                let body = custom_error_body(404, server_config)
-                    .unwrap_or_else(|| b"<h1>404 Not Found</h1>".to_vec());
-                build_response(Response {
-                    status_code: 404,
-                    reason_phrase: "Not Found".to_string(),
-                    headers: {
-                        let mut h = HashMap::new();
-                        h.insert("Content-Type".to_string(), "text/html".to_string());
-                        if let Some(cookie) = set_cookie_header.clone() {
-                            h.insert("Set-Cookie".to_string(), cookie);
-                        }
-                        h
-                    },
-                    body,
-                })
+        "proxy" => match websocket::parse_target(target) {
+            Ok(target) => Start::Proxy {
+                // our own key and version go out, and no extensions are offered
+                head: proxy::forwarded_head(
+                    &target,
+                    req,
+                    route,
+                    &[
+                        "Sec-WebSocket-Key",
+                        "Sec-WebSocket-Version",
+                        "Sec-WebSocket-Extensions",
+                    ],
+                ),
+                target,
+                client_ip: req.remote_addr.map(|addr| addr.ip()),
+            },
+            Err(e) => {
+                eprintln!("Bad WebSocket proxy target {}: {}", target, e);
+                return error_response(500, "Internal Server Error", server_config).into();
             }
-            FileResponse::Forbidden => {
-                let body = custom_error_body(403, server_config)
-                    .unwrap_or_else(|| b"<h1>403 Forbidden</h1>".to_vec());
-                build_response(Response {
-                    status_code: 403,
-                    reason_phrase: "Forbidden".to_string(),
-                    headers: {
-                        let mut h = HashMap::new();
-                        h.insert("Content-Type".to_string(), "text/html".to_string());
-                        if let Some(cookie) = set_cookie_header.clone() {
-                            h.insert("Set-Cookie".to_string(), cookie);
-                        }
-                        h
-                    },
-                    body,
-                })
-            }
-        };
-        if let Some(cookie) = set_cookie_header {
-            // Insert Set-Cookie header if needed
-            let mut resp_str = String::from_utf8_lossy(&response).to_string();
-            let insert_pos = resp_str.find("\r\n\r\n").unwrap_or(resp_str.len());
-            resp_str.insert_str(insert_pos, &format!("Set-Cookie: {}\r\n", cookie));
-            return resp_str.into_bytes();
-        }
-        return response;
-    }
-    // No matching route
-    let mut headers = HashMap::new();
-    headers.insert("Content-Type".to_string(), "text/html".to_string());
-    if let Some(cookie) = set_cookie_header {
-        headers.insert("Set-Cookie".to_string(), cookie);
-    }
-    let body =
-        custom_error_body(404, server_config).unwrap_or_else(|| b"<h1>404 Not Found</h1>".to_vec());
-    build_response(Response {
-        status_code: 404,
-        reason_phrase: "Not Found".to_string(),
-        headers,
-        body,
-    })
+        },
+        _ => Start::Echo,
+    };
+    Reply::WebSocket(Box::new(websocket::Upgrade {
+        accept,
+        set_cookie: None,
+        max_message: ws
+            .max_message_size
+            .unwrap_or(websocket::DEFAULT_MAX_MESSAGE),
+        handler,
+    }))
+}
+
+// `timeout` is in seconds; `name` is what the logs call the script or upstream
+fn start_cgi(
+    req: &Request,
+    server_config: &ServerConfig,
+    name: String,
+    timeout: u64,
+    backend: io::Result<Backend>,
+    local_redirects: usize,
+) -> Reply {
+    match backend {
+        Ok(backend) => {
+            let timeout = Duration::from_secs(timeout);
+            let mut cgi = CgiRequest::new(name, req, backend, timeout);
+            cgi.local_redirects = local_redirects;
+            Reply::Cgi(Box::new(cgi))
+        }
+        Err(e) => {
+            eprintln!("Failed to start {}: {}", name, e);
+            error_response(500, "Internal Server Error", server_config).into()
+        }
+    }
+}
+
+fn delete_file(req: &Request, route: &RouterConfig, server_config: &ServerConfig) -> Response {
+    // Only allow DELETE for files, not directories
+    let rel_path = req.uri_path().trim_start_matches('/');
+    let base = std::path::Path::new(&route.root);
+    let full_path = base.join(rel_path);
+    let full_path = match std::fs::canonicalize(&full_path) {
+        Ok(path) => path,
+        Err(_) => return error_response(404, "Not Found", server_config),
+    };
+    if !full_path.starts_with(std::fs::canonicalize(base).unwrap()) || full_path.is_dir() {
+        return error_response(403, "Forbidden", server_config);
+    }
+    // If-Match / If-Unmodified-Since: don't delete a file the client hasn't seen
+    if let Ok(metadata) = std::fs::metadata(&full_path)
+        && let Precondition::Failed =
+            conditional::evaluate(req, &Validators::from_metadata(&metadata))
+    {
+        return error_response(412, "Precondition Failed", server_config);
+    }
+    match std::fs::remove_file(&full_path) {
+        Ok(_) => Response::html(200, "OK", b"<h1>File deleted successfully</h1>".to_vec()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            error_response(404, "Not Found", server_config)
+        }
+        Err(_) => error_response(500, "Internal Server Error", server_config),
+    }
+}
+
+// bytes queued for a client before a script's stdout is left unread; the
+// script then blocks on a full pipe until the client catches up
+const CGI_MAX_QUEUED: u64 = 1024 * 1024;
+// a script's header block may not be longer than this
+const CGI_MAX_HEADER_BLOCK: usize = 64 * 1024;
+// request line and headers together; a longer head gets 431
+const MAX_HEAD_SIZE: usize = 32 * 1024;
+// idle connections kept open per FastCGI server
+const FASTCGI_MAX_IDLE: usize = 8;
+// and per proxied upstream
+const PROXY_MAX_IDLE: usize = 8;
+// a quiet WebSocket gets a ping after this, and is closed if it goes unanswered as long
+const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
+// how long the client has to answer our close frame
+const WS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// an event stream quiet this long gets a heartbeat comment
+const SSE_HEARTBEAT: Duration = Duration::from_secs(15);
+// how often `event_stream` files are looked at for new lines
+const SSE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// State of the event loop shared by the connection handlers. Each handler gets
+// the connection out of `clients` and says whether it should stay open.
+struct EventLoop<'a> {
+    registry: Registry,
+    listeners: HashMap<Token, ServerListener>,
+    servers: &'a [ServerConfig],
+    session_manager: &'a mut SessionManager,
+    next_token: usize,
+    pipes: HashMap<Token, Token>, // CGI pipe -> client connection it answers
+    exited: Vec<Child>,           // scripts done or killed, waiting to be reaped
+    fastcgi_idle: HashMap<String, Vec<fastcgi::Stream>>, // open FastCGI connections by address
+    proxy_idle: HashMap<String, Vec<mio::net::TcpStream>>, // open upstream connections by host:port
+    addresses: HashMap<String, std::net::SocketAddr>, // proxy_pass hosts resolved so far
+    upstreams: HashMap<(usize, String), Upstream>, // by server index and name
+    probes: HashMap<Token, (PeerId, upstream::Probe)>, // health checks in progress
 }
 
 pub fn run_mio_server(
-    mut listeners: HashMap<Token, TcpListener>,
+    mut listeners: HashMap<Token, ServerListener>,
     session_manager: &mut SessionManager,
-    server_config: &ServerConfig,
+    servers: &[ServerConfig],
 ) -> std::io::Result<()> {
     let mut poll = Poll::new()?; //this is an event loop to watch socket's 
     let mut events = Events::with_capacity(2048);
 
     let mut clients: HashMap<Token, Connection> = HashMap::new();
-    let mut next_token = listeners.len() + 1;
 
     // Register all listening sockets
-    for (token, listener) in listeners.iter_mut() {
+    for (token, server_listener) in listeners.iter_mut() {
         poll.registry()
-            .register(listener, *token, Interest::READABLE)?;
+            .register(&mut server_listener.listener, *token, Interest::READABLE)?;
     }
+    let mut event_loop = EventLoop {
+        registry: poll.registry().try_clone()?,
+        next_token: listeners.len() + 1,
+        listeners,
+        servers,
+        session_manager,
+        pipes: HashMap::new(),
+        exited: Vec::new(),
+        fastcgi_idle: HashMap::new(),
+        proxy_idle: HashMap::new(),
+        addresses: HashMap::new(),
+        upstreams: servers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, server)| {
+                server
+                    .upstreams
+                    .iter()
+                    .map(move |(name, config)| ((index, name.clone()), Upstream::new(name, config)))
+            })
+            .collect(),
+        probes: HashMap::new(),
+    };
 
     println!("Starting mio event loop...");
     loop {
         poll.poll(&mut events, Some(Duration::from_millis(10)))?;
         for event in events.iter() {
             let token = event.token();
-            if listeners.contains_key(&token) {
-                let listener = listeners.get_mut(&token).unwrap();
-                loop {
-                    match listener.accept() {
-                        Ok((mut stream, addr)) => {
-                            let client_token = Token(next_token);
-                            next_token += 1;
-                            poll.registry().register(
-                                &mut stream,
-                                client_token,
-                                Interest::READABLE,
-                            )?;
-                            clients.insert(
-                                client_token,
-                                Connection {
-                                    stream,
-                                    read_buffer: Vec::new(),
-                                    write_buffer: Vec::new(),
-                                    is_writing: false,
-                                    last_active: Instant::now(),
-                                },
-                            );
-                        }
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                            break;
-                        }
-                        Err(_) => {
-                            break;
-                        }
-                    }
-                }
+            if event_loop.listeners.contains_key(&token) {
+                event_loop.accept(token, &mut clients);
+                continue;
+            }
+            if event_loop.probes.contains_key(&token) {
+                event_loop.probe_event(token);
+                continue;
+            }
+            // a CGI pipe is handled together with the connection it answers
+            let client = event_loop.pipes.get(&token).copied().unwrap_or(token);
+            let Some(mut conn) = clients.remove(&client) else {
                 continue;
+            };
+            let open = if client == token {
+                event_loop.client_event(&mut conn, token, event.is_writable())
+            } else {
+                event_loop.pipe_event(&mut conn, client, token)
+            };
+            if open {
+                clients.insert(client, conn);
+            } else {
+                event_loop.close(conn);
             }
-            if let Some(conn) = clients.get_mut(&token) {
-                println!("DEBUG: Handling read event for client {:?}", token);
-                let mut temp_buf = [0; 10000];
-                match conn.stream.read(&mut temp_buf) {
-                    Ok(0) => {
-                        clients.remove(&token);
+        }
+        event_loop.check_timeouts(&mut clients);
+        event_loop.websocket_timeouts(&mut clients);
+        event_loop.event_streams(&mut clients);
+        event_loop.health_checks();
+        // collect the exit status of scripts that are done, so no zombies are left
+        event_loop
+            .exited
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+    }
+}
+
+impl EventLoop<'_> {
+    // A connection that can't be set up is dropped on its own; the listener
+    // keeps accepting
+    fn accept(&mut self, token: Token, clients: &mut HashMap<Token, Connection>) {
+        let server_listener = &self.listeners[&token];
+        loop {
+            match server_listener.listener.accept() {
+                Ok((stream, peer_addr)) => {
+                    let mut stream = match ClientStream::new(stream, server_listener.tls.as_ref()) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("TLS setup for {} failed: {}", peer_addr, e);
+                            continue;
+                        }
+                    };
+                    let client_token = Token(self.next_token);
+                    self.next_token += 1;
+                    if let Err(e) =
+                        self.registry
+                            .register(&mut stream, client_token, Interest::READABLE)
+                    {
+                        eprintln!("Failed to register connection from {}: {}", peer_addr, e);
                         continue;
                     }
-                    Ok(n) => {
-                        conn.read_buffer.extend_from_slice(&temp_buf[..n]);
-                        conn.last_active = Instant::now();
-                        println!(
-                            "DEBUG: Received {} bytes from client {:?}, total buffer: {} bytes",
-                            n,
-                            token,
-                            conn.read_buffer.len()
-                        );
-
-                        // Debug: Show the first 200 bytes of what we received
-                        let debug_len = conn.read_buffer.len().min(200);
-                        let debug_data = &conn.read_buffer[..debug_len];
-                        println!(
-                            "DEBUG: First {} bytes received: {:?}",
-                            debug_len,
-                            String::from_utf8_lossy(debug_data)
-                        );
-
-                        // Try to process the request with whatever data we have
-                        if let Some(header_end) =
-                            conn.read_buffer.windows(4).position(|w| w == b"\r\n\r\n")
-                        {
-                            let headers = &conn.read_buffer[..header_end + 4];
-                            // Try to parse Content-Length
-                            let headers_str = String::from_utf8_lossy(headers);
-                            println!("DEBUG: Headers received:\n{}", headers_str);
-
-                            // Check if this is a chunked transfer encoding request
-                            let is_chunked = headers_str.lines().any(|line| {
-                                line.to_ascii_lowercase()
-                                    .starts_with("transfer-encoding: chunked")
-                            });
-
-                            let content_length = if is_chunked {
-                                0 // For chunked requests, we'll determine length differently
-                            } else {
-                                headers_str
-                                    .lines()
-                                    .find(|line| {
-                                        line.to_ascii_lowercase().starts_with("content-length:")
-                                    })
-                                    .and_then(|line| line.split(':').nth(1))
-                                    .and_then(|v| v.trim().parse::<usize>().ok())
-                                    .unwrap_or(0)
-                            };
+                    clients.insert(
+                        client_token,
+                        Connection {
+                            stream,
+                            read_buffer: Vec::new(),
+                            head_checked: 0,
+                            write_queue: VecDeque::new(),
+                            is_writing: false,
+                            last_active: Instant::now(),
+                            listener: token,
+                            server_index: None,
+                            peer_addr,
+                            pending_request: None,
+                            requests_served: 0,
+                            close_after_write: false,
+                            cgi: None,
+                            websocket: None,
+                            event_stream: None,
+                        },
+                    );
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(_) => {
+                    break;
+                }
+            }
+        }
+    }
 
-                            let total_len = if is_chunked {
-                                // For chunked requests, we need to find the end of the chunked body
-                                // Look for the final "0\r\n\r\n" that marks the end of chunked data
-                                if let Some(chunked_end) =
-                                    find_chunked_body_end(&conn.read_buffer[header_end + 4..])
-                                {
-                                    header_end + 4 + chunked_end
-                                } else {
-                                    // Haven't received the complete chunked body yet
-                                    conn.read_buffer.len() + 1 // Force waiting for more data
-                                }
-                            } else {
-                                header_end + 4 + content_length
-                            };
+    // Reads what the client sent and answers it, then writes what is queued
+    // when the socket is writable. False when the connection should be closed.
+    fn client_event(&mut self, conn: &mut Connection, token: Token, writable: bool) -> bool {
+        let mut temp_buf = [0; 10000];
+        match conn.stream.read(&mut temp_buf) {
+            Ok(0) => return false,
+            Ok(n) => {
+                conn.read_buffer.extend_from_slice(&temp_buf[..n]);
+                conn.last_active = Instant::now();
 
-                            println!(
-                                "DEBUG: Header end: {}, Content-Length: {}, Is chunked: {}, Total needed: {}, Buffer size: {}",
-                                header_end,
-                                content_length,
-                                is_chunked,
-                                total_len,
-                                conn.read_buffer.len()
-                            );
+                if let Some(ws) = conn.websocket.as_mut() {
+                    // anything from the client answers a keepalive ping
+                    ws.ping_sent = None;
+                    self.websocket_input(conn);
+                } else if conn.close_after_write {
+                    // nothing more is answered on this connection
+                    conn.read_buffer.clear();
+                } else {
+                    self.process_requests(conn, token);
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(_) => return false,
+        }
+        if writable && conn.is_writing {
+            // files go out with sendfile, a slice per call, until the socket is full
+            match response_writer::write_queue(&mut conn.stream, &mut conn.write_queue) {
+                Ok(_) => conn.last_active = Instant::now(),
+                Err(_) => return false,
+            }
+            // the client took some output, a paused script may go on
+            if conn.cgi.as_ref().is_some_and(|job| job.paused) {
+                self.read_cgi_output(conn, token);
+            }
+            if conn.websocket.as_ref().is_some_and(|ws| ws.paused) {
+                self.websocket_output(conn);
+            }
+        }
+        self.update_interest(conn, token)
+    }
 
-                            // Only process if we have the complete request
-                            if conn.read_buffer.len() >= total_len {
-                                println!(
-                                    "DEBUG: Processing complete request with {} bytes",
-                                    total_len
-                                );
-                                let response = handle_request(
-                                    &conn.read_buffer[..total_len],
-                                    session_manager,
-                                    server_config,
-                                );
-                            conn.write_buffer = response;
-                            conn.is_writing = true;
-                                conn.read_buffer.drain(..total_len);
-                            poll.registry().reregister(
-                                &mut conn.stream,
-                                token,
-                                Interest::WRITABLE,
-                            )?;
-                            } else {
-                                println!(
-                                    "DEBUG: Waiting for more data... Need {} more bytes",
-                                    total_len - conn.read_buffer.len()
-                                );
-                                // Keep listening for more readable events
-                                poll.registry().reregister(
-                                    &mut conn.stream,
-                                    token,
-                                    Interest::READABLE,
-                                )?;
-                            }
-                        }
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                    Err(_) => {
-                        clients.remove(&token);
-                        continue;
+    // Answers every complete request in the buffer, in order (pipelining).
+    // Bytes of a following request stay in read_buffer for the next pass, and
+    // so do requests behind one that a CGI script is still answering.
+    fn process_requests(&mut self, conn: &mut Connection, token: Token) {
+        let servers = self.servers;
+        while !conn.close_after_write
+            && conn.cgi.is_none()
+            && conn.websocket.is_none()
+            && conn.event_stream.is_none()
+        {
+            // A new request starts once its head is in. The head is
+            // enough to pick the server block and the body limit.
+            if conn.pending_request.is_none() {
+                let candidates = &self.listeners[&conn.listener].servers;
+                // only the bytes that came since the last pass are searched,
+                // from 3 back in case the CRLFCRLF was split between reads
+                let from = conn
+                    .head_checked
+                    .min(conn.read_buffer.len())
+                    .saturating_sub(3);
+                let Some(header_end) = conn.read_buffer[from..]
+                    .windows(4)
+                    .position(|w| w == b"\r\n\r\n")
+                    .map(|at| from + at)
+                else {
+                    conn.head_checked = conn.read_buffer.len();
+                    if conn.read_buffer.len() > MAX_HEAD_SIZE {
+                        let server_config = &servers[candidates[0]];
+                        let response =
+                            error_response(431, "Request Header Fields Too Large", server_config);
+                        close_with(conn, response);
                     }
+                    break;
+                };
+                conn.head_checked = 0;
+                if header_end + 4 > MAX_HEAD_SIZE {
+                    let server_config = &servers[candidates[0]];
+                    let response =
+                        error_response(431, "Request Header Fields Too Large", server_config);
+                    close_with(conn, response);
+                    break;
                 }
-                if event.is_writable() && conn.is_writing {
-                    match conn.stream.write(&conn.write_buffer) {
-                        Ok(n) => {
-                            conn.write_buffer.drain(..n);
-                            if conn.write_buffer.is_empty() {
-                                conn.is_writing = false;
-                                let _ = conn.stream.shutdown(std::net::Shutdown::Both);
-                                poll.registry().deregister(&mut conn.stream)?;
-                                clients.remove(&token);
-                            }
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                        Err(_) => {
-                            clients.remove(&token);
-                            continue;
-                        }
+                let parsed = parse_request_head(&conn.read_buffer);
+                conn.read_buffer.drain(..header_end + 4);
+                let Some((head, _)) = parsed else {
+                    reject_request(conn, BodyError::Malformed, &servers[candidates[0]]);
+                    break;
+                };
+
+                // Virtual hosting: pick the server block for this Host
+                let server_index = select_server(servers, candidates, head.header("Host"));
+                conn.server_index = Some(server_index);
+                let server_config = &servers[server_index];
+                let body_limit = server_config.body_limit(head.uri_path()) as u64;
+                match start_body(&head, body_limit) {
+                    Ok(body) => {
+                        conn.pending_request = Some(PendingRequest {
+                            head,
+                            server_index,
+                            body,
+                        })
                     }
+                    Err(e) => {
+                        reject_request(conn, e, server_config);
+                        break;
+                    }
+                }
+            }
+
+            // Feed what we have of the body; it is decoded and
+            // spooled to disk as it arrives
+            let pending = conn.pending_request.as_mut().unwrap();
+            match pending.body.feed(&conn.read_buffer) {
+                Ok(used) => {
+                    conn.read_buffer.drain(..used);
+                }
+                Err(e) => {
+                    let server_config = &servers[pending.server_index];
+                    conn.pending_request = None;
+                    reject_request(conn, e, server_config);
+                    break;
+                }
+            }
+            if !pending.body.is_done() {
+                break;
+            }
+            let PendingRequest {
+                head: mut request,
+                server_index,
+                body,
+            } = conn.pending_request.take().unwrap();
+            let server_config = &servers[server_index];
+            match body.finish() {
+                Ok(body) => request.body = body,
+                Err(e) => {
+                    reject_request(conn, e, server_config);
+                    break;
                 }
             }
+            request.remote_addr = Some(conn.peer_addr);
+            request.local_addr = conn.stream.local_addr().ok();
+            request.secure = conn.stream.is_tls();
+            let request = Some(request);
+
+            conn.requests_served += 1;
+            let keep_alive = request.as_ref().is_some_and(|r| r.keep_alive())
+                && conn.requests_served < server_config.keepalive_requests();
+
+            let reply = handle_request(request, self.session_manager, server_config);
+            self.dispatch(conn, token, reply, server_index, keep_alive);
         }
-        // Timeout check: remove clients that have been idle for too long
-        let now = Instant::now();
-        let timed_out: Vec<Token> = clients
-            .iter()
-            .filter(|(_, conn)| now.duration_since(conn.last_active) > CLIENT_TIMEOUT)
-            .map(|(token, _)| *token)
-            .collect();
-        for token in timed_out {
-            if let Some(mut conn) = clients.remove(&token) {
-                println!(
-                    "DEBUG: Client {:?} timed out after {} seconds (buffer size: {} bytes)",
-                    token,
-                    now.duration_since(conn.last_active).as_secs(),
-                    conn.read_buffer.len()
-                );
-                let _ = conn.stream.shutdown(std::net::Shutdown::Both);
-                poll.registry().deregister(&mut conn.stream).ok();
+    }
+
+    // Queues a ready response, or starts reading the output of a script
+    fn dispatch(
+        &mut self,
+        conn: &mut Connection,
+        token: Token,
+        reply: Reply,
+        server_index: usize,
+        keep_alive: bool,
+    ) {
+        let server_config = &self.servers[server_index];
+        let mut cgi = match reply {
+            Reply::Ready(response) => {
+                return queue_response(conn, response, keep_alive, server_config);
+            }
+            Reply::Cgi(cgi) => cgi,
+            Reply::WebSocket(upgrade) => {
+                return self.start_websocket(conn, token, *upgrade, server_index, keep_alive);
             }
+            Reply::Events(job) => {
+                return self.start_event_stream(conn, *job, server_config, keep_alive);
+            }
+        };
+        let output_token = Token(self.next_token);
+        let stdin_token = match &cgi.backend {
+            Backend::Process(process) if process.stdin.is_some() => {
+                Some(Token(self.next_token + 1))
+            }
+            _ => None,
+        };
+        self.next_token += 2;
+        if let Err(e) = self.watch(&mut cgi, output_token, stdin_token, server_index) {
+            eprintln!("Failed to start {}: {}", cgi.name, e);
+            // a FastCGI server or upstream we can't reach is a bad gateway
+            let response = match cgi.backend {
+                Backend::FastCgi(_) | Backend::Proxy(_) => {
+                    error_response(502, "Bad Gateway", server_config)
+                }
+                Backend::Process(_) => error_response(500, "Internal Server Error", server_config),
+            };
+            self.upstream_failed(&cgi.backend);
+            self.release(cgi.backend, true);
+            return queue_response(conn, response, keep_alive, server_config);
         }
+        self.pipes.insert(output_token, token);
+        if let Some(stdin_token) = stdin_token {
+            self.pipes.insert(stdin_token, token);
+        }
+        conn.cgi = Some(CgiJob {
+            cgi,
+            output_token,
+            stdin_token,
+            server_index,
+            keep_alive,
+            head: Vec::new(),
+            chunked: None,
+            paused: false,
+            event_stream: None,
+        });
     }
-}
 
-fn handle_path(path: &str) -> io::Result<String> {
-    let mut wd = env::current_dir().unwrap();
-    let file_name = format!("{}.html", path);
-    let html_path = wd.join("html").join(file_name);
-    println!("html path {}", html_path.display());
-    fs::read_to_string(html_path)
-}
+    // Registers the pipes of a process, or connects to the FastCGI server or
+    // upstream (reusing an idle connection when there is one)
+    fn watch(
+        &mut self,
+        cgi: &mut CgiRequest,
+        output_token: Token,
+        stdin_token: Option<Token>,
+        server_index: usize,
+    ) -> io::Result<()> {
+        match &mut cgi.backend {
+            Backend::Process(process) => {
+                self.registry
+                    .register(&mut process.stdout, output_token, Interest::READABLE)?;
+                if let (Some(stdin), Some(stdin_token)) = (process.stdin.as_mut(), stdin_token) {
+                    self.registry
+                        .register(stdin, stdin_token, Interest::WRITABLE)?;
+                }
+            }
+            Backend::FastCgi(fastcgi) => {
+                let mut stream = self.fastcgi_connection(&fastcgi.address)?;
+                self.registry.register(
+                    &mut stream,
+                    output_token,
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+                fastcgi.stream = Some(stream);
+            }
+            Backend::Proxy(proxy) => {
+                let (host, addr, peer) =
+                    self.proxy_host(server_index, &proxy.target.host, proxy.client_ip)?;
+                proxy.host = host;
+                proxy.peer = peer;
+                let mut stream = self.proxy_connection(&proxy.host, addr)?;
+                self.registry.register(
+                    &mut stream,
+                    output_token,
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+                proxy.stream = Some(stream);
+            }
+        }
+        Ok(())
+    }
 
-// Helper function to find the end of a chunked transfer encoding body
-fn find_chunked_body_end(body: &[u8]) -> Option<usize> {
-    let mut i = 0;
-    while i < body.len() {
-        // Find the next CRLF
-        let crlf = match body[i..].windows(2).position(|w| w == b"\r\n") {
-            Some(pos) => i + pos,
-            None => return None, // No CRLF found, incomplete chunk
-        };
+    fn fastcgi_connection(&mut self, address: &str) -> io::Result<fastcgi::Stream> {
+        let idle = self.fastcgi_idle.entry(address.to_string()).or_default();
+        while let Some(mut stream) = idle.pop() {
+            if stream.is_alive() {
+                return Ok(stream);
+            }
+        }
+        let address = fastcgi::Address::parse(address).map_err(io::Error::other)?;
+        fastcgi::Stream::connect(&address)
+    }
 
-        let len_str = match std::str::from_utf8(&body[i..crlf]) {
-            Ok(s) => s,
-            Err(_) => return None, // Invalid UTF-8 in chunk size
+    // host:port and address for a proxy target; a named upstream picks one of
+    // its servers. A host name is looked up once and kept.
+    fn proxy_host(
+        &mut self,
+        server_index: usize,
+        name: &str,
+        client_ip: Option<std::net::IpAddr>,
+    ) -> io::Result<(String, std::net::SocketAddr, Option<PeerId>)> {
+        let Some(upstream) = self.upstreams.get_mut(&(server_index, name.to_string())) else {
+            let host = proxy::with_port(name);
+            let addr = match self.addresses.get(&host) {
+                Some(addr) => *addr,
+                None => {
+                    let addr = proxy::resolve(&host)?;
+                    self.addresses.insert(host.clone(), addr);
+                    addr
+                }
+            };
+            return Ok((host, addr, None));
         };
-
-        let chunk_size = match usize::from_str_radix(len_str.trim(), 16) {
-            Ok(size) => size,
-            Err(_) => return None, // Invalid chunk size
+        let (index, host, addr) = upstream
+            .select(client_ip)
+            .ok_or_else(|| io::Error::other(format!("upstream {} has no server up", name)))?;
+        let peer = PeerId {
+            server: server_index,
+            upstream: name.to_string(),
+            index,
         };
+        Ok((host, addr, Some(peer)))
+    }
 
-        if chunk_size == 0 {
-            // Found the final "0\r\n\r\n" chunk
-            return Some(i + 5); // Include the final CRLF
+    fn proxy_connection(
+        &mut self,
+        host: &str,
+        addr: std::net::SocketAddr,
+    ) -> io::Result<mio::net::TcpStream> {
+        let idle = self.proxy_idle.entry(host.to_string()).or_default();
+        while let Some(stream) = idle.pop() {
+            // an idle upstream connection has nothing to read until it is closed
+            let mut byte = [0; 1];
+            if matches!(stream.peek(&mut byte), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+            {
+                return Ok(stream);
+            }
         }
+        mio::net::TcpStream::connect(addr)
+    }
 
-        i = crlf + 2; // Skip CRLF
-        if i + chunk_size > body.len() {
-            return None; // Chunk size exceeds remaining data
+    // A script's stdin took more of the body, or its stdout has output
+    fn pipe_event(&mut self, conn: &mut Connection, client: Token, pipe: Token) -> bool {
+        if conn.websocket.is_some() {
+            self.websocket_output(conn);
+            return self.update_interest(conn, client);
         }
+        let Some(job) = conn.cgi.as_mut() else {
+            return true;
+        };
+        if job.stdin_token == Some(pipe)
+            && let Backend::Process(process) = &mut job.cgi.backend
+        {
+            if process.write_stdin() {
+                // closing stdin is how the script sees the end of the body
+                if let Some(mut stdin) = process.stdin.take() {
+                    let _ = self.registry.deregister(&mut stdin);
+                }
+                self.pipes.remove(&pipe);
+                job.stdin_token = None;
+            }
+        } else {
+            self.read_cgi_output(conn, client);
+        }
+        self.update_interest(conn, client)
+    }
+
+    // Moves what the script wrote to the client, until the pipe is empty or
+    // enough is queued
+    fn read_cgi_output(&mut self, conn: &mut Connection, client: Token) {
+        let mut buf = [0; 16384];
+        loop {
+            let Some(job) = conn.cgi.as_mut() else {
+                return;
+            };
+            let queued: u64 = conn.write_queue.iter().map(|piece| piece.len()).sum();
+            job.paused = queued > CGI_MAX_QUEUED;
+            if job.paused {
+                // time spent waiting on the client doesn't count against the script
+                job.cgi.touch();
+                return;
+            }
+            match job.cgi.read_output(&mut buf) {
+                Ok(0) => return self.finish_cgi(conn, client),
+                Ok(n) => {
+                    job.cgi.touch();
+                    self.cgi_output(conn, client, &buf[..n]);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!("Failed to read CGI output: {}", e);
+                    return self.fail_cgi(conn, client);
+                }
+            }
+        }
+    }
+
+    // Output of the script: header block first, then body bytes that are
+    // sent as they come
+    fn cgi_output(&mut self, conn: &mut Connection, client: Token, data: &[u8]) {
+        let job = conn.cgi.as_mut().unwrap();
+        conn.last_active = Instant::now();
+        if let Some(chunked) = job.chunked {
+            if let Some(line_end) = job.event_stream.as_mut() {
+                *line_end = data.ends_with(b"\n");
+            }
+            conn.write_queue.push_back(body_piece(data, chunked).into());
+            return;
+        }
+        job.head.extend_from_slice(data);
+        if cgi::header_block_end(&job.head).is_some() {
+            let job = conn.cgi.take().unwrap();
+            self.cgi_head(conn, client, job, false);
+        } else if job.head.len() > CGI_MAX_HEADER_BLOCK {
+            let job = conn.cgi.take().unwrap();
+            eprintln!("CGI header block of {} is too long", job.cgi.name);
+            let (server_index, keep_alive) = (job.server_index, job.keep_alive);
+            self.end_cgi(job, true);
+            let server_config = &self.servers[server_index];
+            let response = error_response(500, "Internal Server Error", server_config);
+            queue_response(conn, response, keep_alive, server_config);
+        }
+    }
+
+    // The header block is in (or the script exited): send the head and go on
+    // streaming, answer with the whole response, or follow a local redirect
+    fn cgi_head(&mut self, conn: &mut Connection, client: Token, mut job: CgiJob, exited: bool) {
+        let servers = self.servers;
+        let server_config = &servers[job.server_index];
+        let keep_alive = job.keep_alive;
+        match cgi::parse_cgi_output(&job.head) {
+            Ok(CgiOutput::Document(mut response)) => {
+                if let Some(cookie) = job.cgi.set_cookie.take() {
+                    response.headers.append("Set-Cookie", cookie);
+                }
+                // all of the body is here (or there is none), it goes out as is
+                if exited || matches!(response.status_code, 204 | 304) {
+                    self.end_cgi(job, false);
+                    return queue_response(conn, response, keep_alive, server_config);
+                }
+                // the length is unknown: chunked for HTTP/1.1, else the body
+                // ends when the connection closes
+                let chunked = job.cgi.chunked_ok();
+                let body = std::mem::replace(&mut response.body, Vec::new().into());
+                if chunked {
+                    response
+                        .headers
+                        .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+                } else {
+                    // without chunks there is nowhere to put trailers
+                    response.headers.remove("Trailer");
+                }
+                // events go out as they come, the script may stay quiet for long
+                if sse::is_event_stream(&response.headers) {
+                    if !response.headers.contains_key("Cache-Control") {
+                        response.headers.insert("Cache-Control", "no-cache");
+                    }
+                    job.event_stream = Some(true);
+                }
+                connection_headers(conn, &mut response, keep_alive && chunked, server_config);
+                conn.write_queue.push_back(response_head(&response).into());
+                job.head.clear();
+                job.chunked = Some(chunked);
+                conn.cgi = Some(job);
+                // body bytes that came with the header block
+                if let ResponseBody::Bytes(data) = body
+                    && !data.is_empty()
+                {
+                    self.cgi_output(conn, client, &data);
+                }
+            }
+            Ok(CgiOutput::LocalRedirect(location)) => {
+                let request = job.cgi.redirect_request(location);
+                let local_redirects = job.cgi.local_redirects;
+                let set_cookie = job.cgi.set_cookie.take();
+                let server_index = job.server_index;
+                // the script's own output is not needed any more
+                self.end_cgi(job, true);
+                if local_redirects >= MAX_LOCAL_REDIRECTS {
+                    eprintln!("Too many CGI local redirects, last to '{}'", request.path);
+                    let response = error_response(500, "Internal Server Error", server_config);
+                    return queue_response(conn, response, keep_alive, server_config);
+                }
+                let reply = route_request(&request, server_config, local_redirects + 1);
+                let reply = finish_reply(&request, reply, set_cookie, server_config);
+                self.dispatch(conn, client, reply, server_index, keep_alive);
+            }
+            Err(e) => {
+                eprintln!("Invalid CGI response from {}: {}", job.cgi.name, e);
+                self.end_cgi(job, true);
+                let response = error_response(500, "Internal Server Error", server_config);
+                queue_response(conn, response, keep_alive, server_config);
+            }
+        }
+    }
+
+    // The script closed its stdout: end the body, then go on with the
+    // requests pipelined behind it
+    fn finish_cgi(&mut self, conn: &mut Connection, client: Token) {
+        let Some(job) = conn.cgi.take() else {
+            return;
+        };
+        match job.chunked {
+            Some(chunked) => {
+                if chunked {
+                    let end = last_chunk(&job.cgi.trailers());
+                    conn.write_queue.push_back(end.into());
+                }
+                self.end_cgi(job, false);
+            }
+            None => self.cgi_head(conn, client, job, true),
+        }
+        self.process_requests(conn, client);
+    }
+
+    // A script whose output broke off, or a FastCGI server that failed: a
+    // 502 if nothing was sent yet, else the response is cut short
+    fn fail_cgi(&mut self, conn: &mut Connection, client: Token) {
+        let Some(job) = conn.cgi.take() else {
+            return;
+        };
+        let (server_index, keep_alive, head_sent) =
+            (job.server_index, job.keep_alive, job.chunked.is_some());
+        self.upstream_failed(&job.cgi.backend);
+        self.end_cgi(job, true);
+        if head_sent {
+            // closing tells the client the body is incomplete
+            conn.close_after_write = true;
+        } else {
+            let server_config = &self.servers[server_index];
+            let response = error_response(502, "Bad Gateway", server_config);
+            queue_response(conn, response, keep_alive, server_config);
+            self.process_requests(conn, client);
+        }
+    }
+
+    fn end_cgi(&mut self, job: CgiJob, kill: bool) {
+        self.pipes.remove(&job.output_token);
+        if let Some(stdin_token) = job.stdin_token {
+            self.pipes.remove(&stdin_token);
+        }
+        self.release(job.cgi.backend, kill);
+    }
+
+    // Stops watching a script's pipes or connection. A process is killed when
+    // its output is not wanted; a FastCGI connection whose request ended
+    // cleanly is kept for the next request.
+    fn release(&mut self, backend: Backend, kill: bool) {
+        match backend {
+            Backend::Process(mut process) => {
+                let _ = self.registry.deregister(&mut process.stdout);
+                if let Some(mut stdin) = process.stdin.take() {
+                    let _ = self.registry.deregister(&mut stdin);
+                }
+                if kill {
+                    process.kill();
+                }
+                self.exited.push(process.child);
+            }
+            Backend::FastCgi(mut fastcgi) => {
+                if let Some(mut stream) = fastcgi.stream.take() {
+                    let _ = self.registry.deregister(&mut stream);
+                    let idle = self
+                        .fastcgi_idle
+                        .entry(fastcgi.address.clone())
+                        .or_default();
+                    if !kill && fastcgi.reusable() && idle.len() < FASTCGI_MAX_IDLE {
+                        idle.push(stream);
+                    }
+                }
+            }
+            Backend::Proxy(mut proxy) => {
+                if let Some(peer) = &proxy.peer {
+                    self.peer_released(peer, !kill);
+                }
+                if let Some(mut stream) = proxy.stream.take() {
+                    let _ = self.registry.deregister(&mut stream);
+                    let idle = self.proxy_idle.entry(proxy.host.clone()).or_default();
+                    if !kill && proxy.reusable() && idle.len() < PROXY_MAX_IDLE {
+                        idle.push(stream);
+                    }
+                }
+            }
+        }
+    }
+
+    // Passive health check: a proxied request that failed counts against its
+    // upstream server
+    fn upstream_failed(&mut self, backend: &Backend) {
+        if let Backend::Proxy(proxy) = backend
+            && let Some(peer) = &proxy.peer
+        {
+            self.peer_failed(peer);
+        }
+    }
+
+    fn peer_failed(&mut self, peer: &PeerId) {
+        if let Some(upstream) = self
+            .upstreams
+            .get_mut(&(peer.server, peer.upstream.clone()))
+        {
+            upstream.failed(peer.index);
+        }
+    }
+
+    // A request to an upstream server is over; `ok` when it ended cleanly
+    fn peer_released(&mut self, peer: &PeerId, ok: bool) {
+        if let Some(upstream) = self
+            .upstreams
+            .get_mut(&(peer.server, peer.upstream.clone()))
+        {
+            upstream.release(peer.index);
+            if ok {
+                upstream.succeeded(peer.index);
+            }
+        }
+    }
+
+    // Switches the connection to WebSocket and starts the route's handler. A
+    // proxied one gets its 101 only once the upstream accepted the handshake.
+    fn start_websocket(
+        &mut self,
+        conn: &mut Connection,
+        token: Token,
+        upgrade: websocket::Upgrade,
+        server_index: usize,
+        keep_alive: bool,
+    ) {
+        let servers = self.servers;
+        let server_config = &servers[server_index];
+        let websocket::Upgrade {
+            accept,
+            set_cookie,
+            max_message,
+            handler,
+        } = upgrade;
+        let mut pending = None;
+        let (handler, tokens) = match handler {
+            Start::Echo => (Handler::Echo, Vec::new()),
+            Start::Process(mut process) => {
+                let output_token = Token(self.next_token);
+                let stdin_token = Token(self.next_token + 1);
+                self.next_token += 2;
+                let registered = self
+                    .registry
+                    .register(&mut process.stdout, output_token, Interest::READABLE)
+                    .and_then(|_| match process.stdin.as_mut() {
+                        Some(stdin) => {
+                            self.registry
+                                .register(stdin, stdin_token, Interest::WRITABLE)
+                        }
+                        None => Ok(()),
+                    });
+                let handler = Handler::Process(Box::new(ProcessHandler::new(process, max_message)));
+                if let Err(e) = registered {
+                    eprintln!("Failed to watch the WebSocket program: {}", e);
+                    self.end_handler(handler, &[], false);
+                    let response = error_response(500, "Internal Server Error", server_config);
+                    return queue_response(conn, response, keep_alive, server_config);
+                }
+                (handler, vec![output_token, stdin_token])
+            }
+            Start::Proxy {
+                target,
+                head,
+                client_ip,
+            } => {
+                let upstream_token = Token(self.next_token);
+                self.next_token += 1;
+                // a fresh connection: once upgraded it can't go back to a pool
+                let connected = self
+                    .proxy_host(server_index, &target.host, client_ip)
+                    .and_then(|(host, addr, peer)| {
+                        let connect = mio::net::TcpStream::connect(addr).and_then(|mut stream| {
+                            self.registry.register(
+                                &mut stream,
+                                upstream_token,
+                                Interest::READABLE | Interest::WRITABLE,
+                            )?;
+                            Ok(stream)
+                        });
+                        match connect {
+                            Ok(stream) => Ok((host, peer, stream)),
+                            Err(e) => {
+                                if let Some(peer) = &peer {
+                                    self.peer_failed(peer);
+                                    self.peer_released(peer, false);
+                                }
+                                Err(e)
+                            }
+                        }
+                    });
+                match connected {
+                    Ok((host, peer, stream)) => {
+                        pending = Some((accept.clone(), set_cookie.clone()));
+                        let proxy = ProxyHandler::new(&head, host, peer, stream, max_message);
+                        (Handler::Proxy(Box::new(proxy)), vec![upstream_token])
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to reach WebSocket upstream {}: {}", target.host, e);
+                        let response = error_response(502, "Bad Gateway", server_config);
+                        return queue_response(conn, response, keep_alive, server_config);
+                    }
+                }
+            }
+        };
+        if pending.is_none() {
+            let head = websocket::switching_protocols(&accept, None, set_cookie.as_deref());
+            conn.write_queue.push_back(head.into());
+        }
+        for pipe in &tokens {
+            self.pipes.insert(*pipe, token);
+        }
+        conn.websocket = Some(Box::new(WebSocket {
+            decoder: websocket::Decoder::new(true, max_message),
+            handler,
+            tokens,
+            pending,
+            server_index,
+            closing: None,
+            ping_sent: None,
+            paused: false,
+        }));
+        // frames the client sent right behind the handshake
+        self.websocket_input(conn);
+    }
+
+    // Handles the frames the client sent, as they complete
+    fn websocket_input(&mut self, conn: &mut Connection) {
+        loop {
+            let Some(ws) = conn.websocket.as_mut() else {
+                return;
+            };
+            // until the upstream accepted, the frames wait in read_buffer
+            if ws.pending.is_some() {
+                return;
+            }
+            let incoming = match ws.decoder.next(&mut conn.read_buffer) {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => return,
+                Err(code) => {
+                    return self.fail_websocket(conn, code);
+                }
+            };
+            match incoming {
+                Incoming::Message(opcode, data) => match &mut ws.handler {
+                    Handler::Echo => conn
+                        .write_queue
+                        .push_back(websocket::frame(opcode, &data, false).into()),
+                    Handler::Process(process) => process.send(&data),
+                    Handler::Proxy(proxy) => {
+                        if let Err(e) = proxy.send(opcode, &data) {
+                            eprintln!("WebSocket upstream {} failed: {}", proxy.host, e);
+                            return self.close_websocket(conn, upstream_gone(), true);
+                        }
+                    }
+                    // the close handshake is under way, messages are dropped
+                    Handler::Closed => {}
+                },
+                Incoming::Ping(data) => conn
+                    .write_queue
+                    .push_back(websocket::frame(websocket::PONG, &data, false).into()),
+                Incoming::Pong => {}
+                Incoming::Close(code, payload) => {
+                    let mut ws = conn.websocket.take().unwrap();
+                    if ws.closing.is_none() {
+                        // answered with the same code
+                        let echo = match code {
+                            Some(code) => websocket::close_frame(code, "", false),
+                            None => websocket::frame(websocket::CLOSE, &[], false),
+                        };
+                        conn.write_queue.push_back(echo.into());
+                    }
+                    if let Handler::Proxy(proxy) = &mut ws.handler {
+                        let _ = proxy.send_close(&payload);
+                    }
+                    self.end_websocket(*ws, false);
+                    conn.close_after_write = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Output of the handler, sent to the client until enough is queued
+    fn websocket_output(&mut self, conn: &mut Connection) {
+        let Some(ws) = conn.websocket.as_mut() else {
+            return;
+        };
+        let mut queued: u64 = conn.write_queue.iter().map(|piece| piece.len()).sum();
+        match &mut ws.handler {
+            Handler::Process(process) => {
+                process.process.write_stdin();
+                loop {
+                    ws.paused = queued > CGI_MAX_QUEUED;
+                    if ws.paused {
+                        return;
+                    }
+                    match process.read_line() {
+                        // a line of text is a text message, anything else binary
+                        Ok(Some(line)) => {
+                            let opcode = match std::str::from_utf8(&line) {
+                                Ok(_) => websocket::TEXT,
+                                Err(_) => websocket::BINARY,
+                            };
+                            let frame = websocket::frame(opcode, &line, false);
+                            queued += frame.len() as u64;
+                            conn.write_queue.push_back(frame.into());
+                        }
+                        Ok(None) => return,
+                        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                            let close = websocket::close_frame(websocket::NORMAL, "", false);
+                            return self.close_websocket(conn, close, false);
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to read the WebSocket program: {}", e);
+                            let close =
+                                websocket::close_frame(websocket::INTERNAL_ERROR, "", false);
+                            return self.close_websocket(conn, close, false);
+                        }
+                    }
+                }
+            }
+            Handler::Proxy(proxy) => {
+                let eof = match proxy.flush().and_then(|_| proxy.read()) {
+                    Ok(eof) => eof,
+                    Err(e) => {
+                        eprintln!("WebSocket upstream {} failed: {}", proxy.host, e);
+                        return self.upstream_lost(conn);
+                    }
+                };
+                let accepted = !proxy.is_open();
+                if accepted {
+                    match proxy.handshake() {
+                        Ok(Some(protocol)) => {
+                            let (accept, cookie) = ws.pending.take().unwrap_or_default();
+                            let head = websocket::switching_protocols(
+                                &accept,
+                                protocol.as_deref(),
+                                cookie.as_deref(),
+                            );
+                            queued += head.len() as u64;
+                            conn.write_queue.push_back(head.into());
+                        }
+                        Ok(None) if !eof => return,
+                        Ok(None) => {
+                            return self.upstream_lost(conn);
+                        }
+                        Err(e) => {
+                            eprintln!("WebSocket upstream {} refused: {}", proxy.host, e);
+                            return self.upstream_lost(conn);
+                        }
+                    }
+                }
+                loop {
+                    ws.paused = queued > CGI_MAX_QUEUED;
+                    if ws.paused {
+                        return;
+                    }
+                    match proxy.next() {
+                        Ok(Some(Incoming::Message(opcode, data))) => {
+                            let frame = websocket::frame(opcode, &data, false);
+                            queued += frame.len() as u64;
+                            conn.write_queue.push_back(frame.into());
+                        }
+                        Ok(Some(Incoming::Ping(data))) => {
+                            if proxy.send(websocket::PONG, &data).is_err() {
+                                return self.upstream_lost(conn);
+                            }
+                        }
+                        Ok(Some(Incoming::Pong)) => {}
+                        // relayed to the client, whose answer ends it
+                        Ok(Some(Incoming::Close(_, payload))) => {
+                            let _ = proxy.send_close(&payload[..payload.len().min(2)]);
+                            let close = websocket::frame(websocket::CLOSE, &payload, false);
+                            return self.close_websocket(conn, close, false);
+                        }
+                        Ok(None) => break,
+                        Err(code) => {
+                            eprintln!(
+                                "WebSocket upstream {} broke the protocol ({})",
+                                proxy.host, code
+                            );
+                            return self.close_websocket(conn, upstream_gone(), true);
+                        }
+                    }
+                }
+                if eof {
+                    let close = websocket::close_frame(websocket::GOING_AWAY, "", false);
+                    return self.close_websocket(conn, close, false);
+                }
+                // the client may have sent frames while the upstream was accepting
+                if accepted {
+                    self.websocket_input(conn);
+                }
+            }
+            Handler::Echo | Handler::Closed => {}
+        }
+    }
+
+    // The upstream failed: a 502 while the client still waits for its 101,
+    // else a close frame
+    fn upstream_lost(&mut self, conn: &mut Connection) {
+        let Some(ws) = conn.websocket.as_ref() else {
+            return;
+        };
+        if ws.pending.is_none() {
+            return self.close_websocket(conn, upstream_gone(), true);
+        }
+        let ws = conn.websocket.take().unwrap();
+        let server_config = &self.servers[ws.server_index];
+        self.end_websocket(*ws, true);
+        let response = error_response(502, "Bad Gateway", server_config);
+        queue_response(conn, response, false, server_config);
+    }
+
+    // The handler is done: `close` goes to the client, whose close frame ends
+    // the connection
+    fn close_websocket(&mut self, conn: &mut Connection, close: Vec<u8>, failed: bool) {
+        let Some(ws) = conn.websocket.as_mut() else {
+            return;
+        };
+        let handler = std::mem::replace(&mut ws.handler, Handler::Closed);
+        let tokens = std::mem::take(&mut ws.tokens);
+        if ws.closing.is_none() {
+            ws.closing = Some(Instant::now());
+            conn.write_queue.push_back(close.into());
+        }
+        self.end_handler(handler, &tokens, failed);
+    }
+
+    // The client broke the protocol: a close frame with `code`, and the
+    // connection closes without waiting for the answer
+    fn fail_websocket(&mut self, conn: &mut Connection, code: u16) {
+        if let Some(ws) = conn.websocket.take() {
+            if ws.closing.is_none() {
+                let close = websocket::close_frame(code, "", false);
+                conn.write_queue.push_back(close.into());
+            }
+            self.end_websocket(*ws, false);
+        }
+        conn.close_after_write = true;
+    }
+
+    fn end_websocket(&mut self, ws: WebSocket, failed: bool) {
+        self.end_handler(ws.handler, &ws.tokens, failed);
+    }
+
+    // Stops the handler: a program is killed, an upstream connection closed
+    // and counted as failed when `failed`
+    fn end_handler(&mut self, handler: Handler, tokens: &[Token], failed: bool) {
+        for pipe in tokens {
+            self.pipes.remove(pipe);
+        }
+        match handler {
+            Handler::Process(process) => {
+                let mut process = process.process;
+                let _ = self.registry.deregister(&mut process.stdout);
+                if let Some(mut stdin) = process.stdin.take() {
+                    let _ = self.registry.deregister(&mut stdin);
+                }
+                process.kill();
+                self.exited.push(process.child);
+            }
+            Handler::Proxy(mut proxy) => {
+                let _ = self.registry.deregister(&mut proxy.stream);
+                if let Some(peer) = &proxy.peer {
+                    if failed {
+                        self.peer_failed(peer);
+                    }
+                    self.peer_released(peer, !failed);
+                }
+            }
+            Handler::Echo | Handler::Closed => {}
+        }
+    }
+
+    // Waits for the socket to take output while there is some, else for the
+    // next request. False once the connection is done.
+    fn update_interest(&mut self, conn: &mut Connection, token: Token) -> bool {
+        // TLS also comes back for records the socket didn't take and for data
+        // it decrypted but wasn't read yet
+        conn.is_writing = !conn.write_queue.is_empty() || conn.stream.has_pending();
+        if !conn.is_writing
+            && conn.close_after_write
+            && conn.cgi.is_none()
+            && conn.event_stream.is_none()
+        {
+            return false;
+        }
+        let interest = if conn.is_writing {
+            Interest::WRITABLE
+        } else {
+            // keep-alive: wait for the next request on this socket
+            Interest::READABLE
+        };
+        self.registry
+            .reregister(&mut conn.stream, token, interest)
+            .is_ok()
+    }
+
+    fn close(&mut self, mut conn: Connection) {
+        // a script still answering a gone client is killed
+        if let Some(job) = conn.cgi.take() {
+            self.end_cgi(job, true);
+        }
+        if let Some(ws) = conn.websocket.take() {
+            self.end_websocket(*ws, false);
+        }
+        let _ = conn.stream.shutdown(std::net::Shutdown::Both);
+        let _ = self.registry.deregister(&mut conn.stream);
+    }
+
+    // Kills scripts that sent nothing for their route's cgi_timeout, and closes
+    // clients that have been idle for too long. A half-received request gets
+    // CLIENT_TIMEOUT, an idle keep-alive connection gets the keepalive_timeout
+    // of its listener's default server.
+    fn check_timeouts(&mut self, clients: &mut HashMap<Token, Connection>) {
+        let expired: Vec<Token> = clients
+            .iter()
+            .filter(|(_, conn)| {
+                conn.cgi.as_ref().is_some_and(|job| {
+                    !job.paused && job.event_stream.is_none() && job.cgi.timed_out()
+                })
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            let mut conn = clients.remove(&token).unwrap();
+            let job = conn.cgi.take().unwrap();
+            eprintln!("CGI script {} timed out, killing it", job.cgi.name);
+            let (server_index, keep_alive, head_sent) =
+                (job.server_index, job.keep_alive, job.chunked.is_some());
+            self.upstream_failed(&job.cgi.backend);
+            self.end_cgi(job, true);
+            if head_sent {
+                // the body is cut short; closing tells the client so
+                conn.close_after_write = true;
+            } else {
+                let server_config = &self.servers[server_index];
+                let response = error_response(504, "Gateway Timeout", server_config);
+                queue_response(&mut conn, response, keep_alive, server_config);
+                self.process_requests(&mut conn, token);
+            }
+            if self.update_interest(&mut conn, token) {
+                clients.insert(token, conn);
+            } else {
+                self.close(conn);
+            }
+        }
+
+        let now = Instant::now();
+        let timed_out: Vec<Token> = clients
+            .iter()
+            .filter(|(_, conn)| {
+                let limit = if conn.read_buffer.is_empty() && !conn.is_writing {
+                    let server_index = conn
+                        .server_index
+                        .unwrap_or(self.listeners[&conn.listener].servers[0]);
+                    self.servers[server_index].keepalive_timeout()
+                } else {
+                    CLIENT_TIMEOUT
+                };
+                // a running script has its own timeout, unless it waits on this client
+                let script_running = conn.cgi.as_ref().is_some_and(|job| !job.paused);
+                !script_running
+                    && conn.websocket.is_none()
+                    && conn.event_stream.is_none()
+                    && now.duration_since(conn.last_active) > limit
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in timed_out {
+            if let Some(conn) = clients.remove(&token) {
+                self.close(conn);
+            }
+        }
+    }
+
+    // WebSocket keepalive: a ping once the connection has been quiet for
+    // WS_PING_INTERVAL, and the connection is closed when the ping or our close
+    // frame goes unanswered. An upstream that doesn't accept gets a 504.
+    fn websocket_timeouts(&mut self, clients: &mut HashMap<Token, Connection>) {
+        let now = Instant::now();
+        let websockets: Vec<Token> = clients
+            .iter()
+            .filter(|(_, conn)| conn.websocket.is_some())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in websockets {
+            let mut conn = clients.remove(&token).unwrap();
+            let ws = conn.websocket.as_mut().unwrap();
+            let quiet = now.duration_since(conn.last_active);
+            if ws.pending.is_some() {
+                if quiet > CLIENT_TIMEOUT {
+                    eprintln!("WebSocket upstream of {:?} timed out", token);
+                    let ws = conn.websocket.take().unwrap();
+                    let server_config = &self.servers[ws.server_index];
+                    self.end_websocket(*ws, true);
+                    let response = error_response(504, "Gateway Timeout", server_config);
+                    queue_response(&mut conn, response, false, server_config);
+                }
+            } else if ws
+                .closing
+                .is_some_and(|at| now.duration_since(at) > WS_CLOSE_TIMEOUT)
+                || ws
+                    .ping_sent
+                    .is_some_and(|at| now.duration_since(at) > WS_PING_INTERVAL)
+            {
+                self.close(conn);
+                continue;
+            } else if ws.ping_sent.is_none() && ws.closing.is_none() && quiet > WS_PING_INTERVAL {
+                ws.ping_sent = Some(now);
+                let ping = websocket::frame(websocket::PING, b"", false);
+                conn.write_queue.push_back(ping.into());
+            } else {
+                clients.insert(token, conn);
+                continue;
+            }
+            if self.update_interest(&mut conn, token) {
+                clients.insert(token, conn);
+            } else {
+                self.close(conn);
+            }
+        }
+    }
+
+    // Starts streaming a file's new lines as events; the events since the
+    // client's Last-Event-ID go out right away
+    fn start_event_stream(
+        &mut self,
+        conn: &mut Connection,
+        mut job: EventStreamJob,
+        server_config: &ServerConfig,
+        keep_alive: bool,
+    ) {
+        let mut response = Response {
+            status_code: 200,
+            reason_phrase: "OK".to_string(),
+            headers: Headers::new(),
+            body: Vec::new().into(),
+        };
+        response
+            .headers
+            .insert("Content-Type", "text/event-stream; charset=utf-8");
+        response.headers.insert("Cache-Control", "no-cache");
+        if job.chunked {
+            response.headers.insert("Transfer-Encoding", "chunked");
+        }
+        if let Some(cookie) = job.set_cookie.take() {
+            response.headers.append("Set-Cookie", cookie);
+        }
+        connection_headers(
+            conn,
+            &mut response,
+            keep_alive && job.chunked,
+            server_config,
+        );
+        conn.write_queue.push_back(response_head(&response).into());
+        conn.event_stream = Some(job);
+        poll_events(conn);
+    }
+
+    // Sends what the `event_stream` files gained, and a heartbeat on event
+    // streams that have been quiet for SSE_HEARTBEAT
+    fn event_streams(&mut self, clients: &mut HashMap<Token, Connection>) {
+        let now = Instant::now();
+        let streams: Vec<Token> = clients
+            .iter()
+            .filter(|(_, conn)| {
+                conn.event_stream.is_some()
+                    || conn
+                        .cgi
+                        .as_ref()
+                        .is_some_and(|job| job.event_stream.is_some())
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in streams {
+            let mut conn = clients.remove(&token).unwrap();
+            let (queued, streaming) = (conn.write_queue.len(), conn.event_stream.is_some());
+            if conn
+                .event_stream
+                .as_ref()
+                .is_some_and(|job| now >= job.next_poll)
+            {
+                poll_events(&mut conn);
+            }
+            if conn.write_queue.is_empty() && now.duration_since(conn.last_active) > SSE_HEARTBEAT {
+                // a script's heartbeat must not land inside one of its lines
+                let chunked = match (&conn.event_stream, &conn.cgi) {
+                    (Some(job), _) => Some(job.chunked),
+                    (None, Some(job)) if job.event_stream == Some(true) => job.chunked,
+                    _ => None,
+                };
+                if let Some(chunked) = chunked {
+                    conn.write_queue
+                        .push_back(body_piece(sse::HEARTBEAT, chunked).into());
+                    conn.last_active = now;
+                }
+            }
+            let unchanged =
+                conn.write_queue.len() == queued && conn.event_stream.is_some() == streaming;
+            if unchanged || self.update_interest(&mut conn, token) {
+                clients.insert(token, conn);
+            } else {
+                self.close(conn);
+            }
+        }
+    }
+
+    // Starts the probes that are due and fails the ones past their timeout
+    fn health_checks(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Token> = self
+            .probes
+            .iter()
+            .filter(|(_, (_, probe))| probe.expired(now))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            self.end_probe(token, false);
+        }
+
+        let mut started = Vec::new();
+        for ((server, name), upstream) in self.upstreams.iter_mut() {
+            for (index, probe) in upstream.due_probes(now) {
+                let peer = PeerId {
+                    server: *server,
+                    upstream: name.clone(),
+                    index,
+                };
+                started.push((peer, probe));
+            }
+        }
+        for (peer, mut probe) in started {
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let registered = self.registry.register(
+                &mut probe.stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            );
+            match registered {
+                Ok(()) => {
+                    self.probes.insert(token, (peer, probe));
+                }
+                Err(_) => self.report_probe(&peer, false),
+            }
+        }
+    }
+
+    fn probe_event(&mut self, token: Token) {
+        let Some((_, probe)) = self.probes.get_mut(&token) else {
+            return;
+        };
+        if let Some(healthy) = probe.event() {
+            self.end_probe(token, healthy);
+        }
+    }
+
+    fn end_probe(&mut self, token: Token, healthy: bool) {
+        if let Some((peer, mut probe)) = self.probes.remove(&token) {
+            let _ = self.registry.deregister(&mut probe.stream);
+            self.report_probe(&peer, healthy);
+        }
+    }
+
+    fn report_probe(&mut self, peer: &PeerId, healthy: bool) {
+        if let Some(upstream) = self
+            .upstreams
+            .get_mut(&(peer.server, peer.upstream.clone()))
+        {
+            upstream.probed(peer.index, healthy);
+        }
+    }
+}
+
+// Connection: keep-alive with the Keep-Alive limits, or Connection: close
+// and the connection is closed once the response is out
+fn connection_headers(
+    conn: &mut Connection,
+    response: &mut Response,
+    keep_alive: bool,
+    server_config: &ServerConfig,
+) {
+    if keep_alive {
+        response
+            .headers
+            .insert("Connection".to_string(), "keep-alive".to_string());
+        response.headers.insert(
+            "Keep-Alive".to_string(),
+            format!(
+                "timeout={}, max={}",
+                server_config.keepalive_timeout().as_secs(),
+                server_config.keepalive_requests() - conn.requests_served
+            ),
+        );
+    } else {
+        response
+            .headers
+            .insert("Connection".to_string(), "close".to_string());
+        conn.close_after_write = true;
+        conn.read_buffer.clear();
+    }
+}
+
+fn queue_response(
+    conn: &mut Connection,
+    mut response: Response,
+    keep_alive: bool,
+    server_config: &ServerConfig,
+) {
+    // a body that ends with the connection can't leave it open
+    let keep_alive = keep_alive && !response.body.is_close_delimited();
+    connection_headers(conn, &mut response, keep_alive, server_config);
+    conn.write_queue.extend(build_response(response));
+}
+
+// Events an `event_stream` file gained since the last look, unless the client
+// is behind; a file that can't be read any more ends the stream
+fn poll_events(conn: &mut Connection) {
+    let Some(job) = conn.event_stream.as_mut() else {
+        return;
+    };
+    job.next_poll = Instant::now() + SSE_POLL_INTERVAL;
+    let queued: u64 = conn.write_queue.iter().map(|piece| piece.len()).sum();
+    if queued > CGI_MAX_QUEUED {
+        return;
+    }
+    match job.events.poll() {
+        Ok(events) if events.is_empty() => {}
+        Ok(events) => {
+            conn.write_queue
+                .push_back(body_piece(&events, job.chunked).into());
+            conn.last_active = Instant::now();
+        }
+        Err(e) => {
+            eprintln!("Event stream ended: {}", e);
+            if job.chunked {
+                let end = last_chunk(&Headers::new());
+                conn.write_queue.push_back(end.into());
+            }
+            conn.event_stream = None;
+            conn.close_after_write = true;
+        }
+    }
+}
+
+// Body bytes of a response of unknown length: a chunk, or the bytes as they
+// are when the connection's end marks the end of the body
+fn body_piece(data: &[u8], chunked: bool) -> Vec<u8> {
+    if chunked { chunk(data) } else { data.to_vec() }
+}
+
+// Close frame for a WebSocket whose upstream failed
+fn upstream_gone() -> Vec<u8> {
+    websocket::close_frame(websocket::BAD_GATEWAY, "", false)
+}
+
+// Sets up the body decoder from Content-Length / Transfer-Encoding
+fn start_body(head: &Request, body_limit: u64) -> Result<BodyReader, BodyError> {
+    let chunked = head
+        .header("Transfer-Encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    let content_length = match head.header("Content-Length") {
+        Some(value) if !chunked => value.trim().parse().map_err(|_| BodyError::Malformed)?,
+        _ => 0,
+    };
+    BodyReader::new(chunked, content_length, body_limit)
+}
+
+// Answers a request whose body can't be read (413 over the limit, 400 bad
+// framing) and closes the connection, since the rest of the body is unread
+fn reject_request(conn: &mut Connection, error: BodyError, server_config: &ServerConfig) {
+    let response = match error {
+        BodyError::TooLarge => error_response(413, "Payload Too Large", server_config),
+        BodyError::Malformed => error_response(400, "Bad Request", server_config),
+        BodyError::Io(e) => {
+            eprintln!("Failed to store request body: {}", e);
+            error_response(500, "Internal Server Error", server_config)
+        }
+    };
+    close_with(conn, response);
+}
+
+// Queues a last response and drops whatever else the client sent
+fn close_with(conn: &mut Connection, mut response: Response) {
+    response
+        .headers
+        .insert("Connection".to_string(), "close".to_string());
+    conn.write_queue.extend(build_response(response));
+    conn.close_after_write = true;
+    conn.read_buffer.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn servers(names: &[&str]) -> Vec<ServerConfig> {
+        names
+            .iter()
+            .map(|name| ServerConfig {
+                server_name: name.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // runs the event loop on a free port of 127.0.0.1 for the blocks given
+    fn serve(servers: Vec<ServerConfig>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listeners = HashMap::from([(
+            Token(0),
+            ServerListener {
+                listener,
+                servers: (0..servers.len()).collect(),
+                tls: None,
+            },
+        )]);
+        let servers: &'static [ServerConfig] = Box::leak(servers.into_boxed_slice());
+        std::thread::spawn(move || {
+            let mut session_manager = SessionManager::new();
+            let _ = run_mio_server(listeners, &mut session_manager, servers);
+        });
+        addr
+    }
+
+    fn static_server(name: &str, root: &Path, keepalive_timeout: Option<u64>) -> ServerConfig {
+        ServerConfig {
+            server_name: name.to_string(),
+            max_body_size: 1024,
+            keepalive_timeout,
+            router: vec![RouterConfig {
+                path: "/".to_string(),
+                methods: vec!["GET".to_string()],
+                root: root.to_string_lossy().into_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn site(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("localhost-site-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "first file").unwrap();
+        std::fs::write(root.join("b.txt"), "second file").unwrap();
+        root
+    }
+
+    #[test]
+    fn pipelined_requests_answered_in_order() {
+        let root = site("pipeline");
+        let addr = serve(vec![static_server("a.test", &root, None)]);
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(
+                b"GET /b.txt HTTP/1.1\r\nHost: a.test\r\n\r\n\
+                  GET /a.txt HTTP/1.1\r\nHost: a.test\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer.matches("HTTP/1.1 200 OK").count(), 2, "{}", answer);
+        let second = answer.find("second file").unwrap();
+        let first = answer.find("first file").unwrap();
+        assert!(second < first, "{}", answer);
+    }
+
+    #[test]
+    fn head_split_between_reads() {
+        let root = site("split");
+        let addr = serve(vec![static_server("a.test", &root, None)]);
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.set_nodelay(true).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for piece in [
+            &b"GET /a.txt HTTP/1.1\r\nHost: a.test\r\nConnection: close\r"[..],
+            b"\n\r",
+            b"\n",
+        ] {
+            client.write_all(piece).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 200 OK"), "{}", answer);
+        assert!(answer.ends_with("first file"), "{}", answer);
+    }
+
+    #[test]
+    fn head_too_large() {
+        let root = site("large-head");
+        let addr = serve(vec![static_server("a.test", &root, None)]);
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut head = b"GET /a.txt HTTP/1.1\r\nHost: a.test\r\n".to_vec();
+        while head.len() <= MAX_HEAD_SIZE {
+            head.extend_from_slice(b"X-Filler: 0123456789abcdef0123456789abcdef\r\n");
+        }
+        // the server answers before the head is over
+        client.write_all(&head).unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert!(
+            answer.starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+            "{}",
+            answer
+        );
+    }
+
+    #[test]
+    fn idle_timeout_of_the_block_that_answered() {
+        let root = site("keepalive");
+        let addr = serve(vec![
+            static_server("default.test", &root, Some(60)),
+            static_server("short.test", &root, Some(1)),
+        ]);
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .write_all(b"GET /a.txt HTTP/1.1\r\nHost: short.test\r\n\r\n")
+            .unwrap();
+        let mut answer = Vec::new();
+        let mut buf = [0; 1024];
+        while !answer.ends_with(b"first file") {
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0, "closed before the response");
+            answer.extend_from_slice(&buf[..n]);
+        }
+        // short.test's 1 second, not the default block's 60
+        let idle = Instant::now();
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(idle.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn host_without_port() {
+        assert_eq!(strip_host_port("example.com"), "example.com");
+        assert_eq!(strip_host_port("example.com:8080"), "example.com");
+        assert_eq!(strip_host_port("[::1]:8080"), "::1");
+        assert_eq!(strip_host_port("[::1]"), "::1");
+        assert_eq!(strip_host_port("::1"), "::1");
+    }
 
-        i += chunk_size + 2; // Skip chunk data and trailing CRLF
+    #[test]
+    fn server_by_host() {
+        let servers = servers(&["default.test", "a.test www.a.test", "::1", "[fe80::1]"]);
+        let all = [0, 1, 2, 3];
+        assert_eq!(select_server(&servers, &all, Some("www.a.test")), 1);
+        assert_eq!(select_server(&servers, &all, Some("A.Test:8080")), 1);
+        assert_eq!(select_server(&servers, &all, Some("[::1]:8080")), 2);
+        assert_eq!(select_server(&servers, &all, Some("[FE80::1]")), 3);
+        // no Host, or one no block names, goes to the listener's default
+        assert_eq!(select_server(&servers, &all, None), 0);
+        assert_eq!(select_server(&servers, &all, Some("other.test")), 0);
+        assert_eq!(select_server(&servers, &[1, 0], Some("other.test")), 1);
+        // only the blocks on this listener are candidates
+        assert_eq!(select_server(&servers, &[0, 2], Some("a.test")), 0);
     }
-    None // Haven't found the end yet
 }
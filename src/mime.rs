@@ -0,0 +1,176 @@
+// # أنواع الملفات (MIME) حسب الامتداد
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// used when neither the table nor the route knows the extension
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+// built-in extension table; `mime_types` in the config adds to it or overrides it
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("avif", "image/avif"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("wasm", "application/wasm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Content-Type for a file: the config's `mime_types` first, then the built-in
+/// table, then the route's `default_type`. Text types get `; charset=utf-8`.
+pub fn content_type(
+    path: &Path,
+    mime_types: &HashMap<String, String>,
+    default_type: Option<&str>,
+) -> String {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let mime = mime_types
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(&extension))
+        .map(|(_, mime)| mime.as_str())
+        .or_else(|| {
+            MIME_TYPES
+                .iter()
+                .find(|(ext, _)| *ext == extension)
+                .map(|(_, mime)| *mime)
+        })
+        .unwrap_or(default_type.unwrap_or(DEFAULT_TYPE));
+    with_charset(mime)
+}
+
+/// adds `; charset=utf-8` to text types that don't name a charset already
+pub fn with_charset(mime: &str) -> String {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    let is_text = essence.starts_with("text/")
+        || matches!(
+            essence,
+            "application/javascript" | "application/json" | "application/xml" | "image/svg+xml"
+        );
+    if is_text && !mime.to_ascii_lowercase().contains("charset=") {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+/// "type/subtype", optionally followed by parameters
+pub fn is_valid(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) => {
+            !kind.is_empty()
+                && !subtype.is_empty()
+                && !subtype.contains('/')
+                && !essence.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(file: &str, overrides: &[(&str, &str)], default_type: Option<&str>) -> String {
+        let mime_types = overrides
+            .iter()
+            .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+            .collect();
+        content_type(Path::new(file), &mime_types, default_type)
+    }
+
+    #[test]
+    fn config_beats_the_table() {
+        assert_eq!(lookup("a.png", &[], None), "image/png");
+        assert_eq!(
+            lookup("a.png", &[("png", "image/x-custom")], None),
+            "image/x-custom"
+        );
+        assert_eq!(
+            lookup("notes.md", &[("MD", "text/markdown")], None),
+            "text/markdown; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn extension_case_is_ignored() {
+        assert_eq!(lookup("INDEX.HTML", &[], None), "text/html; charset=utf-8");
+        assert_eq!(lookup("photo.Png", &[], None), "image/png");
+    }
+
+    #[test]
+    fn unknown_or_missing_extension() {
+        assert_eq!(lookup("Makefile", &[], None), DEFAULT_TYPE);
+        assert_eq!(lookup("data.xyz123", &[], None), DEFAULT_TYPE);
+        assert_eq!(
+            lookup("Makefile", &[], Some("text/plain")),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            lookup("blob.xyz123", &[], Some("application/x-blob")),
+            "application/x-blob"
+        );
+        // a dotfile has no extension
+        assert_eq!(lookup(".htaccess", &[], None), DEFAULT_TYPE);
+    }
+
+    #[test]
+    fn charset_for_text_types() {
+        assert_eq!(with_charset("text/css"), "text/css; charset=utf-8");
+        assert_eq!(
+            with_charset("application/json"),
+            "application/json; charset=utf-8"
+        );
+        assert_eq!(
+            with_charset("text/plain; charset=latin1"),
+            "text/plain; charset=latin1"
+        );
+        assert_eq!(
+            with_charset("text/plain; Charset=UTF-8"),
+            "text/plain; Charset=UTF-8"
+        );
+        assert_eq!(with_charset("image/png"), "image/png");
+    }
+
+    #[test]
+    fn valid_types() {
+        assert!(is_valid("text/markdown"));
+        assert!(is_valid("text/plain; charset=utf-8"));
+        assert!(!is_valid("markdown"));
+        assert!(!is_valid("text/"));
+        assert!(!is_valid("/plain"));
+        assert!(!is_valid("a/b/c"));
+        assert!(!is_valid("text /plain"));
+    }
+}
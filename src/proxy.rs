@@ -0,0 +1,594 @@
+// # بروكسي عكسي (proxy_pass)
+//
+// A route with `proxy_pass` forwards its requests to an upstream HTTP server
+// and streams the answer back. The upstream's response is turned into a CGI
+// style header block ("Status: 404 Not Found" and the header fields) plus the
+// decoded body, so it goes through the same path as script output. Trailer
+// fields after a chunked body are kept for the client's last chunk.
+
+use crate::request_body::RequestBody;
+use crate::requests::{Headers, Request};
+use crate::serverConfig::RouterConfig;
+use crate::upstream::PeerId;
+use mio::net::TcpStream;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// seconds the upstream may stay silent when the route sets no `proxy_timeout`
+pub const DEFAULT_TIMEOUT: u64 = 60;
+// a response head longer than this is refused
+const MAX_HEAD: usize = 64 * 1024;
+// longest chunk-size or trailer line of a chunked response
+const MAX_LINE: usize = 8 * 1024;
+const BODY_CHUNK: usize = 32 * 1024;
+
+// fields about one connection only, never forwarded in either direction
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Content-Length",
+];
+
+// fields a trailer may not carry (framing, routing, caching, authentication and
+// how the content is handled); the upstream's are dropped
+const NOT_IN_TRAILERS: [&str; 14] = [
+    "Host",
+    "Content-Type",
+    "Content-Encoding",
+    "Content-Range",
+    "Cache-Control",
+    "Expires",
+    "Date",
+    "Age",
+    "Location",
+    "Retry-After",
+    "Vary",
+    "Set-Cookie",
+    "Authorization",
+    "WWW-Authenticate",
+];
+
+/// `proxy_pass http://127.0.0.1:3000/api`: where requests go, and the path
+/// that replaces the route's prefix when one is given
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub host: String, // "127.0.0.1:3000", or the name of one of the server's upstreams
+    pub path: Option<String>,
+}
+
+impl Target {
+    pub fn parse(value: &str) -> Result<Target, String> {
+        if value.starts_with("https://") {
+            return Err("https upstreams are not supported".to_string());
+        }
+        let rest = value.strip_prefix("http://").unwrap_or(value);
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], Some(rest[slash..].to_string())),
+            None => (rest, None),
+        };
+        if authority.is_empty() || authority.contains(['@', ' ']) {
+            return Err(format!("'{}' has no upstream host", value));
+        }
+        if let Some((_, port)) = authority.rsplit_once(':')
+            && !authority.ends_with(']')
+            && port.parse::<u16>().is_err()
+        {
+            return Err(format!("'{}' has an invalid port", value));
+        }
+        Ok(Target {
+            host: authority.to_string(),
+            path,
+        })
+    }
+}
+
+/// host:port to connect to; the port defaults to 80 like in a URL
+pub fn with_port(host: &str) -> String {
+    match host.rsplit_once(':') {
+        Some(_) if !host.ends_with(']') => host.to_string(),
+        _ => format!("{}:80", host),
+    }
+}
+
+/// Resolves host:port, the first address is used
+pub fn resolve(host: &str) -> io::Result<SocketAddr> {
+    with_port(host)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{} has no address", host)))
+}
+
+/// One request to an upstream. It is written and the response read as the
+/// socket allows; the event loop connects it before the first event.
+pub struct ProxyRequest {
+    pub target: Target,
+    pub client_ip: Option<IpAddr>, // for ip_hash
+    pub host: String,              // the server connected to, also the key of the connection pool
+    pub peer: Option<PeerId>,      // the upstream server it is, when target names an upstream
+    pub stream: Option<TcpStream>,
+    out: Vec<u8>, // request bytes not written yet
+    written: usize,
+    body: Option<File>, // a spooled body, sent as the socket takes it
+    input: Vec<u8>,     // bytes read that are not decoded yet
+    output: Vec<u8>,    // header block and body bytes for the event loop
+    state: State,
+    head_request: bool,
+    keep_alive: bool,      // the upstream keeps the connection after this response
+    pub trailers: Headers, // after a chunked body
+}
+
+enum State {
+    Head,
+    Length(u64), // body bytes still to come
+    ChunkSize,
+    ChunkData(u64),
+    ChunkEnd, // the CRLF after a chunk
+    Trailers,
+    UntilClose,
+    Done,
+}
+
+/// Request line and header fields for the upstream, without the blank line:
+/// the client's end-to-end fields minus `skip`, and the X-Forwarded-* ones
+pub fn forwarded_head(
+    target: &Target,
+    req: &Request,
+    route: &RouterConfig,
+    skip: &[&str],
+) -> String {
+    // with a path in proxy_pass, it replaces the route's prefix
+    let path = match &target.path {
+        Some(base) => {
+            let rest = req.path.get(route.path.len()..).unwrap_or("");
+            let base = base.trim_end_matches('/');
+            match rest.starts_with('/') || rest.starts_with('?') {
+                true => format!("{}{}", base, rest),
+                false => format!("{}/{}", base, rest),
+            }
+        }
+        None => req.path.clone(),
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, path);
+
+    // fields named in Connection are hop-by-hop too
+    let connection_fields: Vec<String> = req
+        .header("Connection")
+        .unwrap_or("")
+        .split(',')
+        .map(|f| f.trim().to_ascii_lowercase())
+        .collect();
+    let mut forwarded_for = None;
+    for (name, value) in &req.headers {
+        let lower = name.to_ascii_lowercase();
+        if HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+            || connection_fields.contains(&lower)
+            || skip.iter().any(|s| s.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        if lower == "x-forwarded-for" {
+            forwarded_for = Some(value.clone());
+            continue;
+        }
+        // our own Forwarded is added below, with what came before kept in front
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    if let Some(remote) = req.remote_addr {
+        let ip = remote.ip().to_string();
+        let chain = match forwarded_for {
+            Some(before) => format!("{}, {}", before, ip),
+            None => ip.clone(),
+        };
+        head += &format!("X-Forwarded-For: {}\r\n", chain);
+        // RFC 7239: IPv6 addresses are quoted and bracketed
+        let node = if remote.is_ipv6() {
+            format!("\"[{}]\"", ip)
+        } else {
+            ip
+        };
+        let mut forwarded = format!("for={};proto={}", node, req.scheme());
+        if let Some(host) = req.header("Host") {
+            forwarded += &format!(";host=\"{}\"", host.replace('"', ""));
+        }
+        head += &format!("Forwarded: {}\r\n", forwarded);
+    }
+    head += &format!("X-Forwarded-Proto: {}\r\n", req.scheme());
+    if req.header("Host").is_none() {
+        head += &format!("Host: {}\r\n", target.host);
+    }
+    head
+}
+
+impl ProxyRequest {
+    pub fn new(target: Target, req: &Request, route: &RouterConfig) -> io::Result<ProxyRequest> {
+        // Expect: the body is all here already
+        let mut head = forwarded_head(&target, req, route, &["Expect"]);
+        let body_len = req.body.len();
+        if body_len > 0 || req.header("Content-Length").is_some() {
+            head += &format!("Content-Length: {}\r\n", body_len);
+        }
+        // trailers are passed on, so they are asked for when the client takes them
+        let te_trailers = req.header("TE").is_some_and(|te| {
+            te.split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+        });
+        if te_trailers {
+            head += "TE: trailers\r\nConnection: keep-alive, TE\r\n\r\n";
+        } else {
+            head += "Connection: keep-alive\r\n\r\n";
+        }
+
+        let mut out = head.into_bytes();
+        let body = match &req.body {
+            RequestBody::Memory(bytes) => {
+                out.extend_from_slice(bytes);
+                None
+            }
+            RequestBody::Spooled(_) => req.body.spool_file().transpose()?,
+        };
+        Ok(ProxyRequest {
+            target,
+            client_ip: req.remote_addr.map(|addr| addr.ip()),
+            host: String::new(),
+            peer: None,
+            stream: None,
+            out,
+            written: 0,
+            body,
+            input: Vec::new(),
+            output: Vec::new(),
+            state: State::Head,
+            head_request: req.method == "HEAD",
+            keep_alive: false,
+            trailers: Headers::new(),
+        })
+    }
+
+    /// Writes the request until the socket is full; true once it is all out
+    fn send(&mut self) -> io::Result<bool> {
+        let Some(stream) = self.stream.as_mut() else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        loop {
+            while self.written < self.out.len() {
+                match stream.write(&self.out[self.written..]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => self.written += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.out.clear();
+            self.written = 0;
+            let Some(file) = self.body.as_mut() else {
+                return Ok(true);
+            };
+            self.out.resize(BODY_CHUNK, 0);
+            let n = file.read(&mut self.out)?;
+            self.out.truncate(n);
+            if n == 0 {
+                self.body = None;
+            }
+        }
+    }
+
+    /// Sends what the socket takes, then reads the response into `buf` as a
+    /// header block and body: Ok(0) once it is complete, WouldBlock while the
+    /// upstream sent nothing new
+    pub fn read_response(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.send()?;
+        loop {
+            if !self.output.is_empty() {
+                let n = buf.len().min(self.output.len());
+                buf[..n].copy_from_slice(&self.output[..n]);
+                self.output.drain(..n);
+                return Ok(n);
+            }
+            if let State::Done = self.state {
+                return Ok(0);
+            }
+            let mut chunk = [0; 16384];
+            let n = match self.stream.as_mut() {
+                Some(stream) => stream.read(&mut chunk)?,
+                None => return Err(io::ErrorKind::NotConnected.into()),
+            };
+            if n == 0 {
+                if let State::UntilClose = self.state {
+                    self.state = State::Done;
+                    continue;
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "upstream closed the connection",
+                ));
+            }
+            self.input.extend_from_slice(&chunk[..n]);
+            self.decode()?;
+        }
+    }
+
+    // Moves what can be decoded from `input` to `output`
+    fn decode(&mut self) -> io::Result<()> {
+        loop {
+            match self.state {
+                State::Head => {
+                    let Some(end) = self.input.windows(4).position(|w| w == b"\r\n\r\n") else {
+                        if self.input.len() > MAX_HEAD {
+                            return Err(invalid("response head too long"));
+                        }
+                        return Ok(());
+                    };
+                    let head: Vec<u8> = self.input.drain(..end + 4).collect();
+                    self.read_head(&head)?;
+                }
+                State::Length(remaining) => {
+                    let n = remaining.min(self.input.len() as u64) as usize;
+                    self.output.extend(self.input.drain(..n));
+                    self.state = match remaining - n as u64 {
+                        0 => State::Done,
+                        left => State::Length(left),
+                    };
+                    if n == 0 {
+                        return Ok(());
+                    }
+                }
+                State::ChunkSize | State::Trailers => {
+                    let Some(lf) = self.input.windows(2).position(|w| w == b"\r\n") else {
+                        if self.input.len() > MAX_LINE {
+                            return Err(invalid("chunk line too long"));
+                        }
+                        return Ok(());
+                    };
+                    if lf > MAX_LINE {
+                        return Err(invalid("chunk line too long"));
+                    }
+                    let line: Vec<u8> = self.input.drain(..lf + 2).collect();
+                    let line = String::from_utf8_lossy(&line[..lf]).to_string();
+                    if let State::Trailers = self.state {
+                        // the empty line ends the body
+                        if line.is_empty() {
+                            self.state = State::Done;
+                        } else if let Some((name, value)) = line.split_once(':') {
+                            self.add_trailer(name.trim(), value.trim())?;
+                        }
+                        continue;
+                    }
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(invalid("bad chunk size"));
+                    }
+                    let size =
+                        u64::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+                    self.state = match size {
+                        0 => State::Trailers,
+                        size => State::ChunkData(size),
+                    };
+                }
+                State::ChunkData(remaining) => {
+                    let n = remaining.min(self.input.len() as u64) as usize;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    self.output.extend(self.input.drain(..n));
+                    self.state = match remaining - n as u64 {
+                        0 => State::ChunkEnd,
+                        left => State::ChunkData(left),
+                    };
+                }
+                State::ChunkEnd => {
+                    if self.input.len() < 2 {
+                        return Ok(());
+                    }
+                    if &self.input[..2] != b"\r\n" {
+                        return Err(invalid("bad chunk"));
+                    }
+                    self.input.drain(..2);
+                    self.state = State::ChunkSize;
+                }
+                State::UntilClose => {
+                    self.output.append(&mut self.input);
+                    return Ok(());
+                }
+                State::Done => return Ok(()),
+            }
+        }
+    }
+
+    fn add_trailer(&mut self, name: &str, value: &str) -> io::Result<()> {
+        let size: usize = self.trailers.iter().map(|(n, v)| n.len() + v.len()).sum();
+        if size > MAX_HEAD {
+            return Err(invalid("trailers too long"));
+        }
+        let allowed = !HOP_BY_HOP
+            .iter()
+            .chain(NOT_IN_TRAILERS.iter())
+            .any(|field| field.eq_ignore_ascii_case(name));
+        if allowed && !name.is_empty() {
+            self.trailers.append(name, value);
+        }
+        Ok(())
+    }
+
+    // Status line and fields of the upstream response, as a CGI header block
+    fn read_head(&mut self, head: &[u8]) -> io::Result<()> {
+        let head = std::str::from_utf8(head).map_err(|_| invalid("response head is not UTF-8"))?;
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or("");
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        let code: u16 = parts
+            .next()
+            .and_then(|code| code.parse().ok())
+            .filter(|code| (100..=599).contains(code))
+            .ok_or_else(|| invalid("bad status line"))?;
+        let reason = parts.next().unwrap_or("").trim();
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid("bad status line"));
+        }
+        // 100 Continue and other interim responses come before the real one
+        if code < 200 {
+            return Ok(());
+        }
+
+        let fields: Vec<(&str, &str)> = lines
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        let field = |wanted: &str| {
+            fields
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| *value)
+        };
+        let connection = field("Connection").unwrap_or("").to_ascii_lowercase();
+        let connection_fields: Vec<&str> = connection.split(',').map(str::trim).collect();
+        self.keep_alive = if version == "HTTP/1.0" {
+            connection_fields.contains(&"keep-alive")
+        } else {
+            !connection_fields.contains(&"close")
+        };
+
+        let mut block = format!("Status: {} {}\r\n", code, reason);
+        for (name, value) in &fields {
+            let lower = name.to_ascii_lowercase();
+            // Trailer announces the trailers, which are passed on
+            let hop_by_hop = HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+                && !name.eq_ignore_ascii_case("Trailer");
+            // the status line says the status; `Status:` is a CGI convention and
+            // from an upstream it would override it
+            if hop_by_hop
+                || connection_fields.contains(&lower.as_str())
+                || name.eq_ignore_ascii_case("Status")
+            {
+                continue;
+            }
+            block += &format!("{}: {}\r\n", name, value);
+        }
+        block += "\r\n";
+        self.output.extend_from_slice(block.as_bytes());
+
+        let chunked = field("Transfer-Encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        let length = field("Content-Length").and_then(|len| len.parse::<u64>().ok());
+        self.state = if self.head_request || code == 204 || code == 304 {
+            State::Done
+        } else if chunked {
+            State::ChunkSize
+        } else if let Some(length) = length {
+            if length == 0 {
+                State::Done
+            } else {
+                State::Length(length)
+            }
+        } else {
+            self.keep_alive = false;
+            State::UntilClose
+        };
+        Ok(())
+    }
+
+    /// The connection can take another request once this response ended cleanly
+    pub fn reusable(&self) -> bool {
+        matches!(self.state, State::Done)
+            && self.keep_alive
+            && self.input.is_empty()
+            && self.out.is_empty()
+            && self.body.is_none()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::parse_request_head;
+
+    fn proxy_request() -> ProxyRequest {
+        let raw = b"GET /x HTTP/1.1\r\nHost: a.test\r\n\r\n";
+        let req = parse_request_head(raw).unwrap().0;
+        let target = Target::parse("127.0.0.1:9000").unwrap();
+        ProxyRequest::new(target, &req, &RouterConfig::default()).unwrap()
+    }
+
+    fn decoded(response: &[u8]) -> io::Result<(String, ProxyRequest)> {
+        let mut proxy = proxy_request();
+        // a byte at a time, the worst split
+        for &b in response {
+            proxy.input.push(b);
+            proxy.decode()?;
+        }
+        let output = String::from_utf8_lossy(&proxy.output).to_string();
+        Ok((output, proxy))
+    }
+
+    #[test]
+    fn chunked_response_with_trailers() {
+        let (output, proxy) = decoded(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: X-Sum\r\n\r\n\
+              5;ext\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\nSet-Cookie: no\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "Status: 200 OK\r\nTrailer: X-Sum\r\n\r\nhello world"
+        );
+        assert_eq!(proxy.trailers.get("X-Sum"), Some("1"));
+        assert_eq!(proxy.trailers.get("Set-Cookie"), None);
+        assert!(proxy.keep_alive && matches!(proxy.state, State::Done));
+    }
+
+    #[test]
+    fn status_field_does_not_override_the_status_line() {
+        let (output, _) =
+            decoded(b"HTTP/1.1 404 Not Found\r\nStatus: 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        assert_eq!(output, "Status: 404 Not Found\r\n\r\n");
+    }
+
+    #[test]
+    fn long_chunk_line_is_refused() {
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        response.extend_from_slice(&vec![b'0'; MAX_LINE + 1]);
+        assert!(decoded(&response).is_err());
+        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1;".to_vec();
+        response.extend_from_slice(&vec![b'a'; MAX_LINE]);
+        response.extend_from_slice(b"\r\n");
+        let mut proxy = proxy_request();
+        proxy.input = response;
+        assert!(proxy.decode().is_err());
+    }
+
+    #[test]
+    fn bad_chunk_size_is_refused() {
+        for size in ["zz", "+5", "", "10000000000000000"] {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\n",
+                size
+            );
+            assert!(decoded(response.as_bytes()).is_err(), "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn hop_by_hop_fields_are_dropped() {
+        let (output, proxy) = decoded(
+            b"HTTP/1.0 200 OK\r\nConnection: X-Private\r\nX-Private: 1\r\nKeep-Alive: timeout=5\r\n\
+              X-Kept: 2\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .unwrap();
+        assert_eq!(output, "Status: 200 OK\r\nX-Kept: 2\r\n\r\nok");
+        assert!(!proxy.keep_alive);
+    }
+}
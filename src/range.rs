@@ -0,0 +1,185 @@
+// # طلبات جزء من الملف (Range)
+//
+// `Range: bytes=0-99,200-,-50` for static files: one range gives a 206 with
+// Content-Range, several give a multipart/byteranges body, none that fit the
+// file give a 416.
+
+use crate::conditional::{self, Validators};
+use crate::requests::Request;
+
+// more ranges than this and the header is ignored, so a request can't make us
+// send the same bytes hundreds of times
+const MAX_RANGES: usize = 64;
+
+/// inclusive byte positions, like in Content-Range
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+pub enum RangeRequest {
+    Full, // no Range header, or one we ignore
+    Ranges(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+/// What part of a `len`-byte file a GET asks for
+pub fn requested(req: &Request, validators: &Validators, len: u64) -> RangeRequest {
+    if req.method != "GET" {
+        return RangeRequest::Full;
+    }
+    let Some(header) = req.header("Range") else {
+        return RangeRequest::Full;
+    };
+    // the client's copy is stale, it gets the whole new file instead
+    if !conditional::if_range_matches(req, validators) {
+        return RangeRequest::Full;
+    }
+    parse_range(header, len)
+}
+
+/// Parses a Range header; syntax errors and other units mean the header is ignored
+fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = if first.is_empty() {
+            // "-500": the last 500 bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            }
+        } else {
+            let Ok(start) = first.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start >= len {
+                continue; // not satisfiable, the other ranges may be
+            }
+            ByteRange {
+                start,
+                end: end.min(len - 1),
+            }
+        };
+        ranges.push(range);
+    }
+
+    if count == 0 {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Ranges(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::parse_request_head;
+
+    fn ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        match parse_range(header, len) {
+            RangeRequest::Ranges(ranges) => Some(ranges.iter().map(|r| (r.start, r.end)).collect()),
+            RangeRequest::Full => None,
+            RangeRequest::Unsatisfiable => Some(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn single_and_open_ranges() {
+        assert_eq!(ranges("bytes=0-99", 1000), Some(vec![(0, 99)]));
+        assert_eq!(ranges("bytes=900-", 1000), Some(vec![(900, 999)]));
+        assert_eq!(ranges("bytes=990-2000", 1000), Some(vec![(990, 999)]));
+        assert_eq!(ranges("BYTES = 5-5", 1000), Some(vec![(5, 5)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(ranges("bytes=-50", 1000), Some(vec![(950, 999)]));
+        assert_eq!(ranges("bytes=-5000", 1000), Some(vec![(0, 999)]));
+    }
+
+    #[test]
+    fn several_ranges() {
+        assert_eq!(
+            ranges("bytes=0-9, 20-29,-5", 100),
+            Some(vec![(0, 9), (20, 29), (95, 99)])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert_eq!(ranges("bytes=1000-", 1000), Some(Vec::new()));
+        assert_eq!(ranges("bytes=-0", 1000), Some(Vec::new()));
+        assert_eq!(ranges("bytes=0-0", 0), Some(Vec::new()));
+        // the one that fits is still served
+        assert_eq!(ranges("bytes=2000-,0-0", 1000), Some(vec![(0, 0)]));
+    }
+
+    #[test]
+    fn ignored_headers() {
+        for header in [
+            "items=0-9",
+            "bytes",
+            "bytes=",
+            "bytes=9-0",
+            "bytes=a-b",
+            "bytes=5",
+            "bytes=0-99999999999999999999",
+        ] {
+            assert_eq!(ranges(header, 1000), None, "{}", header);
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(ranges(&many, 1000), None);
+    }
+
+    #[test]
+    fn if_range_with_weak_etag_gives_the_whole_file() {
+        let raw = b"GET / HTTP/1.1\r\nRange: bytes=0-9\r\nIf-Range: W/\"a\"\r\n\r\n";
+        let req = parse_request_head(raw).unwrap().0;
+        let validators = Validators {
+            etag: "W/\"a\"".to_string(),
+            modified: 0,
+        };
+        assert!(matches!(
+            requested(&req, &validators, 100),
+            RangeRequest::Full
+        ));
+    }
+}
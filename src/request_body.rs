@@ -0,0 +1,429 @@
+// # قراءة جسم الطلب تدريجياً
+//
+// The body is decoded while it arrives (Content-Length or chunked) and kept in
+// memory until it grows past SPOOL_THRESHOLD; after that it is written to a
+// temp file so a large upload never sits whole in RAM.
+
+use rand::{Rng, distributions::Alphanumeric};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// bodies bigger than this are spooled to disk
+pub const SPOOL_THRESHOLD: usize = 1024 * 1024; // 1MB
+
+/// A complete request body, either in memory or in a temp file
+#[derive(Debug)]
+pub enum RequestBody {
+    Memory(Vec<u8>),
+    Spooled(SpoolFile),
+}
+
+/// Temp file holding a body; removed from disk when dropped
+#[derive(Debug)]
+pub struct SpoolFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl RequestBody {
+    pub fn len(&self) -> u64 {
+        match self {
+            RequestBody::Memory(bytes) => bytes.len() as u64,
+            RequestBody::Spooled(spool) => spool.len,
+        }
+    }
+
+    /// reads the body from the start
+    pub fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self {
+            RequestBody::Memory(bytes) => Ok(Box::new(Cursor::new(bytes.as_slice()))),
+            RequestBody::Spooled(spool) => {
+                let mut file = spool.file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    /// the spool file opened again for reading, e.g. to hand to a child as stdin
+    pub fn spool_file(&self) -> Option<io::Result<File>> {
+        match self {
+            RequestBody::Memory(_) => None,
+            RequestBody::Spooled(spool) => Some(File::open(&spool.path)),
+        }
+    }
+}
+
+/// Collects decoded body bytes, moving to a temp file past SPOOL_THRESHOLD
+struct BodySpool {
+    memory: Vec<u8>,
+    file: Option<SpoolFile>,
+}
+
+impl BodySpool {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(spool) = self.file.as_mut() {
+            spool.file.write_all(data)?;
+            spool.len += data.len() as u64;
+            return Ok(());
+        }
+        if self.memory.len() + data.len() <= SPOOL_THRESHOLD {
+            self.memory.extend_from_slice(data);
+            return Ok(());
+        }
+        let mut spool = create_spool_file()?;
+        spool.file.write_all(&self.memory)?;
+        spool.file.write_all(data)?;
+        spool.len = (self.memory.len() + data.len()) as u64;
+        self.memory = Vec::new();
+        self.file = Some(spool);
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<RequestBody> {
+        match self.file {
+            Some(mut spool) => {
+                spool.file.flush()?;
+                Ok(RequestBody::Spooled(spool))
+            }
+            None => Ok(RequestBody::Memory(self.memory)),
+        }
+    }
+}
+
+fn create_spool_file() -> io::Result<SpoolFile> {
+    let name: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    let path = std::env::temp_dir().join(format!("localhost-body-{}", name));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    Ok(SpoolFile { path, file, len: 0 })
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    Malformed,
+    Io(io::Error),
+}
+
+impl From<io::Error> for BodyError {
+    fn from(e: io::Error) -> Self {
+        BodyError::Io(e)
+    }
+}
+
+enum ChunkState {
+    Size(Vec<u8>),  // reading the "1a;ext=..\r\n" line
+    Data(u64),      // bytes left in the current chunk
+    DataEnd(usize), // CRLF after the chunk data, bytes of it already seen
+    // trailer lines after the last chunk, until an empty one: the line so far
+    // and the bytes of the lines before it
+    Trailer(Vec<u8>, usize),
+    Done,
+}
+
+enum Framing {
+    Length(u64), // bytes still expected
+    Chunked(ChunkState),
+}
+
+/// Decodes a request body incrementally as bytes come off the socket
+pub struct BodyReader {
+    framing: Framing,
+    spool: BodySpool,
+    limit: u64,
+    received: u64, // decoded bytes so far
+}
+
+// longest chunk-size or trailer line we accept
+const MAX_LINE: usize = 8 * 1024;
+// all the trailer lines together
+const MAX_TRAILERS: usize = 16 * 1024;
+
+impl BodyReader {
+    /// `content_length` is ignored when `chunked` is set
+    pub fn new(chunked: bool, content_length: u64, limit: u64) -> Result<Self, BodyError> {
+        if !chunked && content_length > limit {
+            return Err(BodyError::TooLarge);
+        }
+        let framing = if chunked {
+            Framing::Chunked(ChunkState::Size(Vec::new()))
+        } else {
+            Framing::Length(content_length)
+        };
+        Ok(BodyReader {
+            framing,
+            spool: BodySpool {
+                memory: Vec::new(),
+                file: None,
+            },
+            limit,
+            received: 0,
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self.framing,
+            Framing::Length(0) | Framing::Chunked(ChunkState::Done)
+        )
+    }
+
+    /// Consumes body bytes from `input` and returns how many were used.
+    /// Anything after the end of the body is left for the next request.
+    pub fn feed(&mut self, input: &[u8]) -> Result<usize, BodyError> {
+        let mut pos = 0;
+        while pos < input.len() && !self.is_done() {
+            match &mut self.framing {
+                Framing::Length(remaining) => {
+                    let take = (*remaining).min((input.len() - pos) as u64) as usize;
+                    *remaining -= take as u64;
+                    self.store(&input[pos..pos + take])?;
+                    pos += take;
+                }
+                Framing::Chunked(state) => {
+                    pos += Self::feed_chunked(
+                        state,
+                        &input[pos..],
+                        &mut self.spool,
+                        &mut self.received,
+                        self.limit,
+                    )?;
+                }
+            }
+        }
+        Ok(pos)
+    }
+
+    fn store(&mut self, data: &[u8]) -> Result<(), BodyError> {
+        self.received += data.len() as u64;
+        if self.received > self.limit {
+            return Err(BodyError::TooLarge);
+        }
+        self.spool.write(data)?;
+        Ok(())
+    }
+
+    // one step of the chunked decoder, returns the bytes it used
+    fn feed_chunked(
+        state: &mut ChunkState,
+        input: &[u8],
+        spool: &mut BodySpool,
+        received: &mut u64,
+        limit: u64,
+    ) -> Result<usize, BodyError> {
+        match state {
+            ChunkState::Size(line) | ChunkState::Trailer(line, _) => {
+                let lf = input.iter().position(|&b| b == b'\n');
+                line.extend_from_slice(&input[..lf.unwrap_or(input.len())]);
+                if line.len() > MAX_LINE + 1 {
+                    return Err(BodyError::Malformed);
+                }
+                let Some(lf) = lf else {
+                    return Ok(input.len());
+                };
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                if line.len() > MAX_LINE {
+                    return Err(BodyError::Malformed);
+                }
+                let line = std::mem::take(line);
+                *state = match state {
+                    ChunkState::Size(_) => {
+                        // chunk extensions after ';' are ignored
+                        let size = std::str::from_utf8(&line)
+                            .ok()
+                            .and_then(|l| l.split(';').next())
+                            .map(str::trim)
+                            .filter(|l| !l.is_empty() && l.bytes().all(|b| b.is_ascii_hexdigit()))
+                            .map(|l| u64::from_str_radix(l, 16))
+                            .ok_or(BodyError::Malformed)?
+                            // more digits than a u64 holds
+                            .map_err(|_| BodyError::TooLarge)?;
+                        if size == 0 {
+                            ChunkState::Trailer(Vec::new(), 0)
+                        } else {
+                            // a size near u64::MAX must not wrap past the limit
+                            if received.checked_add(size).is_none_or(|total| total > limit) {
+                                return Err(BodyError::TooLarge);
+                            }
+                            ChunkState::Data(size)
+                        }
+                    }
+                    _ if line.is_empty() => ChunkState::Done,
+                    // trailer fields are dropped, but only so many are read
+                    ChunkState::Trailer(_, seen) => {
+                        let seen = *seen + line.len() + 2;
+                        if seen > MAX_TRAILERS {
+                            return Err(BodyError::Malformed);
+                        }
+                        ChunkState::Trailer(Vec::new(), seen)
+                    }
+                    _ => unreachable!(),
+                };
+                Ok(lf + 1)
+            }
+            ChunkState::Data(remaining) => {
+                let take = (*remaining).min(input.len() as u64) as usize;
+                *remaining -= take as u64;
+                *received += take as u64;
+                spool.write(&input[..take])?;
+                if *remaining == 0 {
+                    *state = ChunkState::DataEnd(0);
+                }
+                Ok(take)
+            }
+            ChunkState::DataEnd(seen) => {
+                let expected = b"\r\n";
+                let mut used = 0;
+                while *seen < 2 && used < input.len() {
+                    if input[used] != expected[*seen] {
+                        return Err(BodyError::Malformed);
+                    }
+                    *seen += 1;
+                    used += 1;
+                }
+                if *seen == 2 {
+                    *state = ChunkState::Size(Vec::new());
+                }
+                Ok(used)
+            }
+            ChunkState::Done => Ok(0),
+        }
+    }
+
+    pub fn finish(self) -> Result<RequestBody, BodyError> {
+        Ok(self.spool.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body_bytes(body: RequestBody) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        body.reader().unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn content_length_leaves_the_next_request() {
+        let mut reader = BodyReader::new(false, 5, 100).unwrap();
+        assert_eq!(reader.feed(b"helloGET /").unwrap(), 5);
+        assert!(reader.is_done());
+        assert_eq!(body_bytes(reader.finish().unwrap()), b"hello");
+    }
+
+    #[test]
+    fn content_length_over_limit() {
+        assert!(matches!(
+            BodyReader::new(false, 101, 100),
+            Err(BodyError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn chunked_fed_a_byte_at_a_time() {
+        let input = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\nNEXT";
+        let mut reader = BodyReader::new(true, 0, 100).unwrap();
+        let mut used = 0;
+        while !reader.is_done() {
+            used += reader.feed(&input[used..used + 1]).unwrap();
+        }
+        assert_eq!(&input[used..], b"NEXT");
+        assert_eq!(body_bytes(reader.finish().unwrap()), b"hello world");
+    }
+
+    #[test]
+    fn chunk_size_overflow_is_too_large() {
+        // a u64 size that would wrap once added to what came before, and one
+        // with more digits than a u64 holds
+        for input in [
+            &b"1\r\na\r\nffffffffffffffff\r\n"[..],
+            b"10000000000000000\r\n",
+        ] {
+            let mut reader = BodyReader::new(true, 0, u64::MAX).unwrap();
+            assert!(matches!(reader.feed(input), Err(BodyError::TooLarge)));
+        }
+    }
+
+    #[test]
+    fn chunk_over_limit_is_too_large() {
+        let mut reader = BodyReader::new(true, 0, 10).unwrap();
+        assert!(matches!(reader.feed(b"b\r\n"), Err(BodyError::TooLarge)));
+    }
+
+    #[test]
+    fn chunk_size_must_be_hex() {
+        for line in [&b"zz\r\n"[..], b"+5\r\n", b"\r\n", b"-1\r\n"] {
+            let mut reader = BodyReader::new(true, 0, 100).unwrap();
+            assert!(matches!(reader.feed(line), Err(BodyError::Malformed)));
+        }
+    }
+
+    #[test]
+    fn chunk_data_needs_crlf() {
+        let mut reader = BodyReader::new(true, 0, 100).unwrap();
+        assert!(matches!(
+            reader.feed(b"2\r\nabXY"),
+            Err(BodyError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn long_size_line_is_malformed() {
+        let mut reader = BodyReader::new(true, 0, 100).unwrap();
+        let line = vec![b'0'; MAX_LINE + 2];
+        assert!(matches!(reader.feed(&line), Err(BodyError::Malformed)));
+        // the same with its end in the same read
+        let mut reader = BodyReader::new(true, 0, 100).unwrap();
+        let mut line = vec![b'0'; MAX_LINE + 1];
+        line.extend_from_slice(b"\r\n");
+        assert!(matches!(reader.feed(&line), Err(BodyError::Malformed)));
+    }
+
+    #[test]
+    fn trailers_are_capped() {
+        let field = format!("X-Filler: {}\r\n", "a".repeat(1000));
+        let mut reader = BodyReader::new(true, 0, 100).unwrap();
+        assert_eq!(reader.feed(b"0\r\n").unwrap(), 3);
+        let mut result = Ok(0);
+        for _ in 0..MAX_TRAILERS / field.len() + 1 {
+            result = reader.feed(field.as_bytes());
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(result, Err(BodyError::Malformed)));
+        assert!(!reader.is_done());
+    }
+
+    #[test]
+    fn large_body_is_spooled() {
+        let len = SPOOL_THRESHOLD + 10;
+        let mut reader = BodyReader::new(false, len as u64, u64::MAX).unwrap();
+        let data = vec![b'x'; len];
+        assert_eq!(reader.feed(&data).unwrap(), len);
+        let body = reader.finish().unwrap();
+        assert!(matches!(body, RequestBody::Spooled(_)));
+        assert_eq!(body.len(), len as u64);
+        assert_eq!(body_bytes(body), data);
+    }
+}
@@ -1,31 +1,258 @@
+use crate::request_body::RequestBody;
 use std::collections::HashMap;
-use crate::upload_handler::decode_chunked_body;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
 #[derive(Debug)]
 pub struct Request {
     pub method: String,
-    pub path: String,
+    pub path: String, // request target as sent, query string included
     pub version: String,
     pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    pub body: RequestBody,
+    pub remote_addr: Option<SocketAddr>, // client end of the connection
+    pub local_addr: Option<SocketAddr>,  // our end, the address the client connected to
+    pub secure: bool,                    // came in over TLS
+}
+
+impl Request {
+    /// "https" for a request that came in over TLS, else "http"
+    pub fn scheme(&self) -> &'static str {
+        if self.secure { "https" } else { "http" }
+    }
+
+    /// header lookup ignoring the case of the name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// the path part of the request target, without `?query`
+    pub fn uri_path(&self) -> &str {
+        self.path
+            .split_once('?')
+            .map_or(&self.path, |(path, _)| path)
+    }
+
+    /// the query string after `?`, empty when there is none
+    pub fn query(&self) -> &str {
+        self.path.split_once('?').map_or("", |(_, query)| query)
+    }
+
+    /// HTTP/1.1 keeps the connection open unless the client sends `Connection: close`,
+    /// HTTP/1.0 only when it asks for `Connection: keep-alive`
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("").to_ascii_lowercase();
+        let mut options = connection.split(',').map(|o| o.trim());
+        if options.clone().any(|o| o == "close") {
+            return false;
+        }
+        if options.any(|o| o == "keep-alive") {
+            return true;
+        }
+        self.version == "HTTP/1.1"
+    }
 }
 
 #[derive(Debug)]
 pub struct Response {
     pub status_code: u16,
     pub reason_phrase: String,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    pub headers: Headers,
+    pub body: ResponseBody,
+}
+
+/// Response header fields in the order they were set. Names compare without
+/// case, and a name can repeat (several Set-Cookie lines) through `append`.
+#[derive(Debug, Default, Clone)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers(Vec::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// sets a header, replacing every field of that name
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.0.push((name, value.into()));
+    }
+
+    /// adds a field, keeping the ones of the same name
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    /// removes every field of that name and returns the first value
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.0.retain(|(key, value)| {
+            if key.eq_ignore_ascii_case(name) {
+                removed.get_or_insert_with(|| value.clone());
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl Response {
+    pub fn html(status_code: u16, reason_phrase: &str, body: Vec<u8>) -> Self {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/html; charset=utf-8");
+        Response {
+            status_code,
+            reason_phrase: reason_phrase.to_string(),
+            headers,
+            body: body.into(),
+        }
+    }
 }
 
-pub fn parse_http_request(raw: &[u8]) -> Option<Request> {
-    println!("DEBUG: parse_http_request called with {} bytes", raw.len());
-    
+/// Response body: bytes built in memory, or a region of a file that is sent
+/// straight from the page cache with sendfile(2), or a sequence of both
+/// (multipart/byteranges), or a body generated while it is sent
+#[derive(Debug)]
+pub enum ResponseBody {
+    Bytes(Vec<u8>),
+    File(FileBody),
+    Parts(Vec<ResponseBody>),
+    Stream(StreamBody),
+}
+
+#[derive(Debug)]
+pub struct FileBody {
+    pub file: File,
+    pub offset: u64, // next byte to send
+    pub len: u64,    // bytes left to send
+}
+
+/// Makes the pieces of a body whose length is not known before it is sent
+pub trait BodySource: std::fmt::Debug {
+    /// the next piece, None after the last one
+    fn next_piece(&mut self) -> io::Result<Option<Vec<u8>>>;
+
+    /// fields sent after the last chunk
+    fn trailers(&self) -> Headers {
+        Headers::new()
+    }
+}
+
+/// A generated body: chunked for HTTP/1.1, else it ends when the connection
+/// closes
+#[derive(Debug)]
+pub struct StreamBody {
+    pub source: Box<dyn BodySource>,
+    pub chunked: bool,
+}
+
+impl ResponseBody {
+    /// bytes left to send; a stream counts as empty, its length is unknown
+    pub fn len(&self) -> u64 {
+        match self {
+            ResponseBody::Bytes(bytes) => bytes.len() as u64,
+            ResponseBody::File(file) => file.len,
+            ResponseBody::Parts(parts) => parts.iter().map(ResponseBody::len).sum(),
+            ResponseBody::Stream(_) => 0,
+        }
+    }
+
+    /// a body that ends when the connection closes
+    pub fn is_close_delimited(&self) -> bool {
+        matches!(self, ResponseBody::Stream(stream) if !stream.chunked)
+    }
+
+    /// appends the pieces to `pieces`, joining neighbouring bytes into one write
+    fn flatten_into(self, pieces: &mut Vec<ResponseBody>) {
+        match self {
+            ResponseBody::Parts(parts) => {
+                for part in parts {
+                    part.flatten_into(pieces);
+                }
+            }
+            ResponseBody::Bytes(bytes) => match pieces.last_mut() {
+                Some(ResponseBody::Bytes(last)) => last.extend(bytes),
+                _ => pieces.push(ResponseBody::Bytes(bytes)),
+            },
+            file => pieces.push(file),
+        }
+    }
+}
+
+impl From<Vec<u8>> for ResponseBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        ResponseBody::Bytes(bytes)
+    }
+}
+
+/// standard reason phrase for a status code, for when only the number is known
+pub fn reason_phrase(code: u16) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Parses the request line and headers. Returns the request with an empty body
+/// and the length of the head (including the blank line); the body is read
+/// separately by request_body::BodyReader.
+pub fn parse_request_head(raw: &[u8]) -> Option<(Request, usize)> {
     // Find the end of headers (double CRLF)
     let header_end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
     let header_end = header_end + 4; // Include the CRLF
-    
-    println!("DEBUG: Header end at position: {}", header_end);
-    
+
     // Parse headers as string
     let header_str = std::str::from_utf8(&raw[..header_end]).ok()?;
     let mut lines = header_str.split("\r\n");
@@ -36,57 +263,182 @@ pub fn parse_http_request(raw: &[u8]) -> Option<Request> {
     let path = parts.next()?.to_string();
     let version = parts.next()?.to_string();
 
-    let mut headers = HashMap::new();
+    let mut headers: HashMap<String, String> = HashMap::new();
     for line in &mut lines {
         if line.is_empty() {
             break; // End of headers
         }
         if let Some((key, value)) = line.split_once(": ") {
-            headers.insert(key.to_string(), value.to_string());
+            // a repeated field is one list (RFC 9110 5.3); the name keeps
+            // the case it was first sent with
+            match headers
+                .iter_mut()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            {
+                Some((_, joined)) => {
+                    let separator = if key.eq_ignore_ascii_case("Cookie") {
+                        "; "
+                    } else {
+                        ", "
+                    };
+                    joined.push_str(separator);
+                    joined.push_str(value);
+                }
+                None => {
+                    headers.insert(key.to_string(), value.to_string());
+                }
+            }
         }
     }
 
-    // Get the body as raw bytes
-    let mut body = raw[header_end..].to_vec();
-    println!("DEBUG: Raw body length: {}", body.len());
-    
-    // Handle chunked transfer encoding
-    if headers.get("Transfer-Encoding").map(|s| s.to_lowercase()) == Some("chunked".to_string()) {
-        println!("DEBUG: Detected chunked transfer encoding, decoding body...");
-        // Decode chunked body
-        if let Ok(decoded) = decode_chunked_body(&body) {
-            println!("DEBUG: Successfully decoded chunked body, new length: {}", decoded.len());
-            body = decoded;
-        } else {
-            println!("DEBUG: Failed to decode chunked body");
+    Some((
+        Request {
+            method,
+            path,
+            version,
+            headers,
+            body: RequestBody::Memory(Vec::new()),
+            remote_addr: None,
+            local_addr: None,
+            secure: false,
+        },
+        header_end,
+    ))
+}
+
+/// Serializes the response into the pieces queued on the connection: the head
+/// with any in-memory body appended, then the file regions in between. A
+/// streamed body has no Content-Length; it is chunked or ends with the connection.
+pub fn build_response(res: Response) -> Vec<ResponseBody> {
+    let mut response = format!("HTTP/1.1 {} {}\r\n", res.status_code, res.reason_phrase);
+
+    // 304 and 204 never carry a body, so no length either
+    let bodiless = res.status_code == 304 || res.status_code == 204;
+    match &res.body {
+        ResponseBody::Stream(stream) => {
+            if stream.chunked && !res.headers.contains_key("Transfer-Encoding") {
+                response += "Transfer-Encoding: chunked\r\n";
+            }
+        }
+        body => {
+            if !bodiless && !res.headers.contains_key("Content-Length") {
+                response += &format!("Content-Length: {}\r\n", body.len());
+            }
         }
     }
 
-    println!("DEBUG: Final body length: {}", body.len());
-    Some(Request {
-        method,
-        path,
-        version,
-        headers,
-        body,
-    })
+    response += &header_lines(&res.headers);
+    response += "\r\n";
+
+    let mut pieces = vec![ResponseBody::Bytes(response.into_bytes())];
+    res.body.flatten_into(&mut pieces);
+    pieces
 }
 
-pub fn build_response(res: Response) -> Vec<u8> {
-    let mut response = format!("HTTP/1.1 {} {}\r\n", res.status_code, res.reason_phrase);
+/// Status line and header fields only, for a body that is sent while it is
+/// being produced (chunked, or until the connection closes)
+pub fn response_head(res: &Response) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\n{}\r\n",
+        res.status_code,
+        res.reason_phrase,
+        header_lines(&res.headers)
+    )
+    .into_bytes()
+}
+
+/// One chunk of a chunked body; nothing for empty data, as a zero-size chunk
+/// would end the body
+pub fn chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// The zero-size chunk that ends a chunked body, with its trailer fields
+pub fn last_chunk(trailers: &Headers) -> Vec<u8> {
+    format!("0\r\n{}\r\n", header_lines(trailers)).into_bytes()
+}
+
+fn header_lines(headers: &Headers) -> String {
+    headers
+        .iter()
+        .map(|(key, value)| format!("{}: {}\r\n", key, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_body::BodyReader;
+    use std::io::Read;
 
-    if !res.headers.contains_key("Content-Length") {
-        response += &format!("Content-Length: {}\r\n", res.body.len());
+    #[derive(Debug)]
+    struct Pieces(Vec<&'static [u8]>, Headers);
+
+    impl BodySource for Pieces {
+        fn next_piece(&mut self) -> io::Result<Option<Vec<u8>>> {
+            Ok((!self.0.is_empty()).then(|| self.0.remove(0).to_vec()))
+        }
+
+        fn trailers(&self) -> Headers {
+            self.1.clone()
+        }
     }
 
-    for (key, value) in res.headers {
-        response += &format!("{}: {}\r\n", key, value);
+    // the whole chunked body of a source, as write_queue sends it
+    fn chunked(mut source: impl BodySource) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(piece) = source.next_piece().unwrap() {
+            out.extend(chunk(&piece));
+        }
+        out.extend(last_chunk(&source.trailers()));
+        out
     }
 
-    response += "\r\n";
+    #[test]
+    fn chunk_framing() {
+        assert_eq!(chunk(b"hello"), b"5\r\nhello\r\n");
+        assert_eq!(chunk(&[b'x'; 26])[..4], *b"1a\r\n");
+        assert_eq!(chunk(b""), b"");
+        assert_eq!(last_chunk(&Headers::new()), b"0\r\n\r\n");
+    }
+
+    #[test]
+    fn trailers_follow_the_last_chunk() {
+        let mut trailers = Headers::new();
+        trailers.insert("Server-Timing", "total;dur=12");
+        trailers.append("X-Checksum", "abc");
+        let body = chunked(Pieces(vec![b"hello", b"", b" world"], trailers));
+        assert_eq!(
+            body,
+            b"5\r\nhello\r\n6\r\n world\r\n0\r\n\
+              Server-Timing: total;dur=12\r\nX-Checksum: abc\r\n\r\n"
+        );
 
-    let mut full_response = response.into_bytes();
-    full_response.extend(res.body);
+        // and it reads back as the same body, nothing left over
+        let mut reader = BodyReader::new(true, 0, 100).unwrap();
+        assert_eq!(reader.feed(&body).unwrap(), body.len());
+        assert!(reader.is_done());
+        let mut data = Vec::new();
+        let body = reader.finish().unwrap();
+        body.reader().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world");
+    }
 
-    full_response
+    #[test]
+    fn no_trailers_by_default() {
+        #[derive(Debug)]
+        struct Once(bool);
+        impl BodySource for Once {
+            fn next_piece(&mut self) -> io::Result<Option<Vec<u8>>> {
+                Ok((!std::mem::replace(&mut self.0, true)).then(|| b"hi".to_vec()))
+            }
+        }
+        assert_eq!(chunked(Once(false)), b"2\r\nhi\r\n0\r\n\r\n");
+    }
 }
@@ -0,0 +1,136 @@
+// # كتابة الرد على السوكت
+//
+// A response is a queue of pieces. Bytes are written as they are; a file body
+// goes from the file to the socket with sendfile(2) so it never passes through
+// a userspace buffer, a slice at a time, until the socket would block; over
+// TLS it has to be read in to be encrypted. A generated body is asked for its
+// next piece only when the socket took the rest.
+
+use crate::requests::{FileBody, ResponseBody, chunk, last_chunk};
+use crate::tls::ClientStream;
+use mio::net::TcpStream;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+// most bytes handed to one sendfile call
+const SENDFILE_CHUNK: u64 = 1024 * 1024;
+// most bytes of a file read in for one write, when sendfile can't be used
+const COPY_CHUNK: u64 = 64 * 1024;
+
+/// Writes queued pieces until the queue is empty (true) or the socket would block (false)
+pub fn write_queue(
+    stream: &mut ClientStream,
+    queue: &mut VecDeque<ResponseBody>,
+) -> io::Result<bool> {
+    while let Some(piece) = queue.front_mut() {
+        if let ResponseBody::Parts(parts) = piece {
+            // build_response flattens bodies, this only keeps the queue correct otherwise
+            let parts = std::mem::take(parts);
+            queue.pop_front();
+            for part in parts.into_iter().rev() {
+                queue.push_front(part);
+            }
+            continue;
+        }
+        if let ResponseBody::Stream(stream) = piece {
+            // the next piece of a generated body goes in front of it, and the
+            // last chunk takes its place at the end
+            let next = match stream.source.next_piece()? {
+                Some(data) if data.is_empty() => continue,
+                Some(data) if stream.chunked => chunk(&data),
+                Some(data) => data,
+                None => {
+                    let end = if stream.chunked {
+                        last_chunk(&stream.source.trailers())
+                    } else {
+                        Vec::new()
+                    };
+                    queue.pop_front();
+                    end
+                }
+            };
+            queue.push_front(next.into());
+            continue;
+        }
+        let result = match piece {
+            ResponseBody::Bytes(bytes) if bytes.is_empty() => Ok(0),
+            ResponseBody::Bytes(bytes) => stream.write(bytes).inspect(|&n| {
+                bytes.drain(..n);
+            }),
+            ResponseBody::File(file) if file.len == 0 => Ok(0),
+            ResponseBody::File(file) => send_file(stream, file),
+            ResponseBody::Parts(_) | ResponseBody::Stream(_) => Ok(0),
+        };
+        match result {
+            Ok(0) if piece.len() > 0 => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(_) => {
+                if piece.len() == 0 {
+                    queue.pop_front();
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    // TLS may still hold the last records
+    match stream.flush() {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn send_file(stream: &mut ClientStream, body: &mut FileBody) -> io::Result<usize> {
+    match stream {
+        ClientStream::Plain(tcp) => sendfile(tcp, body),
+        ClientStream::Tls(_) => copy_file(stream, body, COPY_CHUNK),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile(stream: &mut TcpStream, body: &mut FileBody) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = body.offset as libc::off_t;
+    let count = body.len.min(SENDFILE_CHUNK) as usize;
+    // sendfile advances `offset` itself and leaves the file position alone
+    let sent = unsafe {
+        libc::sendfile(
+            stream.as_raw_fd(),
+            body.file.as_raw_fd(),
+            &mut offset,
+            count,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if sent == 0 {
+        // the file got shorter than the Content-Length we already sent
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    body.offset += sent as u64;
+    body.len -= sent as u64;
+    Ok(sent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendfile(stream: &mut TcpStream, body: &mut FileBody) -> io::Result<usize> {
+    copy_file(stream, body, SENDFILE_CHUNK)
+}
+
+// without sendfile: read the slice at the offset and write what the socket takes
+fn copy_file(stream: &mut impl Write, body: &mut FileBody, max: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+
+    let mut buffer = vec![0; body.len.min(max) as usize];
+    let n = body.file.read_at(&mut buffer, body.offset)?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let sent = stream.write(&buffer[..n])?;
+    body.offset += sent as u64;
+    body.len -= sent as u64;
+    Ok(sent)
+}
@@ -1,24 +1,117 @@
-use mio::net::TcpStream;
-use std::collections::HashMap;
-use std::time::Instant;
+use crate::cgi::CgiRequest;
+use crate::request_body::BodyReader;
+use crate::requests::{Request, ResponseBody};
+use crate::sse::FileEvents;
+use crate::tls::ClientStream;
+use crate::websocket::WebSocket;
+use mio::Token;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq, Clone, serde::Deserialize)]
+const DEFAULT_KEEPALIVE_TIMEOUT: u64 = 5; // seconds
+const DEFAULT_KEEPALIVE_REQUESTS: usize = 100;
+
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
 pub struct ServerConfig {
     pub server_name: String,
     pub server_address: Vec<ServerAddress>, //ip and Port
     pub max_body_size: usize,               // in bytes
     pub router: Vec<RouterConfig>,
     pub error_msg: HashMap<u16, String>, // status code and page path
+    #[serde(default)]
+    pub default_server: bool, // answers requests whose Host matches no server_name
+    pub keepalive_timeout: Option<u64>,  // idle seconds before a keep-alive connection is closed
+    pub keepalive_requests: Option<usize>, // max requests served on one connection
+    #[serde(default)]
+    pub mime_types: HashMap<String, String>, // extension (without the dot) -> MIME type
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>, // named backend groups for proxy_pass
 }
-#[derive(Debug, PartialEq, Clone, serde::Deserialize)]
+
+impl ServerConfig {
+    pub fn keepalive_timeout(&self) -> Duration {
+        Duration::from_secs(self.keepalive_timeout.unwrap_or(DEFAULT_KEEPALIVE_TIMEOUT))
+    }
+
+    pub fn keepalive_requests(&self) -> usize {
+        self.keepalive_requests
+            .unwrap_or(DEFAULT_KEEPALIVE_REQUESTS)
+            .max(1)
+    }
+
+    /// max request body size for a path: the route's own limit, else the server's
+    pub fn body_limit(&self, path: &str) -> usize {
+        self.find_route(path)
+            .and_then(|route| route.max_body_size)
+            .unwrap_or(self.max_body_size)
+    }
+
+    /// longest route prefix that matches the request path
+    pub fn find_route(&self, path: &str) -> Option<&RouterConfig> {
+        self.router
+            .iter()
+            .filter(|r| path.starts_with(&r.path))
+            .max_by_key(|r| r.path.len())
+    }
+}
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
 pub struct RouterConfig {
     pub path: String,
     pub methods: Vec<String>, // GET, POST, etc.
     pub root: String,
     pub index: Option<String>, // default file to serve
-    pub cgi: Option<(String, String)>,
+    #[serde(default)]
+    pub cgi: HashMap<String, String>, // extension -> interpreter, ".py" -> "/usr/bin/python3"
+    pub cgi_extensions: Option<Vec<String>>, // extensions allowed to run, default the keys of `cgi`
+    pub fastcgi: Option<String>, // FastCGI server for this route, "127.0.0.1:9000" or "unix:/path"
+    pub cgi_timeout: Option<u64>, // seconds a script may go without output before it is killed, default 30
+    pub proxy_pass: Option<String>, // upstream HTTP server, "127.0.0.1:3000" or "http://host:port/base"
+    pub proxy_timeout: Option<u64>, // seconds the upstream may stay silent before a 504, default 60
+    pub websocket: Option<WebSocketConfig>, // answers Upgrade: websocket requests
+    pub event_stream: Option<String>, // file whose new lines GET streams as server-sent events, relative to root
     pub directory_listing: Option<bool>, // enable/disable directory listing for this route
     pub redirection: Option<RedirectionConfig>, // optional redirection
+    pub max_body_size: Option<usize>, // overrides the server's max_body_size for this route
+    pub default_type: Option<String>, // MIME type for files with an unknown extension
+    pub compression: Option<CompressionConfig>, // gzip/deflate/br for this route, off when missing
+}
+
+/// What a route's WebSocket connections talk to
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
+pub struct WebSocketConfig {
+    pub handler: String, // echo, proxy or process
+    // proxy: the upstream ("127.0.0.1:9000", "ws://host:port/path" or an upstream name);
+    // process: the command line
+    pub target: Option<String>,
+    pub max_message_size: Option<usize>, // bytes, default 1M
+}
+
+/// A group of backends a `proxy_pass` can name instead of one host
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
+pub struct UpstreamConfig {
+    pub servers: Vec<String>,                    // "127.0.0.1:3001", ...
+    pub balance: Option<String>,                 // round_robin (default), least_conn or ip_hash
+    pub max_fails: Option<u32>, // failures in a row that take a server out, default 1
+    pub fail_timeout: Option<u64>, // seconds a failed server stays out, default 10
+    pub health_check: Option<HealthCheckConfig>, // active probes, off when missing
+}
+
+/// `GET path` sent to every server of an upstream; 2xx and 3xx mean healthy
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
+pub struct HealthCheckConfig {
+    pub path: Option<String>,  // default "/"
+    pub interval: Option<u64>, // seconds between probes, default 5
+    pub timeout: Option<u64>,  // seconds a probe may take, default 2
+}
+
+/// Response compression for a route; every field has a default
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
+pub struct CompressionConfig {
+    pub level: Option<u32>, // 1-9, default 6 (brotli quality is capped at 11)
+    pub types: Option<Vec<String>>, // MIME types worth compressing
+    pub min_length: Option<u64>, // smaller bodies are sent as they are
+    pub precompressed: Option<bool>, // serve file.br / file.gz next to the file, default on
 }
 
 #[derive(Debug, PartialEq, Clone, serde::Deserialize)]
@@ -31,11 +124,60 @@ pub struct RedirectionConfig {
 pub struct ServerAddress {
     pub ip: String,
     pub port: u16,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>, // HTTPS on this address
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize)]
+pub struct TlsConfig {
+    pub cert: String, // PEM certificate chain, the server's own certificate first
+    pub key: String,  // PEM private key (PKCS#8, PKCS#1 or SEC1)
 }
 pub struct Connection {
-    pub stream: TcpStream,
+    pub stream: ClientStream,
     pub read_buffer: Vec<u8>,
-    pub write_buffer: Vec<u8>,
+    pub head_checked: usize, // bytes of read_buffer already searched for the end of a head
+    pub write_queue: VecDeque<ResponseBody>, // response pieces still to send
     pub is_writing: bool,
     pub last_active: Instant,
+    pub listener: Token, // listener that accepted this connection
+    pub server_index: Option<usize>, // block that took the last request, for its keep-alive timeout
+    pub peer_addr: SocketAddr,
+    pub pending_request: Option<PendingRequest>, // head parsed, body still arriving
+    pub requests_served: usize,
+    pub close_after_write: bool, // set once a response carries Connection: close
+    pub cgi: Option<CgiJob>,     // script answering the current request
+    pub websocket: Option<Box<WebSocket>>, // set once the connection switched to WebSocket
+    pub event_stream: Option<EventStreamJob>, // an `event_stream` route feeding this connection
+}
+
+/// A request whose head has been parsed while its body is still being read
+pub struct PendingRequest {
+    pub head: Request,
+    pub server_index: usize,
+    pub body: BodyReader,
+}
+
+/// A CGI script or FastCGI request whose output is being sent to a
+/// connection. Requests pipelined behind it wait until it is done.
+pub struct CgiJob {
+    pub cgi: Box<CgiRequest>,
+    pub output_token: Token,        // stdout pipe, or the FastCGI connection
+    pub stdin_token: Option<Token>, // while a process's body is still being written
+    pub server_index: usize,
+    pub keep_alive: bool,
+    pub head: Vec<u8>,         // output received before the end of the header block
+    pub chunked: Option<bool>, // None until the head is sent, then how the body is framed
+    pub paused: bool,          // stdout is left unread until the client catches up
+    // Some for a text/event-stream: no idle timeout, and a heartbeat may go in
+    // while the output ends with a whole line
+    pub event_stream: Option<bool>,
+}
+
+/// An `event_stream` route sending a file's new lines to a connection
+pub struct EventStreamJob {
+    pub events: FileEvents,
+    pub chunked: bool, // else the stream ends when the connection closes
+    pub set_cookie: Option<String>,
+    pub next_poll: Instant,
 }
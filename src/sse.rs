@@ -0,0 +1,175 @@
+// # أحداث الخادم (Server-Sent Events)
+//
+// A response with `Content-Type: text/event-stream` stays open and its events
+// go out as they are produced, with a comment line as heartbeat while the
+// source is quiet. Scripts stream them like any other output; a route with
+// `event_stream` streams the lines appended to a file, each line an event
+// whose id is where it ends in the file, so `Last-Event-ID` resumes after it.
+
+use crate::requests::{Headers, Request};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// a comment line, sent when nothing else went out for a while so proxies and
+/// clients keep the connection and a gone client is noticed
+pub const HEARTBEAT: &[u8] = b": heartbeat\n";
+// most of a file read in one go
+const READ_CHUNK: usize = 64 * 1024;
+
+pub fn is_event_stream(headers: &Headers) -> bool {
+    headers.get("Content-Type").is_some_and(|t| {
+        t.split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .eq_ignore_ascii_case("text/event-stream")
+    })
+}
+
+/// The lines appended to a file, as events
+pub struct FileEvents {
+    file: File,
+    offset: u64, // where the next line starts
+}
+
+impl FileEvents {
+    /// Starts after the client's Last-Event-ID when it is a position in the
+    /// file, else at the end: only new lines are sent
+    pub fn open(path: &Path, req: &Request) -> io::Result<FileEvents> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let offset = req
+            .header("Last-Event-ID")
+            .and_then(|id| id.trim().parse::<u64>().ok())
+            .filter(|&id| id <= len)
+            .unwrap_or(len);
+        Ok(FileEvents { file, offset })
+    }
+
+    /// Events for the whole lines written since the last call; a line still
+    /// being written waits for its newline
+    pub fn poll(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.file.metadata()?.len();
+        if len < self.offset {
+            // truncated or rotated in place: start over
+            self.offset = 0;
+        }
+        let mut data = vec![0; (len - self.offset).min(READ_CHUNK as u64) as usize];
+        let n = self.file.read_at(&mut data, self.offset)?;
+        data.truncate(n);
+        let end = match data.iter().rposition(|&b| b == b'\n') {
+            Some(lf) => lf + 1,
+            // a line longer than a read goes out in pieces
+            None if n == READ_CHUNK => n,
+            None => return Ok(Vec::new()),
+        };
+        let mut events = Vec::new();
+        let mut start = 0;
+        for line in data[..end].split_inclusive(|&b| b == b'\n') {
+            start += line.len();
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+            events.extend_from_slice(format!("id: {}\n", self.offset + start as u64).as_bytes());
+            events.extend_from_slice(b"data: ");
+            events.extend_from_slice(line);
+            events.extend_from_slice(b"\n\n");
+        }
+        self.offset += end as u64;
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::parse_request_head;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn log_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("localhost-sse-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn append(path: &Path, data: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn watch(path: &Path, last_event_id: Option<&str>) -> FileEvents {
+        let raw = match last_event_id {
+            Some(id) => format!(
+                "GET /events HTTP/1.1\r\nHost: a\r\nLast-Event-ID: {}\r\n\r\n",
+                id
+            ),
+            None => "GET /events HTTP/1.1\r\nHost: a\r\n\r\n".to_string(),
+        };
+        let req = parse_request_head(raw.as_bytes()).unwrap().0;
+        FileEvents::open(path, &req).unwrap()
+    }
+
+    #[test]
+    fn partial_line_waits_for_its_newline() {
+        let path = log_file("partial", b"old\n");
+        let mut events = watch(&path, None);
+        assert_eq!(events.poll().unwrap(), b"");
+        append(&path, b"first\r\nsec");
+        assert_eq!(events.poll().unwrap(), b"id: 11\ndata: first\n\n");
+        assert_eq!(events.poll().unwrap(), b"");
+        append(&path, b"ond\n\n");
+        assert_eq!(events.poll().unwrap(), b"id: 18\ndata: second\n\n");
+    }
+
+    #[test]
+    fn last_event_id_resumes_there() {
+        let path = log_file("resume", b"one\ntwo\nthree\n");
+        let mut events = watch(&path, Some("4"));
+        assert_eq!(
+            events.poll().unwrap(),
+            b"id: 8\ndata: two\n\nid: 14\ndata: three\n\n"
+        );
+        // past the end of the file or not a position: only new lines
+        for id in ["15", "abc"] {
+            let mut events = watch(&path, Some(id));
+            assert_eq!(events.poll().unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn truncated_file_starts_over() {
+        let path = log_file("truncated", b"");
+        let mut events = watch(&path, None);
+        append(&path, b"a\nb\n");
+        assert_eq!(
+            events.poll().unwrap(),
+            b"id: 2\ndata: a\n\nid: 4\ndata: b\n\n"
+        );
+        std::fs::write(&path, b"c\n").unwrap();
+        assert_eq!(events.poll().unwrap(), b"id: 2\ndata: c\n\n");
+    }
+
+    #[test]
+    fn long_line_goes_out_in_pieces() {
+        let path = log_file("long", b"");
+        let mut events = watch(&path, None);
+        let mut line = vec![b'x'; READ_CHUNK + 10];
+        line.push(b'\n');
+        append(&path, &line);
+
+        let mut first = format!("id: {}\ndata: ", READ_CHUNK).into_bytes();
+        first.extend_from_slice(&line[..READ_CHUNK]);
+        first.extend_from_slice(b"\n\n");
+        assert_eq!(events.poll().unwrap(), first);
+        let rest = format!("id: {}\ndata: xxxxxxxxxx\n\n", READ_CHUNK + 11);
+        assert_eq!(events.poll().unwrap(), rest.as_bytes());
+        assert_eq!(events.poll().unwrap(), b"");
+    }
+}
@@ -1,21 +1,54 @@
 // # كود قراءة الملفات الثابتة من المسار المطلوب
 
-use std::fs;
+use crate::conditional::Validators;
+use crate::mime;
+use crate::range::ByteRange;
+use crate::requests::{BodySource, FileBody, Headers, Response, ResponseBody, StreamBody};
+use rand::{Rng, distributions::Alphanumeric};
+use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io;
 use std::path::{Path, PathBuf};
 
-/// تمثل نتيجة قراءة الملف: إما نجاح وفيه البايتات، أو خطأ وفيه رسالة
+/// تمثل نتيجة قراءة الملف: إما نجاح وفيه الملف مفتوح، أو خطأ وفيه رسالة
 pub enum FileResponse {
-    Ok(Vec<u8>),
+    Ok(StaticFile),
     NotFound,
     Forbidden,
-    DirectoryListing(String),
+    DirectoryListing(Listing),
+}
+
+/// An opened file; its content is not read here, the event loop streams it
+pub struct StaticFile {
+    pub path: PathBuf,
+    pub file: File,
+    pub metadata: fs::Metadata,
+}
+
+fn open_file(path: PathBuf) -> FileResponse {
+    let opened = File::open(&path).and_then(|file| {
+        let metadata = file.metadata()?;
+        Ok((file, metadata))
+    });
+    match opened {
+        Ok((file, metadata)) => FileResponse::Ok(StaticFile {
+            path,
+            file,
+            metadata,
+        }),
+        Err(_) => FileResponse::NotFound,
+    }
 }
 
 /// قراءة الملف الثابت من المسار المطلوب
-pub fn read_static_file_with_listing(request_path: &str, base_path: &str, index: Option<&str>, directory_listing: bool) -> FileResponse {
+pub fn read_static_file_with_listing(
+    request_path: &str,
+    base_path: &str,
+    index: Option<&str>,
+    directory_listing: bool,
+) -> FileResponse {
     let base = Path::new(base_path);
-    let full_path = base.join(&request_path.trim_start_matches('/'));
+    let full_path = base.join(request_path.trim_start_matches('/'));
     let full_path = match fs::canonicalize(&full_path) {
         Ok(path) => path,
         Err(_) => return FileResponse::NotFound,
@@ -28,77 +61,179 @@ pub fn read_static_file_with_listing(request_path: &str, base_path: &str, index:
         if let Some(index_file) = index {
             let index_path = full_path.join(index_file);
             if index_path.exists() && index_path.is_file() {
-                return match fs::read(&index_path) {
-                    Ok(contents) => FileResponse::Ok(contents),
-                    Err(_) => FileResponse::NotFound,
-                };
+                return open_file(index_path);
             }
         }
         // Directory listing
         if directory_listing {
-            let mut html = String::from("<html><body><h1>Directory listing</h1><ul>");
-            if let Ok(entries) = fs::read_dir(&full_path) {
-                for entry in entries.flatten() {
-                    let name = entry.file_name().to_string_lossy().to_string();
-                    let display = if entry.path().is_dir() { format!("{}/", name) } else { name.clone() };
-                    html.push_str(&format!("<li><a href=\"{}\">{}</a></li>", display, display));
-                }
-            }
-            html.push_str("</ul></body></html>");
-            return FileResponse::DirectoryListing(html);
+            return FileResponse::DirectoryListing(Listing {
+                entries: fs::read_dir(&full_path).ok(),
+                started: false,
+            });
         } else {
             return FileResponse::Forbidden;
         }
     }
     // Serve file
-    match fs::read(&full_path) {
-        Ok(contents) => FileResponse::Ok(contents),
-        Err(_) => FileResponse::NotFound,
+    open_file(full_path)
+}
+
+// entries put in one piece of a listing
+const LISTING_BATCH: usize = 256;
+
+/// A directory listing, written a batch of entries at a time while it is sent,
+/// so a big directory is never read in one go
+#[derive(Debug)]
+pub struct Listing {
+    entries: Option<fs::ReadDir>, // None once the page is closed
+    started: bool,
+}
+
+impl BodySource for Listing {
+    fn next_piece(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.started {
+            self.started = true;
+            return Ok(Some(b"<html><body><h1>Directory listing</h1><ul>".to_vec()));
+        }
+        let Some(entries) = self.entries.as_mut() else {
+            return Ok(None);
+        };
+        let mut html = String::new();
+        for entry in entries.by_ref().flatten().take(LISTING_BATCH) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let display = if entry.path().is_dir() {
+                format!("{}/", name)
+            } else {
+                name.clone()
+            };
+            html.push_str(&format!("<li><a href=\"{}\">{}</a></li>", display, display));
+        }
+        if html.is_empty() {
+            self.entries = None;
+            html.push_str("</ul></body></html>");
+        }
+        Ok(Some(html.into_bytes()))
     }
 }
 
-pub fn build_http_response(file_response: FileResponse) -> Vec<u8> {
+/// `mime_types` and `default_type` come from the server and route config
+pub fn build_http_response(
+    file_response: FileResponse,
+    mime_types: &HashMap<String, String>,
+    default_type: Option<&str>,
+) -> Response {
     match file_response {
         // 1. الملف موجود ويمكن قرائته
-        FileResponse::Ok(contents) => {
-            let status_line = "HTTP/1.1 200 OK\r\n";
-            let content_type = "Content-Type: text/html\r\n";
-            let content_length = format!("Content-Length: {}\r\n", contents.len());
-            let headers = format!("{}{}{}\r\n", status_line, content_type, content_length);
-
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&contents);
+        FileResponse::Ok(static_file) => {
+            let len = static_file.metadata.len();
+            let content_type = mime::content_type(&static_file.path, mime_types, default_type);
+            let validators = Validators::from_metadata(&static_file.metadata);
+            let mut response = Response::html(200, "OK", Vec::new());
+            response
+                .headers
+                .insert("Content-Type".to_string(), content_type);
+            response
+                .headers
+                .insert("ETag".to_string(), validators.etag.clone());
+            response
+                .headers
+                .insert("Accept-Ranges".to_string(), "bytes".to_string());
+            response
+                .headers
+                .insert("Last-Modified".to_string(), validators.last_modified());
+            response.body = ResponseBody::File(FileBody {
+                file: static_file.file,
+                offset: 0,
+                len,
+            });
             response
         }
         FileResponse::NotFound => {
-            let body = b"<h1>404 Not Found</h1>".to_vec();
-            let headers = format!(
-                "HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&body);
-            response
+            Response::html(404, "Not Found", b"<h1>404 Not Found</h1>".to_vec())
         }
         FileResponse::Forbidden => {
-            let body = b"<h1>403 Forbidden</h1>".to_vec();
-            let headers = format!(
-                "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&body);
-            response
+            Response::html(403, "Forbidden", b"<h1>403 Forbidden</h1>".to_vec())
         }
-        FileResponse::DirectoryListing(html) => {
-            let body = html.as_bytes();
-            let headers = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(body);
+        FileResponse::DirectoryListing(listing) => {
+            let mut response = Response::html(200, "OK", Vec::new());
+            response.body = ResponseBody::Stream(StreamBody {
+                source: Box::new(listing),
+                chunked: true,
+            });
             response
         }
     }
 }
+
+/// 304 for a conditional GET whose copy is still fresh: validators, no body
+pub fn not_modified_response(validators: &Validators) -> Response {
+    let mut headers = Headers::new();
+    headers.insert("ETag".to_string(), validators.etag.clone());
+    headers.insert("Last-Modified".to_string(), validators.last_modified());
+    Response {
+        status_code: 304,
+        reason_phrase: "Not Modified".to_string(),
+        headers,
+        body: Vec::new().into(),
+    }
+}
+
+/// Turns the 200 for a whole `len`-byte file into a 206 with only `ranges`:
+/// one range is sent as is, several as multipart/byteranges
+pub fn partial_response(
+    mut response: Response,
+    ranges: &[ByteRange],
+    len: u64,
+) -> io::Result<Response> {
+    let ResponseBody::File(whole) = std::mem::replace(&mut response.body, Vec::new().into()) else {
+        return Ok(response);
+    };
+    response.status_code = 206;
+    response.reason_phrase = "Partial Content".to_string();
+
+    if let [range] = ranges {
+        response.headers.insert(
+            "Content-Range".to_string(),
+            format!("bytes {}-{}/{}", range.start, range.end, len),
+        );
+        response.body = ResponseBody::File(FileBody {
+            file: whole.file,
+            offset: range.start,
+            len: range.len(),
+        });
+        return Ok(response);
+    }
+
+    let boundary: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    let content_type = response
+        .headers
+        .remove("Content-Type")
+        .unwrap_or_else(|| mime::DEFAULT_TYPE.to_string());
+    let mut parts = Vec::new();
+    for range in ranges {
+        let part_head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, range.start, range.end, len
+        );
+        parts.push(ResponseBody::Bytes(part_head.into_bytes()));
+        // sendfile takes the offset itself, so the parts can share one open file
+        parts.push(ResponseBody::File(FileBody {
+            file: whole.file.try_clone()?,
+            offset: range.start,
+            len: range.len(),
+        }));
+    }
+    parts.push(ResponseBody::Bytes(
+        format!("\r\n--{}--\r\n", boundary).into_bytes(),
+    ));
+    response.headers.insert(
+        "Content-Type".to_string(),
+        format!("multipart/byteranges; boundary={}", boundary),
+    );
+    response.body = ResponseBody::Parts(parts);
+    Ok(response)
+}
@@ -0,0 +1,421 @@
+// # التشفير (TLS / HTTPS)
+//
+// An address with `tls` serves HTTPS. rustls runs the handshake and the
+// records while the socket stays non-blocking on the event loop: a connection
+// reads and writes through `ClientStream` whether it is encrypted or not. The
+// server blocks sharing the address each bring a certificate, picked by the
+// name the client sends (SNI); an unknown or missing name gets the default
+// server's. ALPN offers HTTP/1.1.
+
+use crate::serverConfig::{ServerConfig, TlsConfig};
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConnection};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+
+// protocols offered with ALPN, preferred first
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"http/1.1", b"http/1.0"];
+
+/// Reads a PEM certificate chain and the private key that goes with it
+pub fn load_key(config: &TlsConfig) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificate '{}': {}", config.cert, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in '{}'", config.cert));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| format!("cannot read key '{}': {}", config.key, e))?;
+    let key = ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("unsupported key '{}': {}", config.key, e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match().map_err(|_| {
+        format!(
+            "key '{}' does not belong to certificate '{}'",
+            config.key, config.cert
+        )
+    })?;
+    Ok(certified)
+}
+
+// SNI: the certificate of the server block named by the client
+#[derive(Debug)]
+struct ByServerName {
+    names: HashMap<String, Arc<CertifiedKey>>, // lowercase server_name
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for ByServerName {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.names.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+        Some(key.clone())
+    }
+}
+
+/// TLS settings of a listener from its server blocks and their `tls`, the
+/// default server first
+pub fn server_config(
+    blocks: &[(&ServerConfig, &TlsConfig)],
+) -> Result<Arc<rustls::ServerConfig>, String> {
+    let mut names = HashMap::new();
+    let mut default = None;
+    for (server, tls) in blocks {
+        let key = Arc::new(load_key(tls)?);
+        for name in server.server_name.split_whitespace() {
+            names
+                .entry(name.to_ascii_lowercase())
+                .or_insert_with(|| key.clone());
+        }
+        default.get_or_insert(key);
+    }
+    let default = default.ok_or("no certificate for the listener")?;
+    let mut config =
+        rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ByServerName { names, default }));
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// A client connection, in the clear or over TLS
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+pub struct TlsStream {
+    tcp: TcpStream,
+    tls: ServerConnection,
+    plaintext: usize, // decrypted bytes not read yet
+}
+
+impl ClientStream {
+    pub fn new(
+        tcp: TcpStream,
+        config: Option<&Arc<rustls::ServerConfig>>,
+    ) -> io::Result<ClientStream> {
+        let Some(config) = config else {
+            return Ok(ClientStream::Plain(tcp));
+        };
+        let tls = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+        Ok(ClientStream::Tls(Box::new(TlsStream {
+            tcp,
+            tls,
+            plaintext: 0,
+        })))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ClientStream::Tls(_))
+    }
+
+    /// records waiting for the socket, or decrypted bytes waiting for a read:
+    /// the connection has to come back without the client sending anything
+    pub fn has_pending(&self) -> bool {
+        match self {
+            ClientStream::Plain(_) => false,
+            ClientStream::Tls(stream) => stream.tls.wants_write() || stream.plaintext > 0,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    /// Closes the connection, after a close_notify for TLS
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let ClientStream::Tls(stream) = self {
+            stream.tls.send_close_notify();
+            let _ = stream.flush_tls();
+        }
+        self.tcp().shutdown(how)
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(tcp) => tcp,
+            ClientStream::Tls(stream) => &stream.tcp,
+        }
+    }
+
+    fn tcp_mut(&mut self) -> &mut TcpStream {
+        match self {
+            ClientStream::Plain(tcp) => tcp,
+            ClientStream::Tls(stream) => &mut stream.tcp,
+        }
+    }
+}
+
+impl TlsStream {
+    // writes out queued records until the socket would block
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.tls.wants_write() {
+            match self.tls.write_tls(&mut self.tcp) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    // Takes in what the socket has until there is enough to fill `buf`, and
+    // answers the handshake on the way. WouldBlock while no data came yet.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.plaintext < buf.len() {
+            match self.tls.read_tls(&mut self.tcp) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+            match self.tls.process_new_packets() {
+                Ok(state) => self.plaintext = state.plaintext_bytes_to_read(),
+                Err(e) => {
+                    // the alert tells the client what went wrong
+                    let _ = self.flush_tls();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+        }
+        self.flush_tls()?;
+        let n = self.tls.reader().read(buf)?;
+        self.plaintext = self.plaintext.saturating_sub(n);
+        Ok(n)
+    }
+}
+
+impl Write for TlsStream {
+    // New data is taken once the records before it went out, so rustls never
+    // holds more than one write
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush()?;
+        let n = self.tls.writer().write(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // still handshaking, with the buffer full
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.flush_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_tls()?;
+        if self.tls.wants_write() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(tcp) => tcp.read(buf),
+            ClientStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(tcp) => tcp.write(buf),
+            ClientStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(tcp) => tcp.flush(),
+            ClientStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for ClientStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.tcp_mut().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.tcp_mut().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.tcp_mut().deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{ServerName, UnixTime};
+    use rustls::{ClientConnection, DigitallySignedStruct, SignatureScheme};
+
+    // a certificate made for the test, written where load_key can read it
+    fn certificate(name: &str) -> (TlsConfig, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let prefix = format!("localhost-tls-{}-{}", std::process::id(), name);
+        let cert = dir.join(format!("{}.crt", prefix));
+        let key = dir.join(format!("{}.key", prefix));
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+        let config = TlsConfig {
+            cert: cert.to_string_lossy().into_owned(),
+            key: key.to_string_lossy().into_owned(),
+        };
+        (config, generated.cert.der().clone())
+    }
+
+    // the client takes any certificate, so the one the server picked can be
+    // looked at; the handshake signatures are still checked
+    #[derive(Debug)]
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer,
+            _intermediates: &[CertificateDer],
+            _server_name: &ServerName,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            let algorithms = ring::default_provider().signature_verification_algorithms;
+            rustls::crypto::verify_tls12_signature(message, cert, dss, &algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            let algorithms = ring::default_provider().signature_verification_algorithms;
+            rustls::crypto::verify_tls13_signature(message, cert, dss, &algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    // runs a handshake in memory: the certificate the server sent and the
+    // protocol ALPN settled on
+    fn handshake(
+        server_config: &Arc<rustls::ServerConfig>,
+        name: &str,
+        alpn: &[&[u8]],
+    ) -> (CertificateDer<'static>, Option<Vec<u8>>) {
+        let mut config =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+                .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut client = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut server = ServerConnection::new(server_config.clone()).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut records = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut records).unwrap();
+            }
+            server.read_tls(&mut records.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+            let mut records = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut records).unwrap();
+            }
+            client.read_tls(&mut records.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        let sent = client.peer_certificates().unwrap()[0].clone().into_owned();
+        (sent, client.alpn_protocol().map(<[u8]>::to_vec))
+    }
+
+    fn server(name: &str) -> ServerConfig {
+        ServerConfig {
+            server_name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn certificate_by_server_name() {
+        let (a_tls, a_cert) = certificate("a.test");
+        let (b_tls, b_cert) = certificate("b.test");
+        let (a, b) = (server("a.test www.a.test"), server("b.test"));
+        let config = server_config(&[(&a, &a_tls), (&b, &b_tls)]).unwrap();
+
+        assert_eq!(handshake(&config, "b.test", &[]).0, b_cert);
+        assert_eq!(handshake(&config, "WWW.A.test", &[]).0, a_cert);
+        // an unknown name, or none at all, gets the default server's
+        assert_eq!(handshake(&config, "other.test", &[]).0, a_cert);
+        assert_eq!(handshake(&config, "127.0.0.1", &[]).0, a_cert);
+    }
+
+    #[test]
+    fn alpn_settles_on_http11() {
+        let (tls, _) = certificate("alpn.test");
+        let block = server("alpn.test");
+        let config = server_config(&[(&block, &tls)]).unwrap();
+        let (_, protocol) = handshake(&config, "alpn.test", &[b"h2", b"http/1.1"]);
+        assert_eq!(protocol.as_deref(), Some(&b"http/1.1"[..]));
+        // a client that offers nothing gets no protocol
+        assert_eq!(handshake(&config, "alpn.test", &[]).1, None);
+    }
+
+    #[test]
+    fn key_must_match_certificate() {
+        let (a, _) = certificate("mismatch-a.test");
+        let (b, _) = certificate("mismatch-b.test");
+        let crossed = TlsConfig {
+            cert: a.cert,
+            key: b.key,
+        };
+        let error = load_key(&crossed).err().unwrap();
+        assert!(error.contains("does not belong"), "{}", error);
+    }
+}
@@ -1,188 +1,275 @@
 // # كود التعامل مع POST ورفع الملفات
 
-use std::fs::File;
-use std::io::{Read, Write};
+use crate::request_body::RequestBody;
+use crate::requests::Response;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::path::Path;
 
 #[derive(Debug)]
 pub enum UploadResult {
     Ok,
-    PayloadTooLarge,
     BadRequest,
     InternalError,
 }
 
-const MAX_UPLOAD_SIZE: usize = 5 * 1024 * 1024; // 5MB
-
-pub fn handle_file_upload(body: &[u8], content_type: &str) -> UploadResult {
-    println!("DEBUG: Starting file upload handler");
-    println!("DEBUG: Body length: {}", body.len());
-    println!("DEBUG: Content-Type: '{}'", content_type);
-    
-    // 1. التحقق من حجم البيانات
-    if body.len() > MAX_UPLOAD_SIZE {
-        println!("DEBUG: Payload too large");
-        return UploadResult::PayloadTooLarge;
-    }
+pub fn handle_file_upload(body: &RequestBody, content_type: &str) -> UploadResult {
+    // 1. حجم البيانات تم فحصه في run_mio_server قبل قراءة الجسم (max_body_size)
 
     // 2. التحقق من نوع المحتوى
 
     //multipart/form-data اللي هو النوع المستخدم في رفع الملفات
     if !content_type.starts_with("multipart/form-data; boundary=") {
-        println!("DEBUG: Bad content type - expected multipart/form-data, got: '{}'", content_type);
         return UploadResult::BadRequest;
     }
 
     // 3. نطلع الباوندري (الفاصل بين الأجزاء)
     let boundary = match content_type.split("boundary=").nth(1) {
-        Some(b) => format!("--{}", b),
-        None => {
-            println!("DEBUG: Could not extract boundary from content-type");
-            return UploadResult::BadRequest;
-        }
+        Some(b) => format!("--{}", b.trim_matches('"')),
+        None => return UploadResult::BadRequest,
     };
-    
-    println!("DEBUG: Boundary: '{}'", boundary);
-
-    // 5. تحويل الجسم إلى سلسلة
-    // Support chunked transfer encoding (decode if needed)
-    let body_bytes = if content_type.contains("chunked") {
-        match decode_chunked_body(body) {
-            Ok(decoded) => decoded,
-            Err(_) => {
-                println!("DEBUG: Failed to decode chunked body");
-                return UploadResult::BadRequest;
-            }
-        }
-    } else {
-        body.to_vec()
+
+    // 4. نقرأ الجسم كـ stream (ممكن يكون في ملف مؤقت) بدل ما نحمله كله بالذاكرة
+    let reader = match body.reader() {
+        Ok(reader) => reader,
+        Err(_) => return UploadResult::InternalError,
     };
-    let body_str = match std::str::from_utf8(&body_bytes) {
-        Ok(s) => s,
-        Err(_) => {
-            println!("DEBUG: Failed to convert body to UTF-8 string");
+    let mut parts = MultipartReader::new(reader, &boundary);
+
+    // 5. نمشي على الأجزاء لين نلاقي جزء فيه ملف
+    loop {
+        let headers = match parts.next_part_headers() {
+            Ok(Some(headers)) => headers,
+            Ok(None) => break,
+            Err(result) => return result,
+        };
+        let Some(filename) = part_filename(&headers) else {
+            // form field, not a file
+            if let Err(result) = parts.copy_part(&mut io::sink()) {
+                return result;
+            }
+            continue;
+        };
+
+        // 6. نجهز المسار (بس اسم الملف، بدون مجلدات)
+        let Some(filename) = Path::new(&filename).file_name() else {
             return UploadResult::BadRequest;
-        }
-    };
+        };
+        let filepath = Path::new("uploads").join(filename);
 
-    println!("DEBUG: Body string length: {}", body_str.len());
-    println!("DEBUG: Body string starts with: '{}'", &body_str[..body_str.len().min(100)]);
-
-    // 6. التحقق من وجود الجزء المطلوب
-    let parts: Vec<&str> = body_str.split(&boundary).collect();
-    println!("DEBUG: Found {} parts", parts.len());
-
-    for (i, part) in parts.iter().enumerate() {
-        println!("DEBUG: Part {} length: {}", i, part.len());
-        if part.contains("filename=\"") {
-            println!("DEBUG: Found part with filename");
-            // 4. نجيب اسم الملف
-            let filename_start = part.find("filename=\"").unwrap() + 10;
-            let filename_end = part[filename_start..].find('"').unwrap() + filename_start;
-            let filename = &part[filename_start..filename_end];
-            println!("DEBUG: Filename: '{}'", filename);
-
-            // 5. نحدد بداية المحتوى بعد الرأس الفارغ
-            let content_start = part.find("\r\n\r\n").unwrap() + 4;
-            let content = &part.as_bytes()[content_start..];
-            println!("DEBUG: Content length: {}", content.len());
-
-            // 6. نجهز المسار
-            let filepath = Path::new("uploads").join(filename);
-            println!("DEBUG: Filepath: {:?}", filepath);
-
-            // 7. نكتب الملف
-            if let Ok(mut file) = File::create(filepath) {
-                if file.write_all(content).is_ok() {
-                    println!("DEBUG: File written successfully");
-                    return UploadResult::Ok;
-                } else {
-                    println!("DEBUG: Failed to write file content");
-                    return UploadResult::InternalError;
-                }
-            } else {
-                println!("DEBUG: Failed to create file");
+        // 7. نكتب الملف
+        let mut file = match File::create(&filepath) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to create {}: {}", filepath.display(), e);
                 return UploadResult::InternalError;
             }
-        }
+        };
+        return match parts.copy_part(&mut file) {
+            Ok(_) => UploadResult::Ok,
+            Err(result) => {
+                let _ = fs::remove_file(&filepath);
+                result
+            }
+        };
     }
 
-    println!("DEBUG: No part with filename found");
     UploadResult::BadRequest
 }
 
+// filename="..." from the Content-Disposition header of a part
+fn part_filename(headers: &str) -> Option<String> {
+    let start = headers.find("filename=\"")? + 10;
+    let end = headers[start..].find('"')? + start;
+    Some(headers[start..end].to_string())
+}
+
+/// Reads a multipart body part by part without holding it all in memory
+struct MultipartReader<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    delimiter: Vec<u8>, // "\r\n--boundary"
+    started: bool,
+}
+
+const READ_CHUNK: usize = 64 * 1024;
+
+impl<R: Read> MultipartReader<R> {
+    fn new(reader: R, boundary: &str) -> Self {
+        MultipartReader {
+            reader,
+            // the first boundary has no CRLF before it, this lets it match the delimiter
+            buffer: b"\r\n".to_vec(),
+            delimiter: format!("\r\n{}", boundary).into_bytes(),
+            started: false,
+        }
+    }
+
+    // reads more of the body, false at the end
+    fn fill(&mut self) -> Result<bool, UploadResult> {
+        let mut chunk = vec![0; READ_CHUNK];
+        let n = self
+            .reader
+            .read(&mut chunk)
+            .map_err(|_| UploadResult::InternalError)?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    fn find(&mut self, needle: &[u8]) -> Result<usize, UploadResult> {
+        loop {
+            if let Some(pos) = self.buffer.windows(needle.len()).position(|w| w == needle) {
+                return Ok(pos);
+            }
+            if !self.fill()? {
+                return Err(UploadResult::BadRequest);
+            }
+        }
+    }
+
+    /// moves past the next boundary and returns the part headers, None after the last part
+    fn next_part_headers(&mut self) -> Result<Option<String>, UploadResult> {
+        let delimiter = self.delimiter.clone();
+        let pos = self.find(&delimiter)?;
+        if self.started && pos != 0 {
+            return Err(UploadResult::BadRequest);
+        }
+        self.started = true;
+        self.buffer.drain(..pos + delimiter.len());
+        while self.buffer.len() < 2 {
+            if !self.fill()? {
+                return Err(UploadResult::BadRequest);
+            }
+        }
+        if self.buffer.starts_with(b"--") {
+            return Ok(None); // closing boundary
+        }
+        let headers_end = self.find(b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&self.buffer[..headers_end]).to_string();
+        self.buffer.drain(..headers_end + 4);
+        Ok(Some(headers))
+    }
+
+    /// copies the current part's content up to the next boundary
+    fn copy_part(&mut self, out: &mut impl Write) -> Result<u64, UploadResult> {
+        let mut written = 0;
+        loop {
+            if let Some(pos) = self
+                .buffer
+                .windows(self.delimiter.len())
+                .position(|w| w == self.delimiter.as_slice())
+            {
+                out.write_all(&self.buffer[..pos])
+                    .map_err(|_| UploadResult::InternalError)?;
+                self.buffer.drain(..pos);
+                return Ok(written + pos as u64);
+            }
+            // keep a tail that could be the start of the delimiter
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let flush = self.buffer.len() - keep;
+                out.write_all(&self.buffer[..flush])
+                    .map_err(|_| UploadResult::InternalError)?;
+                self.buffer.drain(..flush);
+                written += flush as u64;
+            }
+            if !self.fill()? {
+                return Err(UploadResult::BadRequest);
+            }
+        }
+    }
+}
+
 /// /// تنشئ رد HTTP بناءً على نتيجة رفع الملف
-pub fn build_upload_response(result: UploadResult) -> Vec<u8> {
-    println!("DEBUG: Building upload response for result: {:?}", result);
+pub fn build_upload_response(result: UploadResult) -> Response {
     match result {
         UploadResult::Ok => {
-            println!("DEBUG: Returning OK response");
-            let body = "<h1>✅ File uploaded successfully!</h1>".as_bytes();
-            let headers = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&body);
-            response
-        }
-        UploadResult::PayloadTooLarge => {
-            println!("DEBUG: Returning PayloadTooLarge response");
-            let body = b"<h1>413 Payload Too Large</h1>".to_vec();
-            let headers = format!(
-                "HTTP/1.1 413 Payload Too Large\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&body);
-            response
+            let body = "<h1>✅ File uploaded successfully!</h1>"
+                .as_bytes()
+                .to_vec();
+            Response::html(200, "OK", body)
         }
         UploadResult::BadRequest => {
-            println!("DEBUG: Returning BadRequest response");
-            let body = b"<h1>400 Bad Request</h1>".to_vec();
-            let headers = format!(
-                "HTTP/1.1 400 Bad Request\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&body);
-            response
+            Response::html(400, "Bad Request", b"<h1>400 Bad Request</h1>".to_vec())
         }
         UploadResult::InternalError => {
-            println!("DEBUG: Returning InternalError response");
             let body = b"<h1>500 Internal Server Error</h1>".to_vec();
-            let headers = format!(
-                "HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-                body.len()
-            );
-            let mut response = headers.into_bytes();
-            response.extend_from_slice(&body);
-            response
+            Response::html(500, "Internal Server Error", body)
         }
     }
 }
 
-// Helper to decode chunked transfer encoding
-pub fn decode_chunked_body(body: &[u8]) -> Result<Vec<u8>, ()> {
-    let mut decoded = Vec::new();
-    let mut i = 0;
-    while i < body.len() {
-        // Find the next CRLF
-        let crlf = match body[i..].windows(2).position(|w| w == b"\r\n") {
-            Some(pos) => i + pos,
-            None => return Err(()),
-        };
-        let len_str = std::str::from_utf8(&body[i..crlf]).map_err(|_| ())?;
-        let chunk_size = usize::from_str_radix(len_str.trim(), 16).map_err(|_| ())?;
-        if chunk_size == 0 {
-            break;
-        }
-        i = crlf + 2;
-        if i + chunk_size > body.len() {
-            return Err(());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hands out one byte per read, so every delimiter is split between reads
+    struct OneByte<'a>(&'a [u8]);
+
+    impl Read for OneByte<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
         }
-        decoded.extend_from_slice(&body[i..i + chunk_size]);
-        i += chunk_size + 2; // skip chunk and trailing CRLF
     }
-    Ok(decoded)
+
+    fn part(parts: &mut MultipartReader<OneByte>) -> Result<(String, Vec<u8>), UploadResult> {
+        let headers = parts.next_part_headers()?.expect("a part");
+        let mut content = Vec::new();
+        parts.copy_part(&mut content)?;
+        Ok((headers, content))
+    }
+
+    #[test]
+    fn field_then_file_a_byte_at_a_time() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            line\r\n--XyA almost a delimiter\r\n--X\r\n\
+            --XyZ--\r\n";
+        let mut parts = MultipartReader::new(OneByte(body), "--XyZ");
+
+        let (headers, content) = part(&mut parts).ok().unwrap();
+        assert_eq!(part_filename(&headers), None);
+        assert_eq!(content, b"holiday");
+
+        let (headers, content) = part(&mut parts).ok().unwrap();
+        assert_eq!(part_filename(&headers).as_deref(), Some("a.txt"));
+        assert_eq!(content, b"line\r\n--XyA almost a delimiter\r\n--X");
+
+        assert!(matches!(parts.next_part_headers(), Ok(None)));
+    }
+
+    #[test]
+    fn missing_closing_boundary() {
+        // the part never ends
+        let body = b"--XyZ\r\nContent-Disposition: form-data; filename=\"a\"\r\n\r\ndata";
+        let mut parts = MultipartReader::new(OneByte(body), "--XyZ");
+        assert!(matches!(part(&mut parts), Err(UploadResult::BadRequest)));
+
+        // the last delimiter is cut before its "--"
+        let body = b"--XyZ\r\nContent-Disposition: form-data; filename=\"a\"\r\n\r\ndata\r\n--XyZ";
+        let mut parts = MultipartReader::new(OneByte(body), "--XyZ");
+        assert!(part(&mut parts).is_ok());
+        assert!(matches!(
+            parts.next_part_headers(),
+            Err(UploadResult::BadRequest)
+        ));
+    }
+
+    #[test]
+    fn text_before_the_first_boundary_is_skipped() {
+        let body =
+            b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ--";
+        let mut parts = MultipartReader::new(OneByte(body), "--XyZ");
+        assert_eq!(part(&mut parts).ok().unwrap().1, b"1");
+        assert!(matches!(parts.next_part_headers(), Ok(None)));
+    }
 }
@@ -0,0 +1,337 @@
+// # موازنة الحمل بين الخوادم الخلفية (upstream)
+//
+// An `upstream` names a group of servers that a `proxy_pass` can point at.
+// Each request gets one of them by round robin, least connections or a hash
+// of the client's IP. A server that fails `max_fails` times in a row is left
+// out for `fail_timeout` seconds; with `health_check` the event loop also
+// probes every server with a GET and leaves it out while the probe fails.
+// Server names are resolved once, when the upstream is built.
+
+use crate::proxy;
+use crate::serverConfig::UpstreamConfig;
+use mio::net::TcpStream;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_FAILS: u32 = 1;
+const DEFAULT_FAIL_TIMEOUT: u64 = 10; // seconds
+const DEFAULT_HEALTH_PATH: &str = "/";
+const DEFAULT_HEALTH_INTERVAL: u64 = 5; // seconds
+const DEFAULT_HEALTH_TIMEOUT: u64 = 2; // seconds
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConn,
+    IpHash,
+}
+
+impl Balance {
+    pub fn parse(value: &str) -> Option<Balance> {
+        match value {
+            "round_robin" => Some(Balance::RoundRobin),
+            "least_conn" => Some(Balance::LeastConn),
+            "ip_hash" => Some(Balance::IpHash),
+            _ => None,
+        }
+    }
+}
+
+/// One server of an upstream, as a proxied request or a probe remembers it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerId {
+    pub server: usize, // index of the server block the upstream belongs to
+    pub upstream: String,
+    pub index: usize,
+}
+
+struct Peer {
+    host: String,                // host:port
+    addr: Option<SocketAddr>,    // resolved at startup, None when that failed
+    active: usize,               // requests in flight
+    fails: u32,                  // failures in a row
+    down_until: Option<Instant>, // left out after max_fails failures
+    healthy: bool,               // result of the last probe, true without probes
+    next_probe: Instant,
+}
+
+struct HealthCheck {
+    path: String,
+    interval: Duration,
+    timeout: Duration,
+}
+
+pub struct Upstream {
+    name: String,
+    balance: Balance,
+    max_fails: u32,
+    fail_timeout: Duration,
+    health_check: Option<HealthCheck>,
+    peers: Vec<Peer>,
+    next: usize, // where round robin goes on
+}
+
+impl Upstream {
+    pub fn new(name: &str, config: &UpstreamConfig) -> Upstream {
+        let now = Instant::now();
+        Upstream {
+            name: name.to_string(),
+            balance: config
+                .balance
+                .as_deref()
+                .and_then(Balance::parse)
+                .unwrap_or(Balance::RoundRobin),
+            max_fails: config.max_fails.unwrap_or(DEFAULT_MAX_FAILS).max(1),
+            fail_timeout: Duration::from_secs(config.fail_timeout.unwrap_or(DEFAULT_FAIL_TIMEOUT)),
+            health_check: config.health_check.as_ref().map(|check| HealthCheck {
+                path: check
+                    .path
+                    .clone()
+                    .unwrap_or(DEFAULT_HEALTH_PATH.to_string()),
+                interval: Duration::from_secs(check.interval.unwrap_or(DEFAULT_HEALTH_INTERVAL)),
+                timeout: Duration::from_secs(check.timeout.unwrap_or(DEFAULT_HEALTH_TIMEOUT)),
+            }),
+            peers: config
+                .servers
+                .iter()
+                .map(|host| Peer {
+                    host: proxy::with_port(host),
+                    addr: proxy::resolve(host)
+                        .inspect_err(|e| {
+                            eprintln!(
+                                "Upstream {}: cannot resolve {}, left out: {}",
+                                name, host, e
+                            )
+                        })
+                        .ok(),
+                    active: 0,
+                    fails: 0,
+                    down_until: None,
+                    healthy: true,
+                    // the first probes go out right away
+                    next_probe: now,
+                })
+                .collect(),
+            next: 0,
+        }
+    }
+
+    fn available(&self, index: usize, now: Instant) -> bool {
+        let peer = &self.peers[index];
+        peer.addr.is_some() && peer.healthy && peer.down_until.is_none_or(|until| now >= until)
+    }
+
+    /// Picks the server for a request and counts it as active: its index,
+    /// host:port and address, or None when every server is out
+    pub fn select(&mut self, client_ip: Option<IpAddr>) -> Option<(usize, String, SocketAddr)> {
+        let now = Instant::now();
+        let count = self.peers.len();
+        if count == 0 {
+            return None;
+        }
+        // ip_hash starts at the client's server and moves on only when that one
+        // is out; the others start where round robin left off
+        let start = match (self.balance, client_ip) {
+            (Balance::IpHash, Some(ip)) => {
+                let mut hasher = DefaultHasher::new();
+                ip.hash(&mut hasher);
+                (hasher.finish() % count as u64) as usize
+            }
+            _ => self.next,
+        };
+        let mut candidates = (0..count)
+            .map(|i| (start + i) % count)
+            .filter(|&i| self.available(i, now));
+        let chosen = match self.balance {
+            // the first of the least busy, so ties still rotate
+            Balance::LeastConn => candidates.min_by_key(|&i| self.peers[i].active),
+            _ => candidates.next(),
+        }?;
+        if self.balance != Balance::IpHash || client_ip.is_none() {
+            self.next = (chosen + 1) % count;
+        }
+        let peer = &mut self.peers[chosen];
+        peer.active += 1;
+        peer.down_until = None;
+        Some((chosen, peer.host.clone(), peer.addr?))
+    }
+
+    /// A request to the server is over
+    pub fn release(&mut self, index: usize) {
+        if let Some(peer) = self.peers.get_mut(index) {
+            peer.active = peer.active.saturating_sub(1);
+        }
+    }
+
+    pub fn succeeded(&mut self, index: usize) {
+        if let Some(peer) = self.peers.get_mut(index) {
+            peer.fails = 0;
+        }
+    }
+
+    /// The server could not be reached, broke off or timed out
+    pub fn failed(&mut self, index: usize) {
+        let Some(peer) = self.peers.get_mut(index) else {
+            return;
+        };
+        peer.fails += 1;
+        if peer.fails >= self.max_fails {
+            eprintln!(
+                "Upstream {}: {} failed {} times, out for {} seconds",
+                self.name,
+                peer.host,
+                peer.fails,
+                self.fail_timeout.as_secs()
+            );
+            peer.fails = 0;
+            peer.down_until = Some(Instant::now() + self.fail_timeout);
+        }
+    }
+
+    /// Servers whose next probe is due, with the probe's deadline; the one
+    /// after is scheduled
+    pub fn due_probes(&mut self, now: Instant) -> Vec<(usize, Probe)> {
+        let Some(check) = &self.health_check else {
+            return Vec::new();
+        };
+        let mut probes = Vec::new();
+        for (index, peer) in self.peers.iter_mut().enumerate() {
+            let Some(addr) = peer.addr else {
+                continue;
+            };
+            if now < peer.next_probe {
+                continue;
+            }
+            peer.next_probe = now + check.interval;
+            match Probe::start(addr, &peer.host, &check.path, now + check.timeout) {
+                Ok(probe) => probes.push((index, probe)),
+                Err(_) => set_health(&self.name, peer, false),
+            }
+        }
+        probes
+    }
+
+    /// Result of a server's probe
+    pub fn probed(&mut self, index: usize, healthy: bool) {
+        if let Some(peer) = self.peers.get_mut(index) {
+            set_health(&self.name, peer, healthy);
+        }
+    }
+}
+
+fn set_health(upstream: &str, peer: &mut Peer, healthy: bool) {
+    // only changes are logged, not every probe
+    if peer.healthy != healthy {
+        let state = if healthy { "healthy" } else { "unhealthy" };
+        eprintln!("Upstream {}: {} is {}", upstream, peer.host, state);
+    }
+    peer.healthy = healthy;
+    if healthy {
+        // a passing probe brings back a server passive checks left out
+        peer.fails = 0;
+        peer.down_until = None;
+    }
+}
+
+/// A health check request, written and read as the socket allows
+pub struct Probe {
+    pub stream: TcpStream,
+    request: Vec<u8>,
+    written: usize,
+    response: Vec<u8>,
+    deadline: Instant,
+}
+
+impl Probe {
+    fn start(addr: SocketAddr, host: &str, path: &str, deadline: Instant) -> io::Result<Probe> {
+        let stream = TcpStream::connect(addr)?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: localhost-health-check\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        Ok(Probe {
+            stream,
+            request: request.into_bytes(),
+            written: 0,
+            response: Vec::new(),
+            deadline,
+        })
+    }
+
+    /// Some(healthy) once the status line is in, or the connection failed
+    pub fn event(&mut self) -> Option<bool> {
+        while self.written < self.request.len() {
+            match self.stream.write(&self.request[self.written..]) {
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(_) => return Some(false),
+            }
+        }
+        let mut buf = [0; 1024];
+        loop {
+            if let Some(end) = self.response.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.response[..end]);
+                let status = line.split(' ').nth(1).and_then(|code| code.parse().ok());
+                return Some(status.is_some_and(|code: u16| (200..400).contains(&code)));
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Some(false),
+                Ok(n) => self.response.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(_) => return Some(false),
+            }
+        }
+    }
+
+    pub fn expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(servers: &[&str], balance: &str) -> Upstream {
+        let config = UpstreamConfig {
+            servers: servers.iter().map(|s| s.to_string()).collect(),
+            balance: Some(balance.to_string()),
+            max_fails: None,
+            fail_timeout: None,
+            health_check: None,
+        };
+        Upstream::new("backend", &config)
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut up = upstream(&["127.0.0.1:3001", "127.0.0.1:3002"], "round_robin");
+        let picked: Vec<usize> = (0..4).map(|_| up.select(None).unwrap().0).collect();
+        assert_eq!(picked, [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn unresolved_server_is_left_out() {
+        let mut up = upstream(&["nosuch.invalid:3001", "127.0.0.1:3002"], "round_robin");
+        for _ in 0..3 {
+            let (index, host, addr) = up.select(None).unwrap();
+            assert_eq!((index, host.as_str()), (1, "127.0.0.1:3002"));
+            assert_eq!(addr, "127.0.0.1:3002".parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn failed_server_is_out_until_timeout() {
+        let mut up = upstream(&["127.0.0.1:3001", "127.0.0.1:3002"], "least_conn");
+        let (index, _, _) = up.select(None).unwrap();
+        up.release(index);
+        up.failed(index);
+        let (other, _, _) = up.select(None).unwrap();
+        assert_ne!(other, index);
+        up.release(other);
+        up.failed(other);
+        assert!(up.select(None).is_none());
+    }
+}
@@ -0,0 +1,680 @@
+// # WebSocket (RFC 6455)
+//
+// A route with `websocket` answers `Upgrade: websocket` requests with the
+// opening handshake, and from then on the connection carries frames instead
+// of HTTP. Messages go to the route's handler: `echo` sends them back,
+// `proxy` relays them to a WebSocket upstream and `process` pipes them
+// through a program, a line on stdin per message and a message per line it
+// prints (like websocketd).
+
+use crate::cgi::CgiProcess;
+use crate::proxy::Target;
+use crate::requests::{Headers, Request, Response, response_head};
+use crate::upstream::PeerId;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use mio::Token;
+use mio::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::time::Instant;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// largest message, whole or reassembled, when the route sets no `max_message_size`
+pub const DEFAULT_MAX_MESSAGE: usize = 1024 * 1024;
+
+// opcodes
+const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+// close codes
+pub const NORMAL: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const INVALID_DATA: u16 = 1007;
+pub const TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+pub const BAD_GATEWAY: u16 = 1014;
+
+/// true when the request asks to switch to WebSocket
+pub fn is_upgrade(req: &Request) -> bool {
+    has_token(req.header("Upgrade"), "websocket")
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+pub enum HandshakeError {
+    Invalid(&'static str), // 400
+    Version,               // 426 with the version we speak
+}
+
+/// Checks the client's opening handshake; the Sec-WebSocket-Accept value
+pub fn accept(req: &Request) -> Result<String, HandshakeError> {
+    if req.method != "GET" || req.version != "HTTP/1.1" {
+        return Err(HandshakeError::Invalid(
+            "the handshake must be a GET over HTTP/1.1",
+        ));
+    }
+    if !has_token(req.header("Connection"), "upgrade") {
+        return Err(HandshakeError::Invalid(
+            "Connection does not ask for an upgrade",
+        ));
+    }
+    if req.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(HandshakeError::Version);
+    }
+    let key = req.header("Sec-WebSocket-Key").unwrap_or("").trim();
+    match BASE64.decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(accept_key(key)),
+        _ => Err(HandshakeError::Invalid("bad Sec-WebSocket-Key")),
+    }
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+/// The 101 that switches the connection over
+pub fn switching_protocols(
+    accept: &str,
+    protocol: Option<&str>,
+    set_cookie: Option<&str>,
+) -> Vec<u8> {
+    let mut headers = Headers::new();
+    headers.insert("Upgrade", "websocket");
+    headers.insert("Connection", "Upgrade");
+    headers.insert("Sec-WebSocket-Accept", accept);
+    if let Some(protocol) = protocol {
+        headers.insert("Sec-WebSocket-Protocol", protocol);
+    }
+    if let Some(cookie) = set_cookie {
+        headers.append("Set-Cookie", cookie);
+    }
+    response_head(&Response {
+        status_code: 101,
+        reason_phrase: "Switching Protocols".to_string(),
+        headers,
+        body: Vec::new().into(),
+    })
+}
+
+/// `proxy` target: "host:port", "ws://host:port/path" or an upstream name
+pub fn parse_target(value: &str) -> Result<Target, String> {
+    if value.starts_with("wss://") {
+        return Err("wss upstreams are not supported".to_string());
+    }
+    match value.strip_prefix("ws://") {
+        Some(rest) => Target::parse(rest),
+        None => Target::parse(value),
+    }
+}
+
+/// One unfragmented frame; frames to a client are unmasked, frames to a
+/// server masked
+pub fn frame(opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mut out = vec![0x80 | opcode];
+    let mask_bit = if masked { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.push(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        let key: [u8; 4] = rand::random();
+        out.extend_from_slice(&key);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        out.extend_from_slice(payload);
+    }
+    out
+}
+
+pub fn close_frame(code: u16, reason: &str, masked: bool) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    frame(CLOSE, &payload, masked)
+}
+
+/// What a peer sent: a whole message or a control frame
+pub enum Incoming {
+    Message(u8, Vec<u8>), // TEXT or BINARY
+    Ping(Vec<u8>),
+    Pong,
+    Close(Option<u16>, Vec<u8>), // the code and the raw payload, to send back
+}
+
+/// Reads the frames of one peer and puts fragmented messages back together
+pub struct Decoder {
+    masked: bool, // a client must mask its frames, a server must not
+    max_message: usize,
+    partial: Option<(u8, Vec<u8>)>, // opcode and data of a fragmented message so far
+}
+
+impl Decoder {
+    pub fn new(masked: bool, max_message: usize) -> Decoder {
+        Decoder {
+            masked,
+            max_message,
+            partial: None,
+        }
+    }
+
+    /// Takes the next message or control frame out of `input`: Ok(None) until
+    /// one is complete, Err with the close code when the peer broke the protocol
+    pub fn next(&mut self, input: &mut Vec<u8>) -> Result<Option<Incoming>, u16> {
+        loop {
+            let Some(Frame {
+                fin,
+                opcode,
+                payload,
+                len,
+            }) = self.frame(input)?
+            else {
+                return Ok(None);
+            };
+            input.drain(..len);
+            match opcode {
+                CLOSE => {
+                    let code = close_code(&payload)?;
+                    return Ok(Some(Incoming::Close(code, payload)));
+                }
+                PING => return Ok(Some(Incoming::Ping(payload))),
+                PONG => return Ok(Some(Incoming::Pong)),
+                CONTINUATION => {
+                    let Some((opcode, mut data)) = self.partial.take() else {
+                        return Err(PROTOCOL_ERROR);
+                    };
+                    data.extend_from_slice(&payload);
+                    if fin {
+                        return message(opcode, data).map(Some);
+                    }
+                    self.partial = Some((opcode, data));
+                }
+                _ => {
+                    // a new message while another is still in fragments
+                    if self.partial.is_some() {
+                        return Err(PROTOCOL_ERROR);
+                    }
+                    if fin {
+                        return message(opcode, payload).map(Some);
+                    }
+                    self.partial = Some((opcode, payload));
+                }
+            }
+        }
+    }
+
+    // the first frame in `input`, once it is all in
+    fn frame(&self, input: &[u8]) -> Result<Option<Frame>, u16> {
+        if input.len() < 2 {
+            return Ok(None);
+        }
+        let fin = input[0] & 0x80 != 0;
+        let opcode = input[0] & 0x0F;
+        // RSV bits mean an extension, and none was negotiated
+        if input[0] & 0x70 != 0
+            || !matches!(opcode, CONTINUATION | TEXT | BINARY | CLOSE | PING | PONG)
+        {
+            return Err(PROTOCOL_ERROR);
+        }
+        if (input[1] & 0x80 != 0) != self.masked {
+            return Err(PROTOCOL_ERROR);
+        }
+        let (len, mut pos) = match input[1] & 0x7F {
+            126 if input.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([input[2], input[3]]) as u64, 4),
+            127 if input.len() < 10 => return Ok(None),
+            127 => {
+                let len = u64::from_be_bytes(input[2..10].try_into().unwrap());
+                if len >> 63 != 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                (len, 10)
+            }
+            len => (len as u64, 2),
+        };
+        if opcode >= CLOSE && (!fin || len > 125) {
+            return Err(PROTOCOL_ERROR);
+        }
+        // refused before it is read, so a huge length can't make us buffer it
+        let so_far = self.partial.as_ref().map_or(0, |(_, data)| data.len()) as u64;
+        if opcode < CLOSE && so_far + len > self.max_message as u64 {
+            return Err(TOO_BIG);
+        }
+        let mut key = [0; 4];
+        if self.masked {
+            if input.len() < pos + 4 {
+                return Ok(None);
+            }
+            key.copy_from_slice(&input[pos..pos + 4]);
+            pos += 4;
+        }
+        let len = len as usize;
+        if input.len() < pos + len {
+            return Ok(None);
+        }
+        let mut payload = input[pos..pos + len].to_vec();
+        if self.masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= key[i % 4];
+            }
+        }
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+            len: pos + len,
+        }))
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>, // unmasked
+    len: usize,       // of the whole frame in the input
+}
+
+fn message(opcode: u8, data: Vec<u8>) -> Result<Incoming, u16> {
+    if opcode == TEXT && std::str::from_utf8(&data).is_err() {
+        return Err(INVALID_DATA);
+    }
+    Ok(Incoming::Message(opcode, data))
+}
+
+// the status code of a close frame; 1005, 1006 and 1015 are never sent
+fn close_code(payload: &[u8]) -> Result<Option<u16>, u16> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if payload.len() == 1 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+        return Err(PROTOCOL_ERROR);
+    }
+    if std::str::from_utf8(&payload[2..]).is_err() {
+        return Err(INVALID_DATA);
+    }
+    Ok(Some(code))
+}
+
+/// An accepted handshake, before the event loop starts the handler
+pub struct Upgrade {
+    pub accept: String,
+    pub set_cookie: Option<String>,
+    pub max_message: usize,
+    pub handler: Start,
+}
+
+pub enum Start {
+    Echo,
+    Process(CgiProcess),
+    // the request head for the upstream, without its upgrade fields
+    Proxy {
+        target: Target,
+        head: String,
+        client_ip: Option<IpAddr>,
+    },
+}
+
+/// A connection after the handshake
+pub struct WebSocket {
+    pub decoder: Decoder,
+    pub handler: Handler,
+    pub tokens: Vec<Token>, // the handler's pipes or upstream connection
+    pub pending: Option<(String, Option<String>)>, // accept and cookie of the 101, held back until the upstream accepted
+    pub server_index: usize,                       // server block that accepted the handshake
+    pub closing: Option<Instant>,                  // when our close frame was queued
+    pub ping_sent: Option<Instant>, // when a keepalive ping went out, until the client answers
+    pub paused: bool,               // the handler's output waits for the client
+}
+
+pub enum Handler {
+    Echo,
+    Process(Box<ProcessHandler>),
+    Proxy(Box<ProxyHandler>),
+    Closed, // the handler is gone, the client's close frame is awaited
+}
+
+/// A program talking to the client: a line on stdin per message
+pub struct ProcessHandler {
+    pub process: CgiProcess,
+    line: Vec<u8>, // stdout read after the last newline
+    max_message: usize,
+}
+
+impl ProcessHandler {
+    pub fn new(process: CgiProcess, max_message: usize) -> ProcessHandler {
+        ProcessHandler {
+            process,
+            line: Vec::new(),
+            max_message,
+        }
+    }
+
+    pub fn send(&mut self, message: &[u8]) {
+        self.process.queue_stdin(message);
+        self.process.queue_stdin(b"\n");
+        self.process.write_stdin();
+    }
+
+    /// The next line the program printed, without its newline: Ok(None) while
+    /// there is none yet, UnexpectedEof once stdout is closed
+    pub fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = [0; 16384];
+        loop {
+            if let Some(lf) = self.line.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.line.drain(..lf + 1).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(Some(line));
+            }
+            // a line longer than a message can be goes out in pieces
+            if self.line.len() >= self.max_message {
+                return Ok(Some(self.line.drain(..self.max_message).collect()));
+            }
+            match self.process.stdout.read(&mut buf) {
+                Ok(0) if self.line.is_empty() => return Err(io::ErrorKind::UnexpectedEof.into()),
+                // the last line may have no newline
+                Ok(0) => return Ok(Some(std::mem::take(&mut self.line))),
+                Ok(n) => self.line.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A WebSocket connection to an upstream, relaying messages both ways
+pub struct ProxyHandler {
+    pub host: String,         // host:port connected to
+    pub peer: Option<PeerId>, // when it is a server of an upstream group
+    pub stream: TcpStream,
+    out: Vec<u8>, // bytes not written yet
+    written: usize,
+    input: Vec<u8>,
+    key: Option<String>, // the Sec-WebSocket-Key sent, until the upstream's 101
+    decoder: Decoder,
+}
+
+impl ProxyHandler {
+    pub fn new(
+        head: &str,
+        host: String,
+        peer: Option<PeerId>,
+        stream: TcpStream,
+        max_message: usize,
+    ) -> ProxyHandler {
+        let nonce: [u8; 16] = rand::random();
+        let key = BASE64.encode(nonce);
+        let request = format!(
+            "{}Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            head, key
+        );
+        ProxyHandler {
+            host,
+            peer,
+            stream,
+            out: request.into_bytes(),
+            written: 0,
+            input: Vec::new(),
+            key: Some(key),
+            decoder: Decoder::new(false, max_message),
+        }
+    }
+
+    /// Queues a frame for the upstream and writes what the socket takes
+    pub fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.out.extend_from_slice(&frame(opcode, payload, true));
+        self.flush()
+    }
+
+    pub fn send_close(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send(CLOSE, payload)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        while self.written < self.out.len() {
+            match self.stream.write(&self.out[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.out.clear();
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Reads what the upstream sent; true once it closed the connection
+    pub fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 16384];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(true),
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// true once the upstream's handshake is done
+    pub fn is_open(&self) -> bool {
+        self.key.is_none()
+    }
+
+    /// The upstream's answer to the handshake: Ok(None) until it is all in,
+    /// then the subprotocol it picked, or why it is refused
+    pub fn handshake(&mut self) -> Result<Option<Option<String>>, String> {
+        let Some(end) = self.input.windows(4).position(|w| w == b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let head: Vec<u8> = self.input.drain(..end + 4).collect();
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or("");
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(format!("upstream answered '{}'", status));
+        }
+        let fields: Vec<(&str, &str)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        let field = |wanted: &str| {
+            fields
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| *value)
+        };
+        let key = self.key.take().unwrap_or_default();
+        if field("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+            return Err("upstream sent a wrong Sec-WebSocket-Accept".to_string());
+        }
+        Ok(Some(field("Sec-WebSocket-Protocol").map(str::to_string)))
+    }
+
+    pub fn next(&mut self) -> Result<Option<Incoming>, u16> {
+        self.decoder.next(&mut self.input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::parse_request_head;
+
+    fn decode(masked: bool, input: &[u8]) -> Result<Option<Incoming>, u16> {
+        Decoder::new(masked, DEFAULT_MAX_MESSAGE).next(&mut input.to_vec())
+    }
+
+    #[test]
+    fn accept_key_of_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn handshake_checks_version_and_key() {
+        let head = |version: &str, key: &str| {
+            let raw = format!(
+                "GET /chat HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: {}\r\nSec-WebSocket-Key: {}\r\n\r\n",
+                version, key
+            );
+            parse_request_head(raw.as_bytes()).unwrap().0
+        };
+        let req = head("13", "dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(is_upgrade(&req));
+        assert!(matches!(accept(&req), Ok(key) if key == "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(matches!(
+            accept(&head("8", "dGhlIHNhbXBsZSBub25jZQ==")),
+            Err(HandshakeError::Version)
+        ));
+        assert!(matches!(
+            accept(&head("13", "c2hvcnQ=")),
+            Err(HandshakeError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn masked_text_frame() {
+        // "Hello" from RFC 6455 section 5.7
+        let input = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert!(matches!(
+            decode(true, &input),
+            Ok(Some(Incoming::Message(TEXT, data))) if data == b"Hello"
+        ));
+        // a client must mask, a server must not
+        assert_eq!(decode(false, &input).err(), Some(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn frame_round_trips_through_decoder() {
+        for len in [0, 125, 126, 0xFFFF, 0x10000] {
+            let payload = vec![7; len];
+            let mut input = frame(BINARY, &payload, true);
+            let mut decoder = Decoder::new(true, 0x20000);
+            assert!(matches!(
+                decoder.next(&mut input),
+                Ok(Some(Incoming::Message(BINARY, data))) if data == payload
+            ));
+            assert!(input.is_empty());
+        }
+    }
+
+    #[test]
+    fn partial_frame_waits() {
+        let whole = frame(TEXT, b"hello", true);
+        let mut decoder = Decoder::new(true, DEFAULT_MAX_MESSAGE);
+        let mut input = whole[..4].to_vec();
+        assert!(matches!(decoder.next(&mut input), Ok(None)));
+        assert_eq!(input.len(), 4);
+        input.extend_from_slice(&whole[4..]);
+        assert!(matches!(
+            decoder.next(&mut input),
+            Ok(Some(Incoming::Message(TEXT, data))) if data == b"hello"
+        ));
+    }
+
+    #[test]
+    fn fragments_are_joined_around_a_ping() {
+        let mut input = vec![0x01, 0x03, b'H', b'e', b'l'];
+        input.extend_from_slice(&[0x89, 0x01, b'p']);
+        input.extend_from_slice(&[0x80, 0x02, b'l', b'o']);
+        let mut decoder = Decoder::new(false, DEFAULT_MAX_MESSAGE);
+        assert!(matches!(
+            decoder.next(&mut input),
+            Ok(Some(Incoming::Ping(data))) if data == b"p"
+        ));
+        assert!(matches!(
+            decoder.next(&mut input),
+            Ok(Some(Incoming::Message(TEXT, data))) if data == b"Hello"
+        ));
+    }
+
+    #[test]
+    fn protocol_errors() {
+        // continuation with nothing to continue
+        assert_eq!(decode(false, &[0x80, 0x00]).err(), Some(PROTOCOL_ERROR));
+        // a new message in the middle of a fragmented one
+        assert_eq!(
+            decode(false, &[0x01, 0x01, b'a', 0x81, 0x01, b'b']).err(),
+            Some(PROTOCOL_ERROR)
+        );
+        // RSV bit, unknown opcode, fragmented or long control frame
+        assert_eq!(decode(false, &[0xC1, 0x00]).err(), Some(PROTOCOL_ERROR));
+        assert_eq!(decode(false, &[0x83, 0x00]).err(), Some(PROTOCOL_ERROR));
+        assert_eq!(decode(false, &[0x09, 0x00]).err(), Some(PROTOCOL_ERROR));
+        assert_eq!(
+            decode(false, &[0x89, 0x7E, 0x00, 0x7E]).err(),
+            Some(PROTOCOL_ERROR)
+        );
+        // 64-bit length with the top bit set
+        let mut huge = vec![0x82, 0x7F];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(decode(false, &huge).err(), Some(PROTOCOL_ERROR));
+        // invalid UTF-8 in a text message
+        assert_eq!(decode(false, &[0x81, 0x01, 0xFF]).err(), Some(INVALID_DATA));
+    }
+
+    #[test]
+    fn message_over_the_limit_is_refused_from_its_header() {
+        let mut decoder = Decoder::new(false, 10);
+        let mut input = vec![0x01, 0x06, b'a', b'b', b'c', b'd', b'e', b'f'];
+        assert!(matches!(decoder.next(&mut input), Ok(None)));
+        // the second fragment would make 12 bytes; its payload never arrived
+        assert_eq!(decoder.next(&mut vec![0x80, 0x06]).err(), Some(TOO_BIG));
+        let mut big = vec![0x82, 0x7F];
+        big.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        assert_eq!(Decoder::new(false, 10).next(&mut big).err(), Some(TOO_BIG));
+    }
+
+    #[test]
+    fn close_frames() {
+        assert!(matches!(
+            decode(false, &[0x88, 0x00]),
+            Ok(Some(Incoming::Close(None, _)))
+        ));
+        let close = close_frame(NORMAL, "bye", false);
+        assert!(matches!(
+            decode(false, &close),
+            Ok(Some(Incoming::Close(Some(NORMAL), payload))) if payload[2..] == *b"bye"
+        ));
+        // one byte, or a code that is never sent
+        assert_eq!(
+            decode(false, &[0x88, 0x01, 0x03]).err(),
+            Some(PROTOCOL_ERROR)
+        );
+        assert_eq!(
+            decode(false, &close_frame(1005, "", false)).err(),
+            Some(PROTOCOL_ERROR)
+        );
+    }
+}